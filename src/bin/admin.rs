@@ -0,0 +1,127 @@
+//! Maintenance commands, run against the same database flags and environment as the servers.
+//!
+//! `admin reindex [--db-path <path>] [--db-backend <name>]` rebuilds the secondary indexes of
+//! every namespace. Best run while no server is using the database.
+//!
+//! `admin migrate [--db-path <path>] [--db-backend <name>]` rewrites every stored todo in the
+//! current schema version and lists the records it couldn't, exiting with 1 if there are any.
+//! Also best run while no server is using the database.
+//!
+//! `admin fsck [--quarantine|--repair] [--db-path <path>] [--db-backend <name>]` checks every
+//! namespace for values which don't decode, todos stored under another key than their `_key`,
+//! index entries which don't match the todos and history of todos which are gone. With
+//! `--quarantine` bad entries are moved to the `todos.quarantine` tree of their namespace and the
+//! indexes are fixed, `--repair` also rekeys todos and drops orphaned history. Exits with 1 while
+//! problems are left. Unlike the servers it doesn't reindex or purge anything when opening the
+//! database. Best run while no server is using the database.
+use std::process;
+use various_micro_services::{DbConfig, Fix, FsckMode, Problem, TodoRepository};
+
+const USAGE: &str =
+    "Usage: admin reindex|migrate|fsck [--quarantine|--repair] [--db-path <path>] [--db-backend <name>]";
+
+fn main() {
+    match std::env::args().nth(1).as_deref() {
+        Some("reindex") => reindex(),
+        Some("migrate") => migrate(),
+        Some("fsck") => fsck(),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+fn open() -> TodoRepository {
+    let config = DbConfig::from_env().unwrap_or_else(|e| fail(&e));
+    TodoRepository::open(&config).unwrap_or_else(|e| fail(e.message()))
+}
+
+/// open, without the reindexing and purging which would hide or destroy what fsck looks for.
+fn open_as_is() -> TodoRepository {
+    let config = DbConfig::from_env().unwrap_or_else(|e| fail(&e));
+    TodoRepository::open_as_is(&config).unwrap_or_else(|e| fail(e.message()))
+}
+
+fn namespace(tenant: Option<String>) -> String {
+    match tenant {
+        Some(tenant) => format!("tenant {}", tenant),
+        None => "default namespace".to_owned(),
+    }
+}
+
+fn reindex() {
+    let indexed = open().reindex_all().unwrap_or_else(|e| fail(e.message()));
+    for (tenant, count) in indexed {
+        println!("{}: {} todos indexed", namespace(tenant), count);
+    }
+}
+
+fn migrate() {
+    let migrated = open().migrate_all().unwrap_or_else(|e| fail(e.message()));
+    let mut failed = 0;
+    for (tenant, migration) in migrated {
+        println!(
+            "{}: {} records migrated, {} failed",
+            namespace(tenant),
+            migration.migrated,
+            migration.failed.len()
+        );
+        for unmigrated in &migration.failed {
+            println!(
+                "  {} {:?}: {}",
+                unmigrated.tree,
+                unmigrated.key,
+                unmigrated.error.message()
+            );
+        }
+        failed += migration.failed.len();
+    }
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+fn fsck() {
+    let args: Vec<String> = std::env::args().collect();
+    let mode = match (
+        args.iter().any(|arg| arg == "--quarantine"),
+        args.iter().any(|arg| arg == "--repair"),
+    ) {
+        (false, false) => FsckMode::Check,
+        (true, false) => FsckMode::Quarantine,
+        (false, true) => FsckMode::Repair,
+        (true, true) => fail("Pick one of --quarantine and --repair."),
+    };
+    let findings = open_as_is()
+        .fsck_all(mode)
+        .unwrap_or_else(|e| fail(e.message()));
+    println!("{} problems", findings.len());
+    for finding in &findings {
+        let problem = match &finding.problem {
+            Problem::Undecodable(e) => format!("doesn't decode: {}", e),
+            Problem::KeyMismatch(embedded) => format!("has _key {:?}", embedded),
+            Problem::StaleIndexEntry => "index entry without its todo".to_owned(),
+            Problem::MissingIndexEntry => "todo without its index entry".to_owned(),
+            Problem::OrphanHistory => "history without its todo".to_owned(),
+        };
+        let fix = match finding.fix {
+            Some(Fix::Quarantined) => "quarantined",
+            Some(Fix::Repaired) => "repaired",
+            None => "left as it is",
+        };
+        println!("{} {:?}: {}, {}", finding.tree, finding.key, problem, fix);
+    }
+    let left = findings
+        .iter()
+        .filter(|finding| finding.fix.is_none())
+        .count();
+    if left > 0 {
+        process::exit(1);
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
@@ -1,227 +1,259 @@
+use iron::headers::ContentLength;
 use iron::prelude::*;
 use iron::status;
-use router::Router;
-use serde::Serialize;
-use serde_json::json;
+use iron::typemap::Key;
+use iron::{AroundMiddleware, Handler};
+use persistent::Read as Shared;
+use router::{NoRoute, Router};
+use std::io::Read;
 
-use various_micro_services::{Create, Delete, Fetch, List, Replace, Todo, Update};
+use various_micro_services::service::{self, listen_address, Endpoint, TodoService, MAX_BODY_LEN};
+use various_micro_services::{DbConfig, TodoError, TodoRepository};
+
+/// Key of the shared TodoService in iron's persistent state.
+struct Service;
+impl Key for Service {
+    type Value = TodoService;
+}
 
 fn main() {
+    let config = DbConfig::from_env().expect("Invalid configuration");
+    let repo = TodoRepository::open(&config).expect("Could not open database");
+    let addr = listen_address("127.0.0.1:3000").expect("Invalid configuration");
+
     let mut router = Router::new();
 
-    router.post("todo/add", todo_add, "todo_add");
-    router.get("todo/list", todo_list, "todo_list");
-    router.get("todo/fetch/:todo_key", todo_fetch, "todo_fetch");
-    router.patch("todo/edit", todo_edit, "todo_edit");
-    router.put("todo/replace", todo_replace, "todo_replace");
-    router.delete("todo/delete/:todo_key", todo_delete, "todo_delete");
+    router.get("v1/todos", serve(|_| Endpoint::List), "list");
+    router.post("v1/todos", serve(|_| Endpoint::Create), "create");
+    router.post("v1/todos/bulk", serve(|_| Endpoint::Bulk), "bulk");
+    router.get(
+        "v1/todos/:todo_key",
+        serve(|segment| Endpoint::Fetch(segment("todo_key"))),
+        "fetch",
+    );
+    router.patch(
+        "v1/todos/:todo_key",
+        serve(|segment| Endpoint::Edit(Some(segment("todo_key")))),
+        "edit",
+    );
+    router.put(
+        "v1/todos/:todo_key",
+        serve(|segment| Endpoint::Replace(Some(segment("todo_key")))),
+        "replace",
+    );
+    router.delete(
+        "v1/todos/:todo_key",
+        serve(|segment| Endpoint::Delete(segment("todo_key"))),
+        "delete",
+    );
+    router.post(
+        "v1/todos/:todo_key/:action",
+        serve(|segment| Endpoint::Act(segment("todo_key"), segment("action"))),
+        "action",
+    );
+    router.get(
+        "v1/todos/:todo_key/history",
+        serve(|segment| Endpoint::History(segment("todo_key"))),
+        "history",
+    );
+    router.get(
+        "v1/todos/:todo_key/history/:rev",
+        serve(|segment| Endpoint::Revision(segment("todo_key"), segment("rev"))),
+        "revision",
+    );
+    router.get(
+        "v1/todos/:todo_key/history/diff/:from/:to",
+        serve(|segment| Endpoint::Diff(segment("todo_key"), segment("from"), segment("to"))),
+        "diff",
+    );
+    router.post(
+        "v1/todos/:todo_key/history/:rev/rollback",
+        serve(|segment| Endpoint::Rollback(segment("todo_key"), segment("rev"))),
+        "rollback",
+    );
+    router.get("v1/trash", serve(|_| Endpoint::Trash), "trash");
+    router.post(
+        "v1/trash/:todo_key/restore",
+        serve(|segment| Endpoint::Restore(segment("todo_key"))),
+        "restore",
+    );
+    router.delete(
+        "v1/trash/:todo_key",
+        serve(|segment| Endpoint::Purge(segment("todo_key"))),
+        "purge",
+    );
 
-    let _res = Iron::new(router).http("localhost:3000");
-}
+    router.get("openapi.json", serve(|_| Endpoint::OpenApi), "openapi");
+    router.get("explorer", serve(|_| Endpoint::Explorer), "explorer");
 
-fn todo_add(request: &mut Request) -> Result<iron::response::Response, iron::error::IronError> {
-    let content_type = "application/json".parse::<iron::mime::Mime>().unwrap();
-    let json_body = request.get::<bodyparser::Json>();
-    match json_body {
-        Ok(Some(json_body)) => match serde_json::from_value::<Todo>(json_body) {
-            Ok(todo) => match Todo::create(todo) {
-                Ok(resp) => Ok(Response::with((
-                    content_type,
-                    status::Ok,
-                    serde_json::to_string(&resp).unwrap(),
-                ))),
-                Err(e) => Ok(Response::with((
-                    content_type,
-                    status::BadRequest,
-                    serde_json::to_string(&logged_response("", &e, true)).unwrap(),
-                ))),
-            },
-            Err(e) => Ok(Response::with((
-                content_type,
-                status::BadRequest,
-                serde_json::to_string(&logged_response(&format!("{:?}", e), &json!(""), true))
-                    .unwrap(),
-            ))),
-        },
-        Ok(None) => Ok(Response::with((
-            content_type,
-            status::BadRequest,
-            serde_json::to_string(&logged_response(
-                "Couldn't parse request body.",
-                &json!(""),
-                true,
-            ))
-            .unwrap(),
-        ))),
-        Err(e) => Ok(Response::with((
-            content_type,
-            status::BadRequest,
-            serde_json::to_string(&logged_response(&format!("{:?}", e), &json!(""), true)).unwrap(),
-        ))),
-    }
+    // The routes served before /v1, deprecated.
+    router.post("todo/add", legacy(|_| Endpoint::Create), "todo_add");
+    router.get("todo/list", legacy(|_| Endpoint::List), "todo_list");
+    router.get(
+        "todo/fetch/:todo_key",
+        legacy(|segment| Endpoint::Fetch(segment("todo_key"))),
+        "todo_fetch",
+    );
+    router.patch("todo/edit", legacy(|_| Endpoint::Edit(None)), "todo_edit");
+    router.patch(
+        "todo/edit/:todo_key",
+        legacy(|segment| Endpoint::Edit(Some(segment("todo_key")))),
+        "todo_edit_key",
+    );
+    router.put(
+        "todo/replace",
+        legacy(|_| Endpoint::Replace(None)),
+        "todo_replace",
+    );
+    router.delete(
+        "todo/delete/:todo_key",
+        legacy(|segment| Endpoint::Delete(segment("todo_key"))),
+        "todo_delete",
+    );
+    router.post("todo/bulk", legacy(|_| Endpoint::Bulk), "todo_bulk");
+    router.post(
+        "todo/:todo_key/:action",
+        legacy(|segment| Endpoint::Act(segment("todo_key"), segment("action"))),
+        "todo_action",
+    );
+    router.get("todo/trash", legacy(|_| Endpoint::Trash), "todo_trash");
+    router.post(
+        "todo/trash/:todo_key/restore",
+        legacy(|segment| Endpoint::Restore(segment("todo_key"))),
+        "todo_restore",
+    );
+    router.delete(
+        "todo/trash/:todo_key",
+        legacy(|segment| Endpoint::Purge(segment("todo_key"))),
+        "todo_purge",
+    );
+    router.get(
+        "todo/history/:todo_key",
+        legacy(|segment| Endpoint::History(segment("todo_key"))),
+        "todo_history",
+    );
+    router.get(
+        "todo/history/:todo_key/:rev",
+        legacy(|segment| Endpoint::Revision(segment("todo_key"), segment("rev"))),
+        "todo_revision",
+    );
+    router.get(
+        "todo/history/:todo_key/diff/:from/:to",
+        legacy(|segment| Endpoint::Diff(segment("todo_key"), segment("from"), segment("to"))),
+        "todo_diff",
+    );
+    router.post(
+        "todo/history/:todo_key/:rev/rollback",
+        legacy(|segment| Endpoint::Rollback(segment("todo_key"), segment("rev"))),
+        "todo_rollback",
+    );
+
+    let mut chain = Chain::new(router);
+    chain.link_before(Shared::<Service>::one(TodoService::new(repo)));
+    chain.link_around(CatchNoRoute);
+
+    println!("Listening on http://{}", addr);
+    Iron::new(chain).http(addr).expect("Could not listen");
 }
 
-fn todo_list(request: &mut Request) -> Result<iron::response::Response, iron::error::IronError> {
-    let content_type = "application/json".parse::<iron::mime::Mime>().unwrap();
-    let limit = match request.url.query() {
-        Some(ref query) => query.parse::<u64>().unwrap_or_else(|_| 100u64),
-        None => 100u64,
-    };
-    match Todo::list(limit) {
-        Ok(resp) => Ok(Response::with((
-            content_type,
-            status::Ok,
-            serde_json::to_string(&resp).unwrap(),
-        ))),
-        Err(e) => Ok(Response::with((
-            content_type,
-            status::BadRequest,
-            serde_json::to_string(&logged_response("", &e, true)).unwrap(),
-        ))),
+/// Answers requests no route matches the way every server does.
+struct CatchNoRoute;
+impl AroundMiddleware for CatchNoRoute {
+    fn around(self, handler: Box<dyn Handler>) -> Box<dyn Handler> {
+        Box::new(move |request: &mut Request| {
+            handler.handle(request).or_else(|e| {
+                if e.error.is::<NoRoute>() {
+                    Ok(response(service::Response::no_route()))
+                } else {
+                    Err(e)
+                }
+            })
+        })
     }
 }
 
-fn todo_fetch(request: &mut Request) -> Result<iron::response::Response, iron::error::IronError> {
-    let content_type = "application/json".parse::<iron::mime::Mime>().unwrap();
-    if let Some(ref todo_key) = request.extensions.get::<Router>().unwrap().find("todo_key") {
-        match Todo::fetch(todo_key) {
-            Ok(resp) => Ok(Response::with((
-                content_type,
-                status::Ok,
-                serde_json::to_string(&resp).unwrap(),
-            ))),
-            Err(e) => Ok(Response::with((
-                content_type,
-                status::BadRequest,
-                serde_json::to_string(&logged_response("", &e, true)).unwrap(),
-            ))),
-        }
-    } else {
-        Ok(Response::with((
-            content_type,
-            status::BadRequest,
-            serde_json::to_string(&logged_response("Missing todo _key.", &json!(""), true))
-                .unwrap(),
-        )))
-    }
+/// The endpoint a route serves, made of the segments the router captured, looked up by name.
+type Route = fn(&dyn Fn(&str) -> String) -> Endpoint;
+
+/// A handler passing requests on to the endpoint route makes for them.
+fn serve(route: Route) -> impl Handler {
+    handler(route, false)
 }
 
-fn todo_edit(request: &mut Request) -> Result<iron::response::Response, iron::error::IronError> {
-    let content_type = "application/json".parse::<iron::mime::Mime>().unwrap();
-    let json_body = request.get::<bodyparser::Json>();
-    match json_body {
-        Ok(Some(json_body)) => match Todo::update(json_body) {
-            Ok(resp) => Ok(Response::with((
-                content_type,
-                status::Ok,
-                serde_json::to_string(&resp).unwrap(),
-            ))),
-            Err(e) => Ok(Response::with((
-                content_type,
-                status::BadRequest,
-                serde_json::to_string(&logged_response("", &e, true)).unwrap(),
-            ))),
-        },
-        Ok(None) => Ok(Response::with((
-            content_type,
-            status::BadRequest,
-            serde_json::to_string(&logged_response(
-                "Couldn't parse request body.",
-                &json!(""),
-                true,
-            ))
-            .unwrap(),
-        ))),
-        Err(e) => Ok(Response::with((
-            content_type,
-            status::BadRequest,
-            serde_json::to_string(&logged_response(&format!("{:?}", e), &json!(""), true)).unwrap(),
-        ))),
-    }
+/// serve, for a deprecated route.
+fn legacy(route: Route) -> impl Handler {
+    handler(route, true)
 }
 
-fn todo_replace(request: &mut Request) -> Result<iron::response::Response, iron::error::IronError> {
-    let content_type = "application/json".parse::<iron::mime::Mime>().unwrap();
-    let json_body = request.get::<bodyparser::Json>();
-    match json_body {
-        Ok(Some(json_body)) => match serde_json::from_value::<Todo>(json_body) {
-            Ok(todo) => match Todo::replace(todo) {
-                Ok(resp) => Ok(Response::with((
-                    content_type,
-                    status::Ok,
-                    serde_json::to_string(&resp).unwrap(),
-                ))),
-                Err(e) => Ok(Response::with((
-                    content_type,
-                    status::BadRequest,
-                    serde_json::to_string(&logged_response("", &e, true)).unwrap(),
-                ))),
-            },
-            Err(e) => Ok(Response::with((
-                content_type,
-                status::BadRequest,
-                serde_json::to_string(&logged_response(&format!("{:?}", e), &json!(""), true))
-                    .unwrap(),
-            ))),
-        },
-        Ok(None) => Ok(Response::with((
-            content_type,
-            status::BadRequest,
-            serde_json::to_string(&logged_response(
-                "Couldn't parse request body.",
-                &json!(""),
-                true,
-            ))
-            .unwrap(),
-        ))),
-        Err(e) => Ok(Response::with((
-            content_type,
-            status::BadRequest,
-            serde_json::to_string(&logged_response(&format!("{:?}", e), &json!(""), true)).unwrap(),
-        ))),
+fn handler(route: Route, deprecated: bool) -> impl Handler {
+    move |request: &mut Request| {
+        let endpoint = {
+            let params = request.extensions.get::<Router>();
+            route(&|name| {
+                params
+                    .and_then(|params| params.find(name))
+                    .unwrap_or_default()
+                    .to_owned()
+            })
+        };
+        let service = request
+            .get::<Shared<Service>>()
+            .map_err(|e| TodoError::Internal(e.to_string()));
+        let answer = match (service, service_request(request)) {
+            (Ok(service), Ok(service_request)) => service.handle(&endpoint, &service_request),
+            (Err(e), _) => service::Response::error(&e),
+            (_, Err(answer)) => answer,
+        };
+        if deprecated {
+            Ok(response(answer.deprecated(&endpoint)))
+        } else {
+            Ok(response(answer))
+        }
     }
 }
 
-fn todo_delete(request: &mut Request) -> Result<iron::response::Response, iron::error::IronError> {
-    let content_type = "application/json".parse::<iron::mime::Mime>().unwrap();
-    if let Some(ref todo_key) = request.extensions.get::<Router>().unwrap().find("todo_key") {
-        match Todo::delete(todo_key) {
-            Ok(resp) => Ok(Response::with((
-                content_type,
-                status::Ok,
-                serde_json::to_string(&resp).unwrap(),
-            ))),
-            Err(e) => Ok(Response::with((
-                content_type,
-                status::BadRequest,
-                serde_json::to_string(&logged_response("", &e, true)).unwrap(),
-            ))),
+/// The headers, path, query and body of request, unless its body is too large.
+/// A body whose Content-Length says so isn't read at all.
+fn service_request(request: &mut Request) -> Result<service::Request, service::Response> {
+    if let Some(ContentLength(length)) = request.headers.get::<ContentLength>() {
+        if *length > MAX_BODY_LEN as u64 {
+            return Err(service::Response::body_too_large());
         }
-    } else {
-        Ok(Response::with((
-            content_type,
-            status::BadRequest,
-            serde_json::to_string(&logged_response("Missing todo _key.", &json!(""), true))
-                .unwrap(),
-        )))
     }
+    let mut body = vec![];
+    request
+        .body
+        .by_ref()
+        .take(MAX_BODY_LEN as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| service::Response::error(&TodoError::BadRequest(e.to_string())))?;
+    let headers = &request.headers;
+    Ok(service::Request::from_headers(
+        |name| {
+            headers.get_raw(name).map(|values| {
+                let values: Vec<_> = values
+                    .iter()
+                    .map(|value| String::from_utf8_lossy(value))
+                    .collect();
+                values.join(",")
+            })
+        },
+        format!("/{}", request.url.path().join("/")),
+        request.url.query().unwrap_or("").to_owned(),
+        body,
+    ))
 }
 
-fn logged_response<'t, T: Serialize + core::fmt::Debug>(
-    msg: &'t str,
-    param: &'t T,
-    is_error: bool,
-) -> serde_json::Value {
-    let resp = json!({
-        "is_error": is_error,
-        "message": msg,
-        "detail": param,
-    });
-    log::error!(
-        "{}",
-        serde_json::to_string(&resp).unwrap_or_else(|e| e.to_string())
-    );
-    resp
+/// The iron response to send for response.
+fn response(response: service::Response) -> Response {
+    let mut iron_response =
+        Response::with((status::Status::from_u16(response.status), response.body));
+    for (name, value) in response.headers {
+        iron_response
+            .headers
+            .set_raw(name, vec![value.into_bytes()]);
+    }
+    iron_response
 }
 
 #[cfg(test)]
@@ -229,22 +261,12 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_logged_response() {
-        let num = 1588237987000i64;
-        let exp = json!({
-            "is_error": true,
-            "message": "I'm a teapot.",
-            "detail": {
-                "_key":"",
-                "title":"Write more tests",
-                "timestamp":num,
-                "status":"New"
-            }
-        });
-
-        let mut todo = Todo::new("Write more tests");
-        todo.back_date(&time::OffsetDateTime::from_unix_timestamp(1_588_237_987));
-        let resp = logged_response("I'm a teapot.", &todo, true);
-        assert_eq!(exp.to_string(), serde_json::to_string(&resp).unwrap());
+    fn test_response() {
+        let resp = response(service::Response::no_route());
+        assert_eq!(resp.status, Some(status::NotFound));
+        assert_eq!(
+            resp.headers.get_raw("Content-Type"),
+            Some(&[b"application/problem+json".to_vec()][..])
+        );
     }
 }
@@ -1,112 +1,636 @@
-use serde::{Deserialize, Serialize};
-use serde_json::value::Value;
-use shrinkwraprs::Shrinkwrap;
+// tower-web's derives expand to impl blocks nested inside a const item.
+#![allow(non_local_definitions)]
+
+use bytes::{Buf, Bytes};
+use futures::{Async, Poll};
+use tower_web::codegen::CallSite;
+use tower_web::extract::{self, Context, Extract, ExtractFuture, Immediate};
+use tower_web::util::BufStream;
 use tower_web::{
     derive_resource, derive_resource_impl, impl_web, impl_web_clean_nested,
-    impl_web_clean_top_level, Extract, Response, ServiceBuilder,
+    impl_web_clean_top_level, ServiceBuilder,
 };
 use various_micro_services as vms;
-use vms::{Create, Delete, Fetch, List, Replace, Update};
+use vms::service::{self, listen_address, Endpoint, TodoService, MAX_BODY_LEN};
+use vms::TodoError;
 
 /// This type will be part of the web service as a resource.
-#[derive(Debug)]
-struct HelloWorld;
+/// It owns the service, so every handler works against the same database.
+/// Handlers take the tenant from the `X-Tenant` header, tower-web fills `x_tenant` from it,
+/// `if_match` from the `If-Match` header and `idempotency_key` from the `Idempotency-Key` header.
+#[derive(Clone)]
+struct TodoResource {
+    service: TodoService,
+}
+
+impl_web! {
+    impl TodoResource {
+        #[get("/openapi.json")]
+        fn openapi(&self, head: Head) -> Reply {
+            self.serve(head, &Endpoint::OpenApi, service::Request::default())
+        }
+
+        #[get("/explorer")]
+        fn explorer(&self, head: Head) -> Reply {
+            self.serve(head, &Endpoint::Explorer, service::Request::default())
+        }
+
+        #[get("/v1/todos")]
+        fn list(
+            &self,
+            head: Head,
+            query_string: Vec<u8>,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::List, service::Request {
+                tenant: x_tenant,
+                query: query(query_string),
+                ..service::Request::default()
+            })
+        }
+
+        #[post("/v1/todos")]
+        fn create(
+            &self,
+            head: Head,
+            body: Body,
+            x_tenant: Option<String>,
+            idempotency_key: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Create, service::Request {
+                tenant: x_tenant,
+                idempotency_key,
+                body: body.0,
+                ..service::Request::default()
+            })
+        }
+
+        #[post("/v1/todos/bulk")]
+        fn bulk(
+            &self,
+            head: Head,
+            body: Body,
+            query_string: Vec<u8>,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Bulk, service::Request {
+                tenant: x_tenant,
+                query: query(query_string),
+                body: body.0,
+                ..service::Request::default()
+            })
+        }
+
+        #[get("/v1/todos/:todo_key")]
+        fn fetch(
+            &self,
+            head: Head,
+            todo_key: String,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Fetch(todo_key), tenant(x_tenant))
+        }
+
+        #[patch("/v1/todos/:todo_key")]
+        fn edit(
+            &self,
+            head: Head,
+            todo_key: String,
+            body: Body,
+            x_tenant: Option<String>,
+            if_match: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Edit(Some(todo_key)), service::Request {
+                tenant: x_tenant,
+                if_match,
+                body: body.0,
+                ..service::Request::default()
+            })
+        }
+
+        #[put("/v1/todos/:todo_key")]
+        fn replace(
+            &self,
+            head: Head,
+            todo_key: String,
+            body: Body,
+            x_tenant: Option<String>,
+            if_match: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Replace(Some(todo_key)), service::Request {
+                tenant: x_tenant,
+                if_match,
+                body: body.0,
+                ..service::Request::default()
+            })
+        }
+
+        #[delete("/v1/todos/:todo_key")]
+        fn delete(
+            &self,
+            head: Head,
+            todo_key: String,
+            x_tenant: Option<String>,
+            if_match: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Delete(todo_key), conditional(x_tenant, if_match))
+        }
+
+        #[post("/v1/todos/:todo_key/:action")]
+        fn action(
+            &self,
+            head: Head,
+            todo_key: String,
+            action: String,
+            x_tenant: Option<String>,
+            if_match: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Act(todo_key, action), conditional(x_tenant, if_match))
+        }
+
+        #[get("/v1/todos/:todo_key/history")]
+        fn history(
+            &self,
+            head: Head,
+            todo_key: String,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::History(todo_key), tenant(x_tenant))
+        }
+
+        #[get("/v1/todos/:todo_key/history/:rev")]
+        fn revision(
+            &self,
+            head: Head,
+            todo_key: String,
+            rev: String,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Revision(todo_key, rev), tenant(x_tenant))
+        }
+
+        #[get("/v1/todos/:todo_key/history/diff/:from/:to")]
+        fn diff(
+            &self,
+            head: Head,
+            todo_key: String,
+            from: String,
+            to: String,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Diff(todo_key, from, to), tenant(x_tenant))
+        }
+
+        #[post("/v1/todos/:todo_key/history/:rev/rollback")]
+        fn rollback(
+            &self,
+            head: Head,
+            todo_key: String,
+            rev: String,
+            x_tenant: Option<String>,
+            if_match: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Rollback(todo_key, rev), conditional(x_tenant, if_match))
+        }
+
+        #[get("/v1/trash")]
+        fn trash(
+            &self,
+            head: Head,
+            query_string: Vec<u8>,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Trash, service::Request {
+                tenant: x_tenant,
+                query: query(query_string),
+                ..service::Request::default()
+            })
+        }
+
+        #[post("/v1/trash/:todo_key/restore")]
+        fn restore(
+            &self,
+            head: Head,
+            todo_key: String,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Restore(todo_key), tenant(x_tenant))
+        }
+
+        #[delete("/v1/trash/:todo_key")]
+        fn purge(
+            &self,
+            head: Head,
+            todo_key: String,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.serve(head, &Endpoint::Purge(todo_key), tenant(x_tenant))
+        }
+
+        // The routes served before /v1, deprecated.
+
+        #[get("/todo/list")]
+        fn todo_list(
+            &self,
+            head: Head,
+            query_string: Vec<u8>,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::List, service::Request {
+                tenant: x_tenant,
+                query: query(query_string),
+                ..service::Request::default()
+            })
+        }
+
+        #[get("/todo/fetch/:todo_key")]
+        fn todo_fetch(
+            &self,
+            head: Head,
+            todo_key: String,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Fetch(todo_key), tenant(x_tenant))
+        }
+
+        #[post("/todo/create")]
+        fn todo_create(
+            &self,
+            head: Head,
+            body: Body,
+            x_tenant: Option<String>,
+            idempotency_key: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Create, service::Request {
+                tenant: x_tenant,
+                idempotency_key,
+                body: body.0,
+                ..service::Request::default()
+            })
+        }
+
+        #[patch("/todo/update")]
+        fn todo_update(
+            &self,
+            head: Head,
+            body: Body,
+            x_tenant: Option<String>,
+            if_match: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Edit(None), service::Request {
+                tenant: x_tenant,
+                if_match,
+                body: body.0,
+                ..service::Request::default()
+            })
+        }
+
+        #[patch("/todo/update/:todo_key")]
+        fn todo_update_key(
+            &self,
+            head: Head,
+            todo_key: String,
+            body: Body,
+            x_tenant: Option<String>,
+            if_match: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Edit(Some(todo_key)), service::Request {
+                tenant: x_tenant,
+                if_match,
+                body: body.0,
+                ..service::Request::default()
+            })
+        }
+
+        #[put("/todo/replace")]
+        fn todo_replace(
+            &self,
+            head: Head,
+            body: Body,
+            x_tenant: Option<String>,
+            if_match: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Replace(None), service::Request {
+                tenant: x_tenant,
+                if_match,
+                body: body.0,
+                ..service::Request::default()
+            })
+        }
+
+        #[delete("/todo/delete/:todo_key")]
+        fn todo_delete(
+            &self,
+            head: Head,
+            todo_key: String,
+            x_tenant: Option<String>,
+            if_match: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Delete(todo_key), conditional(x_tenant, if_match))
+        }
+
+        #[post("/todo/:todo_key/:action")]
+        fn todo_action(
+            &self,
+            head: Head,
+            todo_key: String,
+            action: String,
+            x_tenant: Option<String>,
+            if_match: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Act(todo_key, action), conditional(x_tenant, if_match))
+        }
 
-/// This will be the JSON response
-#[derive(Response)]
-struct HelloResponse {
-    message: &'static str,
+        #[post("/todo/bulk")]
+        fn todo_bulk(
+            &self,
+            head: Head,
+            body: Body,
+            query_string: Vec<u8>,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Bulk, service::Request {
+                tenant: x_tenant,
+                query: query(query_string),
+                body: body.0,
+                ..service::Request::default()
+            })
+        }
+
+        #[get("/todo/trash")]
+        fn todo_trash(
+            &self,
+            head: Head,
+            query_string: Vec<u8>,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Trash, service::Request {
+                tenant: x_tenant,
+                query: query(query_string),
+                ..service::Request::default()
+            })
+        }
+
+        #[post("/todo/trash/:todo_key/restore")]
+        fn todo_restore(
+            &self,
+            head: Head,
+            todo_key: String,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Restore(todo_key), tenant(x_tenant))
+        }
+
+        #[delete("/todo/trash/:todo_key")]
+        fn todo_purge(
+            &self,
+            head: Head,
+            todo_key: String,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Purge(todo_key), tenant(x_tenant))
+        }
+
+        #[get("/todo/history/:todo_key")]
+        fn todo_history(
+            &self,
+            head: Head,
+            todo_key: String,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::History(todo_key), tenant(x_tenant))
+        }
+
+        #[get("/todo/history/:todo_key/:rev")]
+        fn todo_revision(
+            &self,
+            head: Head,
+            todo_key: String,
+            rev: String,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Revision(todo_key, rev), tenant(x_tenant))
+        }
+
+        #[get("/todo/history/:todo_key/diff/:from/:to")]
+        fn todo_diff(
+            &self,
+            head: Head,
+            todo_key: String,
+            from: String,
+            to: String,
+            x_tenant: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Diff(todo_key, from, to), tenant(x_tenant))
+        }
+
+        #[post("/todo/history/:todo_key/:rev/rollback")]
+        fn todo_rollback(
+            &self,
+            head: Head,
+            todo_key: String,
+            rev: String,
+            x_tenant: Option<String>,
+            if_match: Option<String>,
+        ) -> Reply {
+            self.legacy(head, &Endpoint::Rollback(todo_key, rev), conditional(x_tenant, if_match))
+        }
+    }
 }
 
-#[derive(Shrinkwrap, Debug, Response, Serialize, Extract)]
-// #[shrinkwrap(mutable)]
-// #[shrinkwrap(transformers)]
-struct Todo(vms::Todo);
-impl From<vms::Todo> for Todo {
-    fn from(vtd: vms::Todo) -> Self {
-        Todo(vtd)
+impl TodoResource {
+    fn serve(&self, head: Head, endpoint: &Endpoint, request: service::Request) -> Reply {
+        let request = head.request(request);
+        Ok(response(self.service.handle(endpoint, &request)))
+    }
+
+    /// serve, for a deprecated route.
+    fn legacy(&self, head: Head, endpoint: &Endpoint, request: service::Request) -> Reply {
+        let request = head.request(request);
+        let answer = self.service.handle(endpoint, &request);
+        Ok(response(answer.deprecated(endpoint)))
     }
 }
-impl Todo {
-    pub(crate) fn map_vec(vtd: Vec<vms::Todo>) -> Vec<Todo> {
-        let mut res = vec![];
-        for item in vtd {
-            res.push(Todo(item));
+
+/// What every endpoint reads of a request whichever route it came by, its path and the formats
+/// of its body and of the answer. tower-web fills handler arguments of this type from any request.
+struct Head {
+    path: String,
+    content_type: Option<String>,
+    accept: Option<String>,
+}
+impl Head {
+    /// request, with the head.
+    fn request(self, request: service::Request) -> service::Request {
+        service::Request {
+            path: self.path,
+            content_type: self.content_type,
+            accept: self.accept,
+            ..request
         }
-        res
     }
 }
+impl<B: BufStream> Extract<B> for Head {
+    type Future = Immediate<Self>;
 
-#[derive(Shrinkwrap, Debug, Extract)]
-struct ListOptions(vms::ListOptions);
+    fn extract(context: &Context) -> Self::Future {
+        let request = context.request();
+        let header = |name: &str| {
+            let values: Vec<_> = request
+                .headers()
+                .get_all(name)
+                .iter()
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                .collect();
+            Some(values.join(",")).filter(|_| !values.is_empty())
+        };
+        Immediate::ok(Head {
+            path: request.uri().path().to_owned(),
+            content_type: header(service::CONTENT_TYPE_HEADER),
+            accept: header(service::ACCEPT_HEADER),
+        })
+    }
+}
 
-impl_web! {
-    impl HelloWorld {
-        #[get("/todo/list")]
-        #[content_type("json")]
-        fn todo_list(&self, query_string: ListOptions) -> Result<Vec<Todo>, Value> {
-            match vms::Todo::list(query_string.limit.unwrap_or_else(|| 100u64)) {
-                Ok(resp) => {
-                    let res: Vec<Todo> = Todo::map_vec(resp);
-                    Ok(res)
-                },
-                Err(e) => Err(e),
-            }
-        }
+/// The body of a request, tower-web fills handler arguments named `body` of this type.
+/// Bodies larger than MAX_BODY_LEN are turned away with 413, unread when their Content-Length
+/// says so up front, and otherwise as soon as reading them goes past MAX_BODY_LEN.
+struct Body(Vec<u8>);
+impl<B: BufStream> Extract<B> for Body {
+    type Future = ReadBody<B>;
 
-        #[get("/todo/fetch/:todo_key")]
-        #[content_type("json")]
-        fn todo_fetch(&self, todo_key: String) -> Result<Todo, Value> {
-            match vms::Todo::fetch(&todo_key) {
-                Ok(resp) => Ok(Todo(resp)),
-                Err(e) => Err(e),
-            }
+    fn extract(_: &Context) -> Self::Future {
+        ReadBody {
+            stream: None,
+            body: vec![],
+            announced_too_large: false,
         }
+    }
 
-        #[get("/todo/create")]
-        #[content_type("json")]
-        fn todo_create(&self, body: Todo) -> Result<Todo, Value> {
-            match vms::Todo::create(body.0) {
-                Ok(resp) => Ok(Todo(resp)),
-                Err(e) => Err(e),
-            }
+    fn extract_body(context: &Context, body: B) -> Self::Future {
+        let announced = context
+            .request()
+            .headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+        ReadBody {
+            stream: Some(body),
+            body: vec![],
+            announced_too_large: announced.is_some_and(|length| length > MAX_BODY_LEN as u64),
         }
+    }
 
-        #[get("/todo/update")]
-        #[content_type("json")]
-        fn todo_update(&self, body: serde_json::Value) -> Result<Todo, Value> {
-            match vms::Todo::update(body) {
-                Ok(resp) => Ok(Todo(resp)),
-                Err(e) => Err(e),
-            }
-        }
+    /// Like the Vec<u8> it replaces, from the body when the argument is named `body`.
+    fn requires_body(callsite: &CallSite) -> bool {
+        <Vec<u8> as Extract<B>>::requires_body(callsite)
+    }
+}
 
-        #[get("/todo/replace")]
-        #[content_type("json")]
-        fn todo_replace(&self, body: Todo) -> Result<Todo, Value> {
-            match vms::Todo::replace(body.0) {
-                Ok(resp) => Ok(Todo(resp)),
-                Err(e) => Err(e),
+/// Reads a Body, chunk by chunk.
+struct ReadBody<B> {
+    /// None once the body has been read.
+    stream: Option<B>,
+    body: Vec<u8>,
+    /// Whether the Content-Length is larger than MAX_BODY_LEN.
+    announced_too_large: bool,
+}
+impl<B: BufStream> ExtractFuture for ReadBody<B> {
+    type Item = Body;
+
+    fn poll(&mut self) -> Poll<(), extract::Error> {
+        loop {
+            if self.announced_too_large || self.body.len() > MAX_BODY_LEN {
+                return Err(tower_web::Error::from(http::StatusCode::PAYLOAD_TOO_LARGE).into());
+            }
+            let stream = match &mut self.stream {
+                Some(stream) => stream,
+                None => return Ok(Async::Ready(())),
+            };
+            let chunk = stream
+                .poll()
+                .map_err(|_| extract::Error::invalid_argument(&"Couldn't read the body."))?;
+            match chunk {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => self.stream = None,
+                Async::Ready(Some(mut chunk)) => {
+                    while chunk.has_remaining() {
+                        let read = chunk.bytes().len();
+                        self.body.extend_from_slice(chunk.bytes());
+                        chunk.advance(read);
+                    }
+                }
             }
         }
+    }
 
-        #[get("/todo/delete/:todo_key")]
-        #[content_type("json")]
-        fn todo_delete(&self, todo_key: String) -> Result<Todo, Value> {
-            match vms::Todo::delete(&todo_key) {
-                Ok(resp) => Ok(Todo(resp)),
-                Err(e) => Err(e),
-            }
+    fn extract(self) -> Body {
+        Body(self.body)
+    }
+}
+
+/// A request carrying only the tenant.
+fn tenant(x_tenant: Option<String>) -> service::Request {
+    service::Request {
+        tenant: x_tenant,
+        ..service::Request::default()
+    }
+}
+
+/// A request carrying the tenant and the revision it expects.
+fn conditional(x_tenant: Option<String>, if_match: Option<String>) -> service::Request {
+    service::Request {
+        if_match,
+        ..tenant(x_tenant)
+    }
+}
+
+fn query(query_string: Vec<u8>) -> String {
+    String::from_utf8_lossy(&query_string).into_owned()
+}
+
+/// What every handler answers, tower-web wants handlers to return a `Result`.
+type Reply = Result<http::Response<Bytes>, tower_web::Error>;
+
+/// The tower-web response to send for response, bodies may be binary.
+fn response(response: service::Response) -> http::Response<Bytes> {
+    let mut http_response = http::Response::new(Bytes::from(response.body));
+    *http_response.status_mut() = http::StatusCode::from_u16(response.status)
+        .unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR);
+    for (name, value) in response.headers {
+        if let Ok(value) = http::header::HeaderValue::from_str(&value) {
+            http_response.headers_mut().insert(name, value);
         }
     }
+    http_response
+}
+
+/// Errors raised by tower-web itself, e.g. an unknown route.
+fn framework_error(e: &tower_web::Error) -> service::Response {
+    let status = e.status_code();
+    if status == http::StatusCode::NOT_FOUND {
+        service::Response::no_route()
+    } else if status == http::StatusCode::PAYLOAD_TOO_LARGE {
+        service::Response::body_too_large()
+    } else if status.is_client_error() {
+        service::Response::error(&TodoError::BadRequest(e.to_string()))
+    } else {
+        service::Response::error(&TodoError::Internal(e.to_string()))
+    }
 }
 
 pub fn main() {
-    let addr = "127.0.0.1:8080".parse().expect("Invalid address");
+    let config = vms::DbConfig::from_env().expect("Invalid configuration");
+    let repo = vms::TodoRepository::open(&config).expect("Could not open database");
+    let addr = listen_address("127.0.0.1:8080").expect("Invalid configuration");
     println!("Listening on http://{}", addr);
 
     ServiceBuilder::new()
-        .resource(HelloWorld)
+        .resource(TodoResource {
+            service: TodoService::new(repo),
+        })
+        .catch(|_: &http::Request<()>, e: tower_web::Error| {
+            Ok::<_, tower_web::Error>(response(framework_error(&e)))
+        })
         .run(&addr)
         .unwrap();
 }
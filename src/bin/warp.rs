@@ -1,137 +1,435 @@
+use various_micro_services::service::{listen_address, TodoService};
+use various_micro_services::{DbConfig, TodoRepository};
+
 #[tokio::main]
 async fn main() {
-    let routes = filters::todo();
+    let config = DbConfig::from_env().expect("Invalid configuration");
+    let repo = TodoRepository::open(&config).expect("Could not open database");
+    let addr = listen_address("127.0.0.1:3030").expect("Invalid configuration");
+    let routes = filters::todo(TodoService::new(repo));
 
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+    println!("Listening on http://{}", addr);
+    warp::serve(routes).run(addr).await;
 }
 
 mod handlers {
+    use super::filters::TooLarge;
     use std::convert::Infallible;
-    use various_micro_services::{Create, Delete, Fetch, List, ListOptions, Replace, Todo, Update};
+    use various_micro_services::service::{self, Endpoint, TodoService};
+    use various_micro_services::TodoError;
+    use warp::http::{HeaderValue, StatusCode};
 
-    pub async fn todo_list(opts: ListOptions) -> Result<impl warp::Reply, Infallible> {
-        match Todo::list(opts.limit.unwrap_or_else(|| 100u64)) {
-            Ok(resp) => Ok(warp::reply::json(&resp)),
-            Err(e) => Ok(warp::reply::json(&e)),
-        }
+    /// Passes the request on to the service, on a thread which may block on the store.
+    pub async fn serve(
+        endpoint: Endpoint,
+        deprecated: bool,
+        request: service::Request,
+        service: TodoService,
+    ) -> Result<warp::reply::Response, Infallible> {
+        let answer = tokio::task::spawn_blocking(move || {
+            let answer = service.handle(&endpoint, &request);
+            if deprecated {
+                answer.deprecated(&endpoint)
+            } else {
+                answer
+            }
+        })
+        .await
+        .unwrap_or_else(|e| service::Response::error(&TodoError::Internal(e.to_string())));
+        Ok(response(answer))
     }
 
-    pub async fn todo_fetch(todo_key: String) -> Result<impl warp::Reply, Infallible> {
-        match Todo::fetch(&todo_key) {
-            Ok(resp) => Ok(warp::reply::json(&resp)),
-            Err(e) => Ok(warp::reply::json(&e)),
+    /// Answers requests no filter takes the way every server does.
+    pub async fn handle_rejection(
+        err: warp::Rejection,
+    ) -> Result<warp::reply::Response, Infallible> {
+        if err.find::<TooLarge>().is_some() {
+            Ok(response(service::Response::body_too_large()))
+        } else {
+            Ok(response(service::Response::no_route()))
         }
     }
 
-    pub async fn todo_create(todo: Todo) -> Result<impl warp::Reply, Infallible> {
-        match Todo::create(todo) {
-            Ok(resp) => Ok(warp::reply::json(&resp)),
-            Err(e) => Ok(warp::reply::json(&e)),
+    /// The warp response to send for response.
+    fn response(response: service::Response) -> warp::reply::Response {
+        let mut warp_response = warp::reply::Response::new(response.body.into());
+        *warp_response.status_mut() =
+            StatusCode::from_u16(response.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        for (name, value) in response.headers {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                warp_response.headers_mut().insert(name, value);
+            }
         }
+        warp_response
     }
+}
 
-    pub async fn todo_update(
-        todo_patch: serde_json::Value,
-    ) -> Result<impl warp::Reply, Infallible> {
-        match Todo::update(todo_patch) {
-            Ok(resp) => Ok(warp::reply::json(&resp)),
-            Err(e) => Ok(warp::reply::json(&e)),
-        }
+mod filters {
+    use super::handlers;
+    use std::convert::Infallible;
+    use tokio::stream::{Stream, StreamExt};
+    use various_micro_services::service::{self, Endpoint, TodoService, MAX_BODY_LEN};
+    use warp::filters::BoxedFilter;
+    use warp::http::HeaderMap;
+    use warp::hyper::body::Buf;
+    use warp::path::FullPath;
+    use warp::Filter;
+
+    /// The body of the request is larger than MAX_BODY_LEN.
+    #[derive(Debug)]
+    pub struct TooLarge;
+    impl warp::reject::Reject for TooLarge {}
+
+    /// The canonical and the deprecated Todo api filters combined.
+    pub fn todo(
+        service: TodoService,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone {
+        let v1 = v1()
+            .or(docs())
+            .unify()
+            .map(|endpoint| (endpoint, false))
+            .untuple_one();
+        let legacy = legacy().map(|endpoint| (endpoint, true)).untuple_one();
+        v1.or(legacy)
+            .unify()
+            .and(request())
+            .and(warp::any().map(move || service.clone()))
+            .and_then(handlers::serve)
+            .recover(handlers::handle_rejection)
+            .unify()
     }
 
-    pub async fn todo_replace(todo: Todo) -> Result<impl warp::Reply, Infallible> {
-        match Todo::replace(todo) {
-            Ok(resp) => Ok(warp::reply::json(&resp)),
-            Err(e) => Ok(warp::reply::json(&e)),
-        }
+    /// The OpenAPI document of the canonical routes, and the explorer trying them out.
+    pub fn docs() -> BoxedFilter<(Endpoint,)> {
+        // GET /openapi.json
+        let openapi = warp::path!("openapi.json")
+            .and(warp::get())
+            .map(|| Endpoint::OpenApi);
+        // GET /explorer
+        let explorer = warp::path!("explorer")
+            .and(warp::get())
+            .map(|| Endpoint::Explorer);
+        openapi.or(explorer).unify().boxed()
     }
 
-    pub async fn todo_delete(todo_key: String) -> Result<impl warp::Reply, Infallible> {
-        match Todo::delete(&todo_key) {
-            Ok(resp) => Ok(warp::reply::json(&resp)),
-            Err(e) => Ok(warp::reply::json(&e)),
-        }
+    /// The canonical routes, under /v1.
+    pub fn v1() -> BoxedFilter<(Endpoint,)> {
+        let todos = || warp::path("v1").and(warp::path("todos"));
+        let trash = || warp::path("v1").and(warp::path("trash"));
+        // GET /v1/todos?offset=3&limit=5 or /v1/todos?limit=5&after=<next_cursor>
+        let list = todos()
+            .and(warp::path::end())
+            .and(warp::get())
+            .map(|| Endpoint::List);
+        // POST /v1/todos with JSON body, and optionally an Idempotency-Key header
+        let create = todos()
+            .and(warp::path::end())
+            .and(warp::post())
+            .map(|| Endpoint::Create);
+        // POST /v1/todos/bulk?mode=best-effort with a JSON array of operations
+        let bulk = todos()
+            .and(warp::path!("bulk"))
+            .and(warp::post())
+            .map(|| Endpoint::Bulk);
+        // GET /v1/todos/:todo_key
+        let fetch = todos()
+            .and(warp::path!(String))
+            .and(warp::get())
+            .map(Endpoint::Fetch);
+        // PATCH /v1/todos/:todo_key with a merge patch or JSON Patch body
+        let edit = todos()
+            .and(warp::path!(String))
+            .and(warp::patch())
+            .map(|todo_key| Endpoint::Edit(Some(todo_key)));
+        // PUT /v1/todos/:todo_key with JSON body
+        let replace = todos()
+            .and(warp::path!(String))
+            .and(warp::put())
+            .map(|todo_key| Endpoint::Replace(Some(todo_key)));
+        // DELETE /v1/todos/:todo_key
+        let delete = todos()
+            .and(warp::path!(String))
+            .and(warp::delete())
+            .map(Endpoint::Delete);
+        // POST /v1/todos/:todo_key/start, or block, complete, cancel and reopen
+        let action = todos()
+            .and(warp::path!(String / String))
+            .and(warp::post())
+            .map(Endpoint::Act);
+        // GET /v1/todos/:todo_key/history
+        let history = todos()
+            .and(warp::path!(String / "history"))
+            .and(warp::get())
+            .map(Endpoint::History);
+        // GET /v1/todos/:todo_key/history/:rev
+        let revision = todos()
+            .and(warp::path!(String / "history" / String))
+            .and(warp::get())
+            .map(Endpoint::Revision);
+        // GET /v1/todos/:todo_key/history/diff/:from/:to
+        let diff = todos()
+            .and(warp::path!(String / "history" / "diff" / String / String))
+            .and(warp::get())
+            .map(Endpoint::Diff);
+        // POST /v1/todos/:todo_key/history/:rev/rollback
+        let rollback = todos()
+            .and(warp::path!(String / "history" / String / "rollback"))
+            .and(warp::post())
+            .map(Endpoint::Rollback);
+        // GET /v1/trash?offset=3&limit=5 or /v1/trash?limit=5&after=<next_cursor>
+        let list_trash = trash()
+            .and(warp::path::end())
+            .and(warp::get())
+            .map(|| Endpoint::Trash);
+        // POST /v1/trash/:todo_key/restore
+        let restore = trash()
+            .and(warp::path!(String / "restore"))
+            .and(warp::post())
+            .map(Endpoint::Restore);
+        // DELETE /v1/trash/:todo_key
+        let purge = trash()
+            .and(warp::path!(String))
+            .and(warp::delete())
+            .map(Endpoint::Purge);
+
+        list.or(create)
+            .unify()
+            .or(bulk)
+            .unify()
+            .or(fetch)
+            .unify()
+            .or(edit)
+            .unify()
+            .or(replace)
+            .unify()
+            .or(delete)
+            .unify()
+            .or(action)
+            .unify()
+            .or(history)
+            .unify()
+            .or(revision)
+            .unify()
+            .or(diff)
+            .unify()
+            .or(rollback)
+            .unify()
+            .or(list_trash)
+            .unify()
+            .or(restore)
+            .unify()
+            .or(purge)
+            .unify()
+            .boxed()
     }
-}
 
-mod filters {
-    use super::handlers;
-    use various_micro_services::{ListOptions, Todo};
-    use warp::Filter;
+    /// The 16 routes served before /v1, deprecated.
+    pub fn legacy() -> BoxedFilter<(Endpoint,)> {
+        warp::path("todo")
+            .and(
+                todo_list()
+                    .or(todo_fetch())
+                    .unify()
+                    .or(todo_create())
+                    .unify()
+                    .or(todo_update())
+                    .unify()
+                    .or(todo_update_key())
+                    .unify()
+                    .or(todo_replace())
+                    .unify()
+                    .or(todo_delete())
+                    .unify()
+                    .or(todo_bulk())
+                    .unify()
+                    .or(todo_trash())
+                    .unify()
+                    .or(todo_restore())
+                    .unify()
+                    .or(todo_purge())
+                    .unify()
+                    .or(todo_history())
+                    .unify()
+                    .or(todo_revision())
+                    .unify()
+                    .or(todo_diff())
+                    .unify()
+                    .or(todo_rollback())
+                    .unify()
+                    .or(todo_action())
+                    .unify(),
+            )
+            .boxed()
+    }
 
-    /// The 6 Todo api filters combined.
-    pub fn todo() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path("todo").and(
-            todo_list()
-                .or(todo_fetch())
-                .or(todo_create())
-                .or(todo_update())
-                .or(todo_replace())
-                .or(todo_delete()),
-        )
-    }
-
-    /// GET /todo/list/?offset=3&limit=5
-    pub fn todo_list() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("list")
-            .and(warp::get())
-            .and(warp::query::<ListOptions>())
-            .and_then(handlers::todo_list)
+    /// GET /todo/list?offset=3&limit=5 or /todo/list?limit=5&after=<next_cursor>
+    pub fn todo_list() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
+        warp::path!("list").and(warp::get()).map(|| Endpoint::List)
     }
 
     /// GET /todo/fetch/:todo_key
-    pub fn todo_fetch() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
-    {
+    pub fn todo_fetch() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
         warp::path!("fetch" / String)
             .and(warp::get())
-            .and_then(handlers::todo_fetch)
+            .map(Endpoint::Fetch)
     }
 
-    /// POST /todo/create with JSON body
-    pub fn todo_create() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
-    {
+    /// POST /todo/create with JSON body, and optionally an Idempotency-Key header
+    pub fn todo_create() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
         warp::path!("create")
             .and(warp::post())
-            .and(json_body())
-            .and_then(handlers::todo_create)
+            .map(|| Endpoint::Create)
     }
 
-    /// PATCH /todo/update with JSON body
-    pub fn todo_update() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
-    {
+    /// PATCH /todo/update with a merge patch body naming its _key
+    pub fn todo_update() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
         warp::path!("update")
             .and(warp::patch())
-            .and(json_value_body())
-            .and_then(handlers::todo_update)
+            .map(|| Endpoint::Edit(None))
     }
 
-    /// PUT /todo/replace with JSON body
-    pub fn todo_replace() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    /// PATCH /todo/update/:todo_key with a merge patch or JSON Patch body
+    pub fn todo_update_key() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone
     {
+        warp::path!("update" / String)
+            .and(warp::patch())
+            .map(|todo_key| Endpoint::Edit(Some(todo_key)))
+    }
+
+    /// PUT /todo/replace with JSON body
+    pub fn todo_replace() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
         warp::path!("replace")
             .and(warp::put())
-            .and(json_body())
-            .and_then(handlers::todo_replace)
+            .map(|| Endpoint::Replace(None))
     }
 
     /// DELETE /todo/delete/:todo_key
-    pub fn todo_delete() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
-    {
+    pub fn todo_delete() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
         warp::path!("delete" / String)
             .and(warp::delete())
-            .and_then(handlers::todo_delete)
+            .map(Endpoint::Delete)
+    }
+
+    /// POST /todo/bulk?mode=best-effort with a JSON array of operations
+    pub fn todo_bulk() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
+        warp::path!("bulk").and(warp::post()).map(|| Endpoint::Bulk)
+    }
+
+    /// GET /todo/trash?offset=3&limit=5 or /todo/trash?limit=5&after=<next_cursor>
+    pub fn todo_trash() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
+        warp::path!("trash")
+            .and(warp::get())
+            .map(|| Endpoint::Trash)
+    }
+
+    /// POST /todo/trash/:todo_key/restore
+    pub fn todo_restore() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
+        warp::path!("trash" / String / "restore")
+            .and(warp::post())
+            .map(Endpoint::Restore)
+    }
+
+    /// DELETE /todo/trash/:todo_key
+    pub fn todo_purge() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
+        warp::path!("trash" / String)
+            .and(warp::delete())
+            .map(Endpoint::Purge)
+    }
+
+    /// GET /todo/history/:todo_key
+    pub fn todo_history() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
+        warp::path!("history" / String)
+            .and(warp::get())
+            .map(Endpoint::History)
+    }
+
+    /// GET /todo/history/:todo_key/:rev
+    pub fn todo_revision() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
+        warp::path!("history" / String / String)
+            .and(warp::get())
+            .map(Endpoint::Revision)
     }
 
-    fn json_body() -> impl Filter<Extract = (Todo,), Error = warp::Rejection> + Clone {
-        // When accepting a body, we want a JSON body
-        // (and to reject huge payloads)...
-        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+    /// GET /todo/history/:todo_key/diff/:from/:to
+    pub fn todo_diff() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
+        warp::path!("history" / String / "diff" / String / String)
+            .and(warp::get())
+            .map(Endpoint::Diff)
     }
 
-    fn json_value_body(
-    ) -> impl Filter<Extract = (serde_json::Value,), Error = warp::Rejection> + Clone {
-        // When accepting a body, we want a JSON body
-        // (and to reject huge payloads)...
-        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+    /// POST /todo/history/:todo_key/:rev/rollback
+    pub fn todo_rollback() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
+        warp::path!("history" / String / String / "rollback")
+            .and(warp::post())
+            .map(Endpoint::Rollback)
+    }
+
+    /// POST /todo/:todo_key/start, or block, complete, cancel and reopen
+    pub fn todo_action() -> impl Filter<Extract = (Endpoint,), Error = warp::Rejection> + Clone {
+        warp::path!(String / String)
+            .and(warp::post())
+            .map(Endpoint::Act)
+    }
+
+    /// The headers, path, query and body the service reads, bodies up to MAX_BODY_LEN.
+    fn request() -> impl Filter<Extract = (service::Request,), Error = warp::Rejection> + Clone {
+        warp::header::headers_cloned()
+            .and(warp::path::full())
+            .and(query())
+            .and(body())
+            .map(
+                |headers: HeaderMap, path: FullPath, query: String, body: Vec<u8>| {
+                    service::Request::from_headers(
+                        |name| {
+                            let values: Vec<_> = headers
+                                .get_all(name)
+                                .iter()
+                                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                                .collect();
+                            Some(values.join(",")).filter(|_| !values.is_empty())
+                        },
+                        path.as_str().to_owned(),
+                        query,
+                        body,
+                    )
+                },
+            )
+    }
+
+    /// The raw query string, parsed by the service so every server reports bad queries alike.
+    fn query() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+        warp::query::raw().or_else(|_| async { Ok::<_, Infallible>((String::new(),)) })
+    }
+
+    /// The body, empty when the request has none. Bodies announcing more than MAX_BODY_LEN are
+    /// turned away unread, and reading any other stops once it goes past MAX_BODY_LEN.
+    fn body() -> impl Filter<Extract = (Vec<u8>,), Error = warp::Rejection> + Clone {
+        warp::header::optional::<u64>("content-length")
+            .and_then(|length: Option<u64>| async move {
+                match length {
+                    Some(length) if length > MAX_BODY_LEN as u64 => {
+                        Err(warp::reject::custom(TooLarge))
+                    }
+                    _ => Ok(()),
+                }
+            })
+            .untuple_one()
+            .and(warp::body::stream())
+            .and_then(read_capped)
+    }
+
+    /// All chunks, unless they add up to more than MAX_BODY_LEN.
+    async fn read_capped(
+        mut chunks: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+    ) -> Result<Vec<u8>, warp::Rejection> {
+        let mut body = vec![];
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.map_err(|_| warp::reject())?;
+            body.extend_from_slice(chunk.bytes());
+            if body.len() > MAX_BODY_LEN {
+                return Err(warp::reject::custom(TooLarge));
+            }
+        }
+        Ok(body)
     }
 }
@@ -0,0 +1,361 @@
+//! The one error type every operation reports, and how it maps onto HTTP.
+use crate::keys::KeyError;
+use crate::store::StoreError;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::any::Any;
+use std::fmt;
+
+/// Media type of error bodies, see RFC 7807.
+pub const PROBLEM_JSON_TYPE: &str = "application/problem+json";
+
+/// Problem types are this followed by the code of the kind of error.
+pub const PROBLEM_TYPE_PREFIX: &str = "urn:various-micro-services:problem:";
+
+/// A kind of error clients can tell apart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorKind {
+    /// Stable, machine readable name, never renamed nor reused.
+    pub code: &'static str,
+    /// HTTP status code to answer with.
+    pub status: u16,
+    /// Short summary, the same for every error of the kind.
+    pub title: &'static str,
+}
+impl ErrorKind {
+    /// The problem type URI of the kind.
+    pub fn problem_type(&self) -> String {
+        format!("{}{}", PROBLEM_TYPE_PREFIX, self.code)
+    }
+}
+
+/// Every kind of error the api answers with, in the order of the TodoError variants.
+pub const ERROR_CATALOGUE: [ErrorKind; 11] = [
+    ErrorKind {
+        code: "bad_request",
+        status: 400,
+        title: "The request could not be understood.",
+    },
+    ErrorKind {
+        code: "not_found",
+        status: 404,
+        title: "Nothing was found.",
+    },
+    ErrorKind {
+        code: "not_acceptable",
+        status: 406,
+        title: "The answer can't be sent in any format the request accepts.",
+    },
+    ErrorKind {
+        code: "conflict",
+        status: 409,
+        title: "The request clashes with the current state.",
+    },
+    ErrorKind {
+        code: "precondition_failed",
+        status: 412,
+        title: "The todo is not at the expected revision.",
+    },
+    ErrorKind {
+        code: "payload_too_large",
+        status: 413,
+        title: "The request body is larger than the server reads.",
+    },
+    ErrorKind {
+        code: "unsupported_media_type",
+        status: 415,
+        title: "The request body is in a format which isn't supported.",
+    },
+    ErrorKind {
+        code: "validation",
+        status: 422,
+        title: "The request content is not valid.",
+    },
+    ErrorKind {
+        code: "decode",
+        status: 500,
+        title: "A stored document could not be read.",
+    },
+    ErrorKind {
+        code: "storage_unavailable",
+        status: 503,
+        title: "The storage is unavailable.",
+    },
+    ErrorKind {
+        code: "internal",
+        status: 500,
+        title: "The server failed to handle the request.",
+    },
+];
+
+/// A field of a request body which is not valid, and why.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    /// Name of the field, as it is in the body.
+    pub field: String,
+    pub message: String,
+}
+
+/// Everything that can go wrong while serving a todo request.
+/// Each variant carries a human readable message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TodoError {
+    /// The request itself could not be understood, e.g. malformed JSON.
+    BadRequest(String),
+    /// No document with the requested key.
+    NotFound(String),
+    /// None of the formats the request's Accept header names can be answered in.
+    NotAcceptable(String),
+    /// The request clashes with the current state, e.g. a key which is already taken.
+    Conflict(String),
+    /// The document is not at the revision the request's If-Match header asks for.
+    PreconditionFailed(String),
+    /// The request body is larger than servers read, see service::MAX_BODY_LEN.
+    PayloadTooLarge(String),
+    /// The request's Content-Type names a format bodies can't be read from.
+    UnsupportedMediaType(String),
+    /// The request was understood but its content is not acceptable.
+    Validation(String),
+    /// Like Validation, for a body with fields which are not acceptable, see invalid_fields.
+    InvalidFields(String, Vec<FieldError>),
+    /// A stored document could not be decoded.
+    Decode(String),
+    /// The storage backend failed or is not reachable.
+    StorageUnavailable(String),
+    /// Anything else, a bug.
+    Internal(String),
+}
+
+impl TodoError {
+    /// An error for a body with invalid fields, telling what is wrong with each of them.
+    pub fn invalid_fields(fields: Vec<FieldError>) -> Self {
+        let detail: Vec<_> = fields
+            .iter()
+            .map(|field| format!("{}: {}", field.field, field.message))
+            .collect();
+        TodoError::InvalidFields(format!("Invalid fields, {}.", detail.join("; ")), fields)
+    }
+
+    /// The kind of error, from ERROR_CATALOGUE.
+    pub fn kind(&self) -> &'static ErrorKind {
+        let index = match self {
+            TodoError::BadRequest(_) => 0,
+            TodoError::NotFound(_) => 1,
+            TodoError::NotAcceptable(_) => 2,
+            TodoError::Conflict(_) => 3,
+            TodoError::PreconditionFailed(_) => 4,
+            TodoError::PayloadTooLarge(_) => 5,
+            TodoError::UnsupportedMediaType(_) => 6,
+            TodoError::Validation(_) | TodoError::InvalidFields(..) => 7,
+            TodoError::Decode(_) => 8,
+            TodoError::StorageUnavailable(_) => 9,
+            TodoError::Internal(_) => 10,
+        };
+        &ERROR_CATALOGUE[index]
+    }
+
+    /// HTTP status code to answer with.
+    pub fn status(&self) -> u16 {
+        self.kind().status
+    }
+
+    /// Stable, machine readable name of the kind of error.
+    pub fn code(&self) -> &'static str {
+        self.kind().code
+    }
+
+    /// The fields which are not valid, if that is what is wrong.
+    pub fn fields(&self) -> &[FieldError] {
+        match self {
+            TodoError::InvalidFields(_, fields) => fields,
+            _ => &[],
+        }
+    }
+
+    /// The RFC 7807 problem details of the error, for a request to instance.
+    pub fn problem<'a>(&'a self, instance: Option<&'a str>) -> ProblemDetails<'a> {
+        ProblemDetails {
+            error: self,
+            instance,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            TodoError::BadRequest(msg)
+            | TodoError::NotFound(msg)
+            | TodoError::NotAcceptable(msg)
+            | TodoError::Conflict(msg)
+            | TodoError::PreconditionFailed(msg)
+            | TodoError::PayloadTooLarge(msg)
+            | TodoError::UnsupportedMediaType(msg)
+            | TodoError::Validation(msg)
+            | TodoError::InvalidFields(msg, _)
+            | TodoError::Decode(msg)
+            | TodoError::StorageUnavailable(msg)
+            | TodoError::Internal(msg) => msg,
+        }
+    }
+
+    /// Stands in for a request handler which panicked.
+    /// The panic message is logged, the client only learns that something went wrong.
+    pub fn panicked(cause: &(dyn Any + Send)) -> Self {
+        let msg = cause
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| cause.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        log::error!("Request handler panicked: {}", msg);
+        TodoError::Internal("The server failed to handle the request.".to_owned())
+    }
+}
+
+impl fmt::Display for TodoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+impl std::error::Error for TodoError {}
+
+/// An error as RFC 7807 problem details:
+/// `{"type": type, "title": title, "status": status, "detail": message, "instance": path,
+/// "code": code}`, validation errors add the invalid `errors`.
+#[derive(Debug)]
+pub struct ProblemDetails<'a> {
+    error: &'a TodoError,
+    /// The path of the request which failed, if it is known.
+    instance: Option<&'a str>,
+}
+impl Serialize for ProblemDetails<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let kind = self.error.kind();
+        let mut body = serializer.serialize_struct("ProblemDetails", 7)?;
+        body.serialize_field("type", &kind.problem_type())?;
+        body.serialize_field("title", kind.title)?;
+        body.serialize_field("status", &kind.status)?;
+        body.serialize_field("detail", self.error.message())?;
+        if let Some(instance) = self.instance {
+            body.serialize_field("instance", instance)?;
+        } else {
+            body.skip_field("instance")?;
+        }
+        body.serialize_field("code", kind.code)?;
+        if kind.status == 422 {
+            body.serialize_field("errors", self.error.fields())?;
+        } else {
+            body.skip_field("errors")?;
+        }
+        body.end()
+    }
+}
+
+/// Errors serialize as their problem details, without an instance.
+impl Serialize for TodoError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.problem(None).serialize(serializer)
+    }
+}
+
+impl From<StoreError> for TodoError {
+    fn from(e: StoreError) -> Self {
+        TodoError::StorageUnavailable(e.to_string())
+    }
+}
+
+impl From<KeyError> for TodoError {
+    fn from(e: KeyError) -> Self {
+        match e {
+            KeyError::Storage(e) => e.into(),
+            KeyError::Invalid(msg) => TodoError::Validation(msg),
+        }
+    }
+}
+
+impl From<serde_cbor::Error> for TodoError {
+    fn from(e: serde_cbor::Error) -> Self {
+        TodoError::Decode(format!("Stored document is unreadable: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn status_mapping() {
+        let cases = vec![
+            (TodoError::BadRequest(String::new()), 400),
+            (TodoError::NotFound(String::new()), 404),
+            (TodoError::NotAcceptable(String::new()), 406),
+            (TodoError::Conflict(String::new()), 409),
+            (TodoError::PreconditionFailed(String::new()), 412),
+            (TodoError::PayloadTooLarge(String::new()), 413),
+            (TodoError::UnsupportedMediaType(String::new()), 415),
+            (TodoError::Validation(String::new()), 422),
+            (TodoError::invalid_fields(vec![]), 422),
+            (TodoError::Decode(String::new()), 500),
+            (TodoError::StorageUnavailable(String::new()), 503),
+            (TodoError::Internal(String::new()), 500),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error.status(), status, "{}", error);
+        }
+    }
+
+    #[test]
+    fn body_shape() {
+        let error = TodoError::NotFound("No todo with _key \"7\".".to_owned());
+        assert_eq!(
+            serde_json::to_value(error.problem(Some("/v1/todos/7"))).unwrap(),
+            json!({
+                "type": "urn:various-micro-services:problem:not_found",
+                "title": "Nothing was found.",
+                "status": 404,
+                "detail": "No todo with _key \"7\".",
+                "instance": "/v1/todos/7",
+                "code": "not_found"
+            })
+        );
+
+        let error = TodoError::invalid_fields(vec![FieldError {
+            field: "title".to_owned(),
+            message: "is required".to_owned(),
+        }]);
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            json!({
+                "type": "urn:various-micro-services:problem:validation",
+                "title": "The request content is not valid.",
+                "status": 422,
+                "detail": "Invalid fields, title: is required.",
+                "code": "validation",
+                "errors": [{"field": "title", "message": "is required"}]
+            })
+        );
+    }
+
+    #[test]
+    fn catalogue_codes_are_unique() {
+        let mut codes: Vec<_> = ERROR_CATALOGUE.iter().map(|kind| kind.code).collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), ERROR_CATALOGUE.len());
+    }
+
+    #[test]
+    fn conversions() {
+        let storage: TodoError = StoreError("disk on fire".to_owned()).into();
+        assert_eq!(storage.status(), 503);
+        let key: TodoError = KeyError::Invalid("bad key".to_owned()).into();
+        assert_eq!(key, TodoError::Validation("bad key".to_owned()));
+    }
+
+    #[test]
+    fn panics_become_internal_errors() {
+        let cause = std::panic::catch_unwind(|| panic!("index out of bounds")).unwrap_err();
+        let error = TodoError::panicked(&*cause);
+        assert_eq!(error.status(), 500);
+        assert!(!error.message().contains("index out of bounds"));
+    }
+}
@@ -0,0 +1,228 @@
+//! The formats request and response bodies may be in, and picking them from a request's
+//! `Content-Type` and `Accept` headers.
+use crate::TodoError;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// A format bodies may be in, every one of them carries the same serde data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    /// RFC 8949 CBOR, the format todos are stored in.
+    Cbor,
+    MessagePack,
+    Yaml,
+}
+impl Format {
+    /// Every format, the one answered in when a request accepts several first.
+    pub const ALL: [Format; 4] = [
+        Format::Json,
+        Format::Cbor,
+        Format::MessagePack,
+        Format::Yaml,
+    ];
+
+    /// The media type answers in the format are sent as.
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Cbor => "application/cbor",
+            Format::MessagePack => "application/msgpack",
+            Format::Yaml => "application/yaml",
+        }
+    }
+
+    /// Every media type naming the format, the unregistered ones clients still send included.
+    fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Format::Json => &["application/json"],
+            Format::Cbor => &["application/cbor"],
+            Format::MessagePack => &[
+                "application/msgpack",
+                "application/x-msgpack",
+                "application/vnd.msgpack",
+            ],
+            Format::Yaml => &["application/yaml", "application/x-yaml", "text/yaml"],
+        }
+    }
+
+    /// The format a request body sent with content_type is in, JSON when it names none.
+    /// Any `+json` type, like a JSON Patch, is JSON.
+    pub fn of_body(content_type: Option<&str>) -> Result<Self, TodoError> {
+        let media_type = match content_type.map(media_type) {
+            None => return Ok(Format::Json),
+            Some(media_type) if media_type.ends_with("+json") => return Ok(Format::Json),
+            Some(media_type) => media_type,
+        };
+        Format::ALL
+            .iter()
+            .copied()
+            .find(|format| format.media_types().contains(&media_type.as_str()))
+            .ok_or_else(|| {
+                TodoError::UnsupportedMediaType(format!(
+                    "Can't read {:?} bodies, send {}.",
+                    media_type,
+                    supported()
+                ))
+            })
+    }
+
+    /// The format to answer a request sent with an accept header like
+    /// `application/cbor, application/json;q=0.5` in, JSON when it has none.
+    /// The most specific media range matching a format gives its quality, see RFC 9110.
+    pub fn negotiate(accept: Option<&str>) -> Result<Self, TodoError> {
+        let accept = match accept.map(str::trim) {
+            None | Some("") => return Ok(Format::Json),
+            Some(accept) => accept,
+        };
+        let ranges: Vec<(String, f32)> = accept.split(',').map(media_range).collect();
+        let quality = |format: Format| {
+            ranges
+                .iter()
+                .filter_map(|(range, q)| Some((format.specificity(range)?, *q)))
+                .max_by_key(|(specificity, _)| *specificity)
+                .map_or(0.0, |(_, q)| q)
+        };
+        let mut best: Option<(Format, f32)> = None;
+        for format in Format::ALL.iter().copied() {
+            let q = quality(format);
+            if q > 0.0 && best.is_none_or(|(_, best)| q > best) {
+                best = Some((format, q));
+            }
+        }
+        best.map(|(format, _)| format).ok_or_else(|| {
+            TodoError::NotAcceptable(format!(
+                "Can't answer in {:?}, accept {}.",
+                accept,
+                supported()
+            ))
+        })
+    }
+
+    /// How closely range, like `application/cbor`, `application/*` or `*/*`, names the format,
+    /// None when it doesn't.
+    fn specificity(self, range: &str) -> Option<u8> {
+        let types = self.media_types();
+        if types.contains(&range) {
+            Some(2)
+        } else if range == "*/*" {
+            Some(0)
+        } else {
+            let kind = range.strip_suffix("/*")?;
+            types
+                .iter()
+                .any(|media_type| media_type.split('/').next() == Some(kind))
+                .then_some(1)
+        }
+    }
+
+    /// value in the format.
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, TodoError> {
+        let encoded = match self {
+            Format::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Format::Cbor => serde_cbor::to_vec(value).map_err(|e| e.to_string()),
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::to_vec(value).map_err(|e| e.to_string()),
+        };
+        encoded.map_err(TodoError::Internal)
+    }
+
+    /// Reads a request body in the format.
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, TodoError> {
+        let decoded = match self {
+            Format::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+            Format::Cbor => serde_cbor::from_slice(body).map_err(|e| e.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(body).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::from_slice(body).map_err(|e| e.to_string()),
+        };
+        decoded.map_err(|e| TodoError::BadRequest(format!("Couldn't parse request body: {}", e)))
+    }
+}
+
+/// The media type of a header value, lowercase and without parameters like charset.
+fn media_type(value: &str) -> String {
+    value
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// A media range of an Accept header and its quality, 1 unless its `q` parameter says otherwise.
+fn media_range(range: &str) -> (String, f32) {
+    let q = range
+        .split(';')
+        .skip(1)
+        .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+        .find_map(|q| q.trim().parse().ok())
+        .unwrap_or(1.0);
+    (media_type(range), q)
+}
+
+fn supported() -> String {
+    let types: Vec<_> = Format::ALL
+        .iter()
+        .map(|format| format.media_type())
+        .collect();
+    types.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn negotiation() {
+        let negotiate = |accept| Format::negotiate(accept).map_err(|e| e.status());
+        assert_eq!(negotiate(None), Ok(Format::Json));
+        assert_eq!(negotiate(Some("*/*")), Ok(Format::Json));
+        assert_eq!(negotiate(Some("application/cbor")), Ok(Format::Cbor));
+        assert_eq!(
+            negotiate(Some("application/json;q=0.5, application/x-msgpack")),
+            Ok(Format::MessagePack)
+        );
+        assert_eq!(
+            negotiate(Some("text/html, text/*;q=0.1, */*;q=0.8")),
+            Ok(Format::Json)
+        );
+        assert_eq!(negotiate(Some("text/*")), Ok(Format::Yaml));
+        assert_eq!(
+            negotiate(Some("application/*, application/json;q=0")),
+            Ok(Format::Cbor)
+        );
+        assert_eq!(negotiate(Some("text/html")), Err(406));
+        assert_eq!(negotiate(Some("application/json;q=0")), Err(406));
+
+        let of_body = |content_type| Format::of_body(content_type).map_err(|e| e.status());
+        assert_eq!(of_body(None), Ok(Format::Json));
+        assert_eq!(
+            of_body(Some("application/json-patch+json; charset=utf-8")),
+            Ok(Format::Json)
+        );
+        assert_eq!(of_body(Some("Application/YAML")), Ok(Format::Yaml));
+        assert_eq!(of_body(Some("text/plain")), Err(415));
+    }
+
+    #[test]
+    fn round_trips() {
+        let todo = json!({"_key": "7", "title": "milk", "timestamp": 0, "status": "New",
+            "tags": ["shop"], "metadata": {"aisle": 3, "cold": true}});
+        for format in Format::ALL.iter().copied() {
+            let encoded = format.encode(&todo).unwrap();
+            assert_eq!(
+                format.decode::<serde_json::Value>(&encoded).unwrap(),
+                todo,
+                "{:?}",
+                format
+            );
+        }
+        assert_eq!(
+            Format::Cbor
+                .decode::<serde_json::Value>(b"{")
+                .unwrap_err()
+                .status(),
+            400
+        );
+    }
+}
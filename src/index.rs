@@ -0,0 +1,79 @@
+//! Secondary indexes over todos, kept in trees next to the documents they point to.
+//!
+//! Index entries have empty values, everything is in the key:
+//! - `by_status`: status, timestamp, document key, so one status in a time range is one scan.
+//! - `by_timestamp`: timestamp, document key.
+//!
+//! Timestamps are stored big endian with the sign bit flipped, so byte order is numeric order.
+use crate::{ListOptions, Todo, TodoStatus};
+
+/// Entity names of the index trees, scoped like every other tree.
+pub(crate) const BY_STATUS: &str = "todos.by_status";
+pub(crate) const BY_TIMESTAMP: &str = "todos.by_timestamp";
+
+/// Bumped whenever the layout of index entries changes, so stores get reindexed on open.
+pub(crate) const VERSION: u8 = 2;
+
+const TIMESTAMP_LEN: usize = 8;
+
+/// Sorts after every index key, document keys are UTF-8 and never start with 0xff.
+const END: [u8; TIMESTAMP_LEN + 1] = [0xff; TIMESTAMP_LEN + 1];
+
+fn timestamp(timestamp: i64) -> [u8; TIMESTAMP_LEN] {
+    ((timestamp as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+/// Index keys of doc stored under key, by index entity.
+pub(crate) fn entries(key: &[u8], doc: &Todo) -> [(&'static str, Vec<u8>); 2] {
+    let by_timestamp = [&timestamp(doc.timestamp)[..], key].concat();
+    let by_status = [&[doc.status as u8][..], &by_timestamp].concat();
+    [(BY_STATUS, by_status), (BY_TIMESTAMP, by_timestamp)]
+}
+
+/// The document key an entry of the index entity points to.
+pub(crate) fn doc_key<'a>(entity: &str, index_key: &'a [u8]) -> &'a [u8] {
+    let skip = match entity {
+        BY_STATUS => 1 + TIMESTAMP_LEN,
+        _ => TIMESTAMP_LEN,
+    };
+    index_key.get(skip..).unwrap_or_default()
+}
+
+/// A range of index keys, from start up to but excluding end.
+pub(crate) struct Scan {
+    pub entity: &'static str,
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+}
+
+/// The index scans which find every todo passing the status and timestamp filters of options.
+/// None when options filter on neither, the index can't help then.
+pub(crate) fn scans(options: &ListOptions) -> Option<Vec<Scan>> {
+    let start = timestamp(options.since.unwrap_or(i64::MIN)).to_vec();
+    let end = match options.until.and_then(|until| until.checked_add(1)) {
+        Some(after_until) => timestamp(after_until).to_vec(),
+        None => END.to_vec(),
+    };
+    if !options.status.is_empty() {
+        let mut statuses: Vec<TodoStatus> = options.status.clone();
+        statuses.sort();
+        statuses.dedup();
+        let scans = statuses
+            .into_iter()
+            .map(|status| Scan {
+                entity: BY_STATUS,
+                start: [&[status as u8][..], &start].concat(),
+                end: [&[status as u8][..], &end].concat(),
+            })
+            .collect();
+        Some(scans)
+    } else if options.since.is_some() || options.until.is_some() {
+        Some(vec![Scan {
+            entity: BY_TIMESTAMP,
+            start,
+            end,
+        }])
+    } else {
+        None
+    }
+}
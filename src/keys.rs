@@ -0,0 +1,182 @@
+//! Strategies for picking the `_key` of newly created documents.
+use crate::store::{StoreError, TodoStore};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Why no key could be handed out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyError {
+    /// The store failed to produce the next sequence number.
+    Storage(StoreError),
+    /// The client supplied key is unusable.
+    Invalid(String),
+}
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Storage(e) => write!(f, "Could not generate a key: {}", e),
+            KeyError::Invalid(msg) => f.write_str(msg),
+        }
+    }
+}
+impl std::error::Error for KeyError {}
+
+/// Picks the key of a document about to be created.
+pub trait KeyGenerator: Send + Sync {
+    /// Returns the key for the new document, requested is the `_key` the client sent, if any.
+    fn generate(&self, store: &dyn TodoStore, requested: &str) -> Result<String, KeyError>;
+
+    /// Whether calling generate again may yield a different key when the first one is taken.
+    fn retry_on_collision(&self) -> bool {
+        true
+    }
+}
+
+/// Atomic monotonic numbers from the store, zero padded so they also sort as strings.
+#[derive(Debug, Default)]
+pub struct SequenceKeys;
+impl KeyGenerator for SequenceKeys {
+    fn generate(&self, store: &dyn TodoStore, _requested: &str) -> Result<String, KeyError> {
+        let id = store.generate_id().map_err(KeyError::Storage)?;
+        Ok(format!("{:020}", id))
+    }
+}
+
+/// Random UUIDv4 keys.
+#[derive(Debug, Default)]
+pub struct UuidKeys;
+impl KeyGenerator for UuidKeys {
+    fn generate(&self, _store: &dyn TodoStore, _requested: &str) -> Result<String, KeyError> {
+        Ok(uuid::Uuid::new_v4().to_string())
+    }
+}
+
+/// ULID keys, these sort by creation time.
+#[derive(Debug, Default)]
+pub struct UlidKeys;
+impl KeyGenerator for UlidKeys {
+    fn generate(&self, _store: &dyn TodoStore, _requested: &str) -> Result<String, KeyError> {
+        Ok(ulid::Ulid::new().to_string())
+    }
+}
+
+/// Longest accepted slug.
+pub const MAX_SLUG_LEN: usize = 64;
+
+/// 1 to MAX_SLUG_LEN lowercase ascii letters, digits, `-` and `_`, starting with a letter or digit.
+pub fn is_slug(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_lowercase() || c.is_ascii_digit() => {}
+        _ => return false,
+    }
+    s.len() <= MAX_SLUG_LEN
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Uses the `_key` sent by the client, which has to be a slug.
+#[derive(Debug, Default)]
+pub struct ClientKeys;
+impl KeyGenerator for ClientKeys {
+    fn generate(&self, _store: &dyn TodoStore, requested: &str) -> Result<String, KeyError> {
+        if is_slug(requested) {
+            Ok(requested.to_owned())
+        } else {
+            Err(KeyError::Invalid(format!(
+                "_key {:?} must be 1 to {} lowercase letters, digits, '-' or '_', starting with a letter or digit.",
+                requested, MAX_SLUG_LEN
+            )))
+        }
+    }
+
+    fn retry_on_collision(&self) -> bool {
+        false
+    }
+}
+
+/// The key generators a deployment can be configured with.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum KeyStrategy {
+    #[default]
+    Sequence,
+    Uuid,
+    Ulid,
+    ClientSupplied,
+}
+impl KeyStrategy {
+    pub fn generator(self) -> Arc<dyn KeyGenerator> {
+        match self {
+            KeyStrategy::Sequence => Arc::new(SequenceKeys),
+            KeyStrategy::Uuid => Arc::new(UuidKeys),
+            KeyStrategy::Ulid => Arc::new(UlidKeys),
+            KeyStrategy::ClientSupplied => Arc::new(ClientKeys),
+        }
+    }
+}
+impl FromStr for KeyStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sequence" => Ok(Self::Sequence),
+            "uuid" => Ok(Self::Uuid),
+            "ulid" => Ok(Self::Ulid),
+            "client" => Ok(Self::ClientSupplied),
+            other => Err(format!("Unknown key strategy {:?}.", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn sequence_keys_sort_numerically() {
+        let store = MemoryStore::new();
+        let keys: Vec<String> = (0..12)
+            .map(|_| SequenceKeys.generate(&store, "").unwrap())
+            .collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+        assert_eq!(keys[9], "00000000000000000009");
+        assert_eq!(keys[10], "00000000000000000010");
+    }
+
+    #[test]
+    fn random_keys_are_distinct() {
+        let store = MemoryStore::new();
+        let a = UuidKeys.generate(&store, "").unwrap();
+        assert_eq!(a.len(), 36);
+        assert_ne!(a, UuidKeys.generate(&store, "").unwrap());
+
+        let b = UlidKeys.generate(&store, "").unwrap();
+        assert_eq!(b.len(), 26);
+        assert_ne!(b, UlidKeys.generate(&store, "").unwrap());
+    }
+
+    #[test]
+    fn client_keys_must_be_slugs() {
+        let store = MemoryStore::new();
+        assert_eq!(
+            ClientKeys.generate(&store, "buy-milk_2").unwrap(),
+            "buy-milk_2"
+        );
+        for bad in &["", "-milk", "Milk", "buy milk", "a/b", &"x".repeat(65)] {
+            assert!(ClientKeys.generate(&store, bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn strategy_names() {
+        assert_eq!("ULID".parse::<KeyStrategy>(), Ok(KeyStrategy::Ulid));
+        assert_eq!(
+            "client".parse::<KeyStrategy>(),
+            Ok(KeyStrategy::ClientSupplied)
+        );
+        assert!("random".parse::<KeyStrategy>().is_err());
+    }
+}
@@ -1,244 +1,991 @@
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use std::collections::BTreeSet;
 
-#[derive(Debug, Deserialize)]
+/// Which page of a collection to list, and which todos it is made of.
+/// Usually read from a `?offset=3&limit=5&status=New,Started&sort=-timestamp` query string.
+#[derive(Debug, Default, Clone, Deserialize)]
 pub struct ListOptions {
     pub offset: Option<u64>,
     pub limit: Option<u64>,
+    /// Opaque cursor from a previous page's `next_cursor`, the page starts right after it.
+    /// Offset is counted from the cursor when both are given.
+    pub after: Option<String>,
+    /// Only todos in one of these states, all states when empty.
+    #[serde(default, deserialize_with = "comma_separated")]
+    pub status: Vec<TodoStatus>,
+    /// Only todos with a timestamp (in milliseconds) at or after since.
+    pub since: Option<i64>,
+    /// Only todos with a timestamp (in milliseconds) at or before until.
+    pub until: Option<i64>,
+    /// Only todos whose title contains this, ignoring case.
+    pub title: Option<String>,
+    /// Order of the listing, key order when None.
+    pub sort: Option<Sort>,
 }
+impl ListOptions {
+    /// Page size when the request doesn't set a limit.
+    pub const DEFAULT_LIMIT: u64 = 100;
 
-#[derive(Serialize, Deserialize, Debug)]
+    /// Parses a list query string, every field is optional.
+    pub fn from_query(query: &str) -> Result<Self, TodoError> {
+        serde_urlencoded::from_str(query)
+            .map_err(|e| TodoError::BadRequest(format!("Invalid list query: {}", e)))
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit.unwrap_or(Self::DEFAULT_LIMIT)
+    }
+
+    /// Whether any filter narrows the listing down.
+    pub fn is_filtered(&self) -> bool {
+        !self.status.is_empty()
+            || self.since.is_some()
+            || self.until.is_some()
+            || self.title.is_some()
+    }
+
+    /// Whether todo passes every filter.
+    pub fn matches(&self, todo: &Todo) -> bool {
+        (self.status.is_empty() || self.status.contains(&todo.status))
+            && self.since.is_none_or(|since| todo.timestamp >= since)
+            && self.until.is_none_or(|until| todo.timestamp <= until)
+            && self
+                .title
+                .as_ref()
+                .is_none_or(|title| todo.title.to_lowercase().contains(&title.to_lowercase()))
+    }
+
+    fn query(&self) -> String {
+        let mut pairs = vec![("limit", self.limit().to_string())];
+        if let Some(offset) = self.offset {
+            pairs.push(("offset", offset.to_string()));
+        }
+        if let Some(after) = &self.after {
+            pairs.push(("after", after.clone()));
+        }
+        if !self.status.is_empty() {
+            let names: Vec<String> = self.status.iter().map(|s| format!("{:?}", s)).collect();
+            pairs.push(("status", names.join(",")));
+        }
+        if let Some(since) = self.since {
+            pairs.push(("since", since.to_string()));
+        }
+        if let Some(until) = self.until {
+            pairs.push(("until", until.to_string()));
+        }
+        if let Some(title) = &self.title {
+            pairs.push(("title", title.clone()));
+        }
+        if let Some(sort) = self.sort {
+            pairs.push(("sort", sort.name().to_owned()));
+        }
+        serde_urlencoded::to_string(pairs).unwrap_or_default()
+    }
+}
+
+/// `status=New,Started` lists both states.
+fn comma_separated<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<TodoStatus>, D::Error> {
+    String::deserialize(deserializer)?
+        .split(',')
+        .map(|name| name.trim().parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Orders a listing can be sorted in, ties are broken by key.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Sort {
+    #[serde(rename = "timestamp")]
+    Timestamp,
+    /// Newest first.
+    #[serde(rename = "-timestamp")]
+    TimestampDesc,
+    /// Alphabetical, ignoring case.
+    #[serde(rename = "title")]
+    Title,
+    /// In workflow order, New first and Cancelled last.
+    #[serde(rename = "status")]
+    Status,
+}
+impl Sort {
+    pub const ALL: [Sort; 4] = [
+        Sort::Timestamp,
+        Sort::TimestampDesc,
+        Sort::Title,
+        Sort::Status,
+    ];
+
+    /// The name used in query strings.
+    pub fn name(self) -> &'static str {
+        match self {
+            Sort::Timestamp => "timestamp",
+            Sort::TimestampDesc => "-timestamp",
+            Sort::Title => "title",
+            Sort::Status => "status",
+        }
+    }
+}
+
+/// Path every server lists todos under, paging links point here.
+pub const LIST_PATH: &str = "/v1/todos";
+/// Path every server lists the trash under.
+pub const TRASH_PATH: &str = "/v1/trash";
+
+/// One page of a listing.
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Number of documents matching the filters, across all pages.
+    pub total: u64,
+    /// Pass as `after` to get the following page, None on the last page.
+    pub next_cursor: Option<String>,
+    pub links: Links,
+}
+impl<T> Page<T> {
+    /// A page of the todo list, listed with options, linking to its neighbours.
+    pub fn new(
+        items: Vec<T>,
+        total: u64,
+        next_cursor: Option<String>,
+        options: &ListOptions,
+    ) -> Self {
+        Self::linked(LIST_PATH, items, total, next_cursor, options)
+    }
+
+    /// A page of the listing served under path.
+    pub fn linked(
+        path: &str,
+        items: Vec<T>,
+        total: u64,
+        next_cursor: Option<String>,
+        options: &ListOptions,
+    ) -> Self {
+        let limit = options.limit();
+        let link = |query: &ListOptions| format!("{}?{}", path, query.query());
+        let page = |offset, after| ListOptions {
+            offset,
+            limit: Some(limit),
+            after,
+            ..options.clone()
+        };
+        // Going back is only possible with offsets, a cursor only points forward.
+        let prev = match (options.offset, &options.after) {
+            (Some(offset), None) if offset > 0 => {
+                Some(link(&page(Some(offset.saturating_sub(limit)), None)))
+            }
+            _ => None,
+        };
+        let links = Links {
+            this: link(options),
+            first: link(&page(None, None)),
+            next: next_cursor
+                .clone()
+                .map(|cursor| link(&page(None, Some(cursor)))),
+            prev,
+        };
+        Page {
+            items,
+            total,
+            next_cursor,
+            links,
+        }
+    }
+}
+
+/// Paging links of a Page, relative to the server's root.
+#[derive(Debug, Serialize)]
+pub struct Links {
+    #[serde(rename = "self")]
+    pub this: String,
+    pub first: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev: Option<String>,
+}
+
+/// Where a todo is in its workflow, declared in workflow order.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum TodoStatus {
+    #[default]
     New,
     Started,
+    /// Started, but waiting on something.
+    Blocked,
     Complete,
+    /// Dropped without being completed.
+    Cancelled,
 }
-impl Default for TodoStatus {
-    fn default() -> Self {
-        Self::New
+impl TodoStatus {
+    pub const ALL: [TodoStatus; 5] = [
+        TodoStatus::New,
+        TodoStatus::Started,
+        TodoStatus::Blocked,
+        TodoStatus::Complete,
+        TodoStatus::Cancelled,
+    ];
+
+    /// Whether a todo in this status may be moved to next, staying put is always allowed.
+    /// Finished todos only go back to New, by being reopened.
+    pub fn can_become(self, next: TodoStatus) -> bool {
+        use TodoStatus::*;
+        self == next
+            || matches!(
+                (self, next),
+                (New, Started)
+                    | (New, Blocked)
+                    | (New, Complete)
+                    | (New, Cancelled)
+                    | (Started, Blocked)
+                    | (Started, Complete)
+                    | (Started, Cancelled)
+                    | (Blocked, Started)
+                    | (Blocked, Cancelled)
+                    | (Complete, New)
+                    | (Cancelled, New)
+            )
     }
 }
+impl std::str::FromStr for TodoStatus {
+    type Err = String;
 
-#[derive(Serialize, Deserialize, Debug)]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "new" => Ok(Self::New),
+            "started" => Ok(Self::Started),
+            "blocked" => Ok(Self::Blocked),
+            "complete" => Ok(Self::Complete),
+            "cancelled" => Ok(Self::Cancelled),
+            other => Err(format!("Unknown todo status {:?}.", other)),
+        }
+    }
+}
+
+/// Status changes with an endpoint of their own, like `POST /v1/todos/<key>/start`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Start,
+    Block,
+    Complete,
+    Cancel,
+    Reopen,
+}
+impl Action {
+    pub const ALL: [Action; 5] = [
+        Action::Start,
+        Action::Block,
+        Action::Complete,
+        Action::Cancel,
+        Action::Reopen,
+    ];
+
+    /// The last segment of the action's path.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Start => "start",
+            Action::Block => "block",
+            Action::Complete => "complete",
+            Action::Cancel => "cancel",
+            Action::Reopen => "reopen",
+        }
+    }
+
+    /// The status the action moves a todo to.
+    pub fn status(self) -> TodoStatus {
+        match self {
+            Action::Start => TodoStatus::Started,
+            Action::Block => TodoStatus::Blocked,
+            Action::Complete => TodoStatus::Complete,
+            Action::Cancel => TodoStatus::Cancelled,
+            Action::Reopen => TodoStatus::New,
+        }
+    }
+}
+impl std::str::FromStr for Action {
+    type Err = TodoError;
+
+    /// Parses the last segment of an action's path.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Action::ALL
+            .iter()
+            .copied()
+            .find(|action| action.name() == s)
+            .ok_or_else(|| {
+                TodoError::NotFound(format!(
+                    "No todo action {:?}, try start, block, complete, cancel or reopen.",
+                    s
+                ))
+            })
+    }
+}
+
+/// Whether value is what a field of type T may hold.
+fn check_field<T: DeserializeOwned>(value: &serde_json::Value) -> Result<(), String> {
+    serde_json::from_value::<T>(value.clone())
+        .map(drop)
+        .map_err(|e| e.to_string())
+}
+
+/// A field of a todo document, as requests send it.
+pub(crate) struct TodoField {
+    pub name: &'static str,
+    /// Whether documents must have the field.
+    pub required: bool,
+    /// Whether value is what the field may hold.
+    pub check: fn(&serde_json::Value) -> Result<(), String>,
+    /// JSON schema of what the field may hold, as the api documents it.
+    pub schema: fn() -> serde_json::Value,
+}
+
+/// Every field of a todo document, in the order they are serialized.
+pub(crate) const TODO_FIELDS: [TodoField; 12] = [
+    TodoField {
+        name: "_key",
+        required: true,
+        check: check_field::<String>,
+        schema: || json!({"type": "string", "description": "Identifies the todo."}),
+    },
+    TodoField {
+        name: "_rev",
+        required: false,
+        check: check_field::<u64>,
+        schema: || {
+            json!({"type": "integer", "minimum": 0, "readOnly": true,
+                "description": "Bumped by every write, sent as the ETag."})
+        },
+    },
+    TodoField {
+        name: "title",
+        required: true,
+        check: check_field::<String>,
+        schema: || json!({"type": "string"}),
+    },
+    TodoField {
+        name: "timestamp",
+        required: true,
+        check: check_field::<i64>,
+        schema: || json!({"type": "integer", "format": "int64", "description": "Milliseconds."}),
+    },
+    TodoField {
+        name: "status",
+        required: true,
+        check: check_field::<TodoStatus>,
+        schema: || json!({"$ref": "#/components/schemas/TodoStatus"}),
+    },
+    TodoField {
+        name: "started_at",
+        required: false,
+        check: check_field::<Option<i64>>,
+        schema: || {
+            json!({"type": "integer", "format": "int64", "nullable": true, "readOnly": true,
+                "description": "When work on the todo started, in milliseconds."})
+        },
+    },
+    TodoField {
+        name: "completed_at",
+        required: false,
+        check: check_field::<Option<i64>>,
+        schema: || {
+            json!({"type": "integer", "format": "int64", "nullable": true, "readOnly": true,
+                "description": "When the todo was completed, in milliseconds."})
+        },
+    },
+    TodoField {
+        name: "description",
+        required: false,
+        check: check_field::<String>,
+        schema: || json!({"type": "string", "description": "Markdown."}),
+    },
+    TodoField {
+        name: "due",
+        required: false,
+        check: check_field::<Option<i64>>,
+        schema: || {
+            json!({"type": "integer", "format": "int64", "nullable": true,
+                "description": "When the todo is due, in milliseconds."})
+        },
+    },
+    TodoField {
+        name: "priority",
+        required: false,
+        check: check_field::<Priority>,
+        schema: || json!({"$ref": "#/components/schemas/Priority"}),
+    },
+    TodoField {
+        name: "tags",
+        required: false,
+        check: check_field::<BTreeSet<String>>,
+        schema: || json!({"type": "array", "items": {"type": "string"}, "uniqueItems": true}),
+    },
+    TodoField {
+        name: "metadata",
+        required: false,
+        check: check_field::<serde_json::Map<String, serde_json::Value>>,
+        schema: || json!({"type": "object", "additionalProperties": true}),
+    },
+];
+
+/// The fields of a todo document which are missing or hold what they may not.
+fn invalid_fields(doc: &serde_json::Value) -> Vec<FieldError> {
+    let doc = match doc.as_object() {
+        Some(doc) => doc,
+        None => return vec![],
+    };
+    let mut invalid = vec![];
+    for field in TODO_FIELDS.iter() {
+        let message = match doc.get(field.name) {
+            None if field.required => Some("is required".to_owned()),
+            None => None,
+            Some(value) => (field.check)(value).err(),
+        };
+        if let Some(message) = message {
+            invalid.push(FieldError {
+                field: field.name.to_owned(),
+                message,
+            });
+        }
+    }
+    invalid
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Todo {
     /// _key is required to identify the document
     _key: String,
+    /// Bumped by every write, documents stored before revisions existed are at 0.
+    #[serde(default)]
+    _rev: u64,
     title: String,
     timestamp: i64,
     status: TodoStatus,
+    /// When work on the todo started, in milliseconds.
+    /// Only status changes set it, like completed_at.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    started_at: Option<i64>,
+    /// When the todo was completed, in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    completed_at: Option<i64>,
+    /// Markdown, empty when there is none.
+    /// This and the fields below came later, documents stored before decode with defaults.
+    #[serde(default)]
+    description: String,
+    /// When the todo is due, in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    due: Option<i64>,
+    #[serde(default)]
+    priority: Priority,
+    #[serde(default)]
+    tags: BTreeSet<String>,
+    /// Anything clients want to keep with the todo.
+    #[serde(default)]
+    metadata: serde_json::Map<String, serde_json::Value>,
 }
 impl Todo {
     pub fn new(title: &str) -> Self {
         // timestamp in millisec
-        let now = time::OffsetDateTime::now_utc().timestamp() * 1000;
+        let now = time::OffsetDateTime::now_utc().unix_timestamp() * 1000;
         Todo {
             _key: String::new(),
+            _rev: 0,
             title: title.to_owned(),
             timestamp: now,
             status: TodoStatus::New,
+            started_at: None,
+            completed_at: None,
+            description: String::new(),
+            due: None,
+            priority: Priority::default(),
+            tags: BTreeSet::new(),
+            metadata: serde_json::Map::new(),
         }
     }
     pub fn back_date(&mut self, date: &time::OffsetDateTime) {
-        let now = date.to_offset(time::offset!(+0)).timestamp() * 1000;
+        let now = date.to_offset(time::offset!(+0)).unix_timestamp() * 1000;
         self.timestamp = now;
     }
+
+    /// The todo doc describes, or which of its fields are not valid.
+    pub fn from_json(doc: serde_json::Value) -> Result<Self, TodoError> {
+        serde_json::from_value(doc.clone()).map_err(|e| {
+            let fields = invalid_fields(&doc);
+            if fields.is_empty() {
+                TodoError::Validation(e.to_string())
+            } else {
+                TodoError::invalid_fields(fields)
+            }
+        })
+    }
+
+    pub fn with_description(self, description: &str) -> Self {
+        Todo {
+            description: description.to_owned(),
+            ..self
+        }
+    }
+
+    /// Due at due, in milliseconds, or never.
+    pub fn with_due(self, due: Option<i64>) -> Self {
+        Todo { due, ..self }
+    }
+
+    pub fn with_priority(self, priority: Priority) -> Self {
+        Todo { priority, ..self }
+    }
+
+    /// Tagged with tags, instead of the tags it had.
+    pub fn with_tags<I: IntoIterator<Item = S>, S: Into<String>>(self, tags: I) -> Self {
+        Todo {
+            tags: tags.into_iter().map(Into::into).collect(),
+            ..self
+        }
+    }
+
+    pub fn with_metadata(self, metadata: serde_json::Map<String, serde_json::Value>) -> Self {
+        Todo { metadata, ..self }
+    }
+
+    pub fn key(&self) -> &str {
+        &self._key
+    }
+    /// The revision this document was stored at.
+    pub fn rev(&self) -> u64 {
+        self._rev
+    }
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+    /// When the todo was made, in milliseconds.
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+    pub fn status(&self) -> TodoStatus {
+        self.status
+    }
+    pub fn started_at(&self) -> Option<i64> {
+        self.started_at
+    }
+    pub fn completed_at(&self) -> Option<i64> {
+        self.completed_at
+    }
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+    pub fn due(&self) -> Option<i64> {
+        self.due
+    }
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+    pub fn tags(&self) -> &BTreeSet<String> {
+        &self.tags
+    }
+    pub fn metadata(&self) -> &serde_json::Map<String, serde_json::Value> {
+        &self.metadata
+    }
+    /// Strong entity tag of the stored revision, for the `ETag` header.
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self._rev)
+    }
+
+    /// Checks that this todo's status may follow the one previous is stored with, None for a
+    /// new todo, and carries previous's transition timestamps over, stamping the transition
+    /// made at now.
+    pub(crate) fn follow(&mut self, previous: Option<&Todo>, now: i64) -> Result<(), TodoError> {
+        let from = previous.map(|previous| previous.status);
+        if let Some(from) = from {
+            if !from.can_become(self.status) {
+                return Err(TodoError::Conflict(format!(
+                    "Todo {:?} is {:?} and can't become {:?}.",
+                    self._key, from, self.status
+                )));
+            }
+        }
+        self.started_at = previous.and_then(|previous| previous.started_at);
+        self.completed_at = previous.and_then(|previous| previous.completed_at);
+        if from != Some(self.status) {
+            match self.status {
+                TodoStatus::New => {
+                    self.started_at = None;
+                    self.completed_at = None;
+                }
+                TodoStatus::Started => {
+                    self.started_at.get_or_insert(now);
+                }
+                TodoStatus::Complete => self.completed_at = Some(now),
+                TodoStatus::Blocked | TodoStatus::Cancelled => {}
+            }
+        }
+        Ok(())
+    }
 }
 
-pub trait List<E: Serialize> {
-    /// Lists elements of Self up to limit.
-    /// Returns anything for Error of type E which can be Serialized.
-    fn list(limit: u64) -> Result<Vec<Self>, E>
-    where
-        Self: Sized + Serialize;
+/// How urgent a todo is, least urgent first.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+    Urgent,
 }
-pub trait Fetch<E: Serialize> {
-    /// Fetch Self by key.
-    /// Returns anything for Error of type E which can be Serialized.
-    fn fetch(key: &str) -> Result<Self, E>
-    where
-        Self: Sized + Serialize;
+impl Priority {
+    pub const ALL: [Priority; 4] = [
+        Priority::Low,
+        Priority::Normal,
+        Priority::High,
+        Priority::Urgent,
+    ];
 }
-pub trait Create<I: DeserializeOwned, E: Serialize> {
-    /// Create Self based on I input, which mush be convertible to Self with into().
-    /// Returns anything for Error of type E which can be Serialized.
-    fn create(data: I) -> Result<Self, E>
-    where
-        Self: Sized + Serialize,
-        I: Into<Self>;
+
+/// A deleted todo, kept in the trash until it is restored or purged.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Trashed {
+    /// When the todo was deleted, in milliseconds.
+    pub deleted_at: i64,
+    pub todo: Todo,
 }
-pub trait Update<I: DeserializeOwned, E: Serialize> {
-    /// Update Self based on I input, which mush be convertible to Self with into().
-    /// Returns anything for Error of type E which can be Serialized.
-    /// Note: To be able to implement this, the type Self should have some key, or other unique identifier.
-    fn update(data: I) -> Result<Self, E>
-    where
-        Self: Sized + Serialize;
+
+/// Request header making a write conditional on the revision of the stored document.
+pub const IF_MATCH_HEADER: &str = "If-Match";
+/// Response header carrying the revision of the returned document.
+pub const ETAG_HEADER: &str = "ETag";
+/// Request header under which a create is remembered, so retrying it doesn't create another todo.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Revisions a write is conditional on, read from an If-Match header.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IfMatch {
+    /// `*`, any revision as long as the document exists.
+    Any,
+    /// One of these revisions, tags which aren't ours never match.
+    Revisions(Vec<u64>),
 }
-pub trait Replace<I: DeserializeOwned, E: Serialize> {
-    /// Replace Self based on I input, which mush be convertible to Self with into().
-    /// Returns anything for Error of type E which can be Serialized.
-    /// Note: To be able to implement this, the type Self should have some key, or other unique identifier.
-    fn replace(data: I) -> Result<Self, E>
-    where
-        Self: Sized + Serialize,
-        I: Into<Self>;
+impl IfMatch {
+    /// Parses a header value like `"3"` or `"3", "4"` or `*`.
+    /// Weak tags never match, If-Match calls for strong comparison.
+    pub fn parse(header: &str) -> Self {
+        if header.trim() == "*" {
+            return IfMatch::Any;
+        }
+        let revisions = header
+            .split(',')
+            .filter_map(|tag| {
+                let tag = tag.trim();
+                tag.strip_prefix('"')
+                    .and_then(|tag| tag.strip_suffix('"'))
+                    .and_then(|rev| rev.parse().ok())
+            })
+            .collect();
+        IfMatch::Revisions(revisions)
+    }
+
+    /// Whether a document stored at rev satisfies the condition.
+    pub fn matches(&self, rev: u64) -> bool {
+        match self {
+            IfMatch::Any => true,
+            IfMatch::Revisions(revisions) => revisions.contains(&rev),
+        }
+    }
 }
-pub trait Delete<E: Serialize> {
-    /// Update Self based on I input, which mush be convertible to Self with into().
-    /// Returns anything for Error of type E which can be Serialized.
-    /// Note: To be able to implement this, the type Self should have some key, or other unique identifier.
-    fn delete(key: &str) -> Result<Self, E>
-    where
-        Self: Sized + Serialize;
-}
-
-// TODO: move these to it's own file
-use serde_json::{json, Value};
-use sled::Config;
-
-impl List<Value> for Todo {
-    fn list(limit: u64) -> Result<Vec<Self>, Value> {
-        // TODO: use a named tree instead
-        let config = Config::new().temporary(true);
-        if let Ok(t) = config.open() {
-            let collection = t.iter();
-
-            let mut res = vec![];
-            for item in collection {
-                if let Ok(item) = &item {
-                    if let Ok(doc) = serde_cbor::from_slice::<Todo>(&item.1) {
-                        // Since ret.len() is usize, this may fail on a larger than 64bit target architecture, let's worry about it when this code needs to run on such a machine.
-                        if (res.len() as u64) < limit {
-                            res.push(doc);
-                        } else {
-                            return Ok(res);
-                        }
-                    }
-                }
-            }
-            Ok(res)
-        } else {
-            Err(json!("Could not open database."))
-        }
-    }
-}
-
-impl Fetch<Value> for Todo {
-    fn fetch(key: &str) -> Result<Self, Value> {
-        // TODO: use a named tree instead.
-        let config = Config::new().temporary(true);
-        if let Ok(t) = config.open() {
-            // TODO: get rid of these unwraps.
-            let encoded_stored = t.get(key).unwrap().unwrap();
-            let decoded: Todo = serde_cbor::from_slice(&encoded_stored).unwrap();
-            Ok(decoded)
-        } else {
-            Err(json!("Could not open database."))
-        }
-    }
-}
-
-impl Create<Todo, Value> for Todo {
-    fn create(data: Todo) -> Result<Self, Value> {
-        // TODO: use a named tree instead.
-        let config = Config::new().temporary(true);
-        if let Ok(t) = config.open() {
-            // TODO: get rid of these unwraps.
-            // Find the last entry.
-            let last = t.iter().next_back().unwrap().unwrap();
-            let idx_vec: Vec<u8> = last.0.to_vec();
-            let idx: u64 = String::from_utf8(idx_vec)
-                .unwrap_or_default()
-                .parse::<u64>()
-                .unwrap()
-                + 1;
-            let new_key = idx.to_string();
-
-            let data = Todo {
-                _key: new_key.clone(),
-                ..data
-            };
-
-            let encoded = serde_cbor::to_vec(&data).unwrap();
-            if t.insert(new_key.as_bytes(), encoded).is_ok() {
-                Ok(data)
-            } else {
-                Err(json!("Could not write new document to the database."))
-            }
-        } else {
-            Err(json!("Could not open the database."))
-        }
-    }
-}
-
-impl Update<Value, Value> for Todo {
-    fn update(data: Value) -> Result<Self, Value> {
-        // TODO: use a named tree instead.
-        let config = Config::new().temporary(true);
-        if let Ok(t) = config.open() {
-            // TODO: get rid of these unwraps.
-            if let Some(key) = data["_key"].as_str() {
-                let encoded_stored = t.get(key).unwrap().unwrap();
-                let mut decoded_val: Value = serde_cbor::from_slice(&encoded_stored).unwrap();
-                // Patch the data.
-                json_patch::merge(&mut decoded_val, &data);
-                // Do not let _key change.
-                *decoded_val.get_mut("_key").unwrap() = json!(key.clone());
-
-                let decoded: Todo = serde_json::from_value(decoded_val).unwrap();
-                let encoded = serde_cbor::to_vec(&decoded).unwrap();
-                if t.insert(key.as_bytes(), encoded).is_ok() {
-                    Ok(decoded)
-                } else {
-                    Err(json!("Could not write new document to the database."))
-                }
-            } else {
-                Err(json!("Input document doesn't have a _key."))
+
+/// Content-Type of RFC 6902 JSON Patch edits, any other type is read as an RFC 7396 merge patch.
+pub const JSON_PATCH_TYPE: &str = "application/json-patch+json";
+
+/// How an edit request changes a stored todo.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    /// RFC 7396 merge patch, the fields to set, null ones are removed.
+    Merge(serde_json::Value),
+    /// RFC 6902 JSON Patch, operations applied in order, all or none of them.
+    /// `test` operations make the edit conditional on the stored fields.
+    Patch(json_patch::Patch),
+}
+impl Edit {
+    /// Reads body as the patch its content_type names, parameters like charset aside.
+    /// Merge patches may be in any Format.
+    pub fn parse(content_type: Option<&str>, body: &[u8]) -> Result<Self, TodoError> {
+        let body: serde_json::Value = Format::of_body(content_type)?.decode(body)?;
+        let media_type = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .map(str::trim);
+        match media_type {
+            Some(media_type) if media_type.eq_ignore_ascii_case(JSON_PATCH_TYPE) => {
+                json_patch::from_value(body)
+                    .map(Edit::Patch)
+                    .map_err(|e| TodoError::Validation(e.to_string()))
             }
-        } else {
-            Err(json!("Could not open the database."))
+            _ => Ok(Edit::Merge(body)),
         }
     }
 }
 
-impl Replace<Todo, Value> for Todo {
-    fn replace(data: Todo) -> Result<Self, Value> {
-        // TODO: use a named tree instead.
-        let config = Config::new().temporary(true);
-        if let Ok(t) = config.open() {
-            // TODO: get rid of these unwraps.
-            let key: String = data._key.clone();
+/// Most operations one bulk request may carry, they all go into a single transaction.
+pub const MAX_BULK_OPERATIONS: usize = 1000;
 
-            let encoded = serde_cbor::to_vec(&data).unwrap();
-            if t.insert(key.as_bytes(), encoded).is_ok() {
-                Ok(data)
-            } else {
-                Err(json!("Could not write new document to the database."))
-            }
-        } else {
-            Err(json!("Could not open the database."))
+/// One write of a bulk request, like `{"op": "delete", "_key": "7", "rev": 3}`.
+/// `rev` makes updates, replaces and deletes conditional like an If-Match header.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BulkOp {
+    Create {
+        todo: Todo,
+    },
+    /// A merge patch naming the todo by its _key.
+    Update {
+        patch: serde_json::Value,
+        rev: Option<u64>,
+    },
+    Replace {
+        todo: Todo,
+        rev: Option<u64>,
+    },
+    Delete {
+        #[serde(rename = "_key")]
+        key: String,
+        rev: Option<u64>,
+    },
+}
+impl BulkOp {
+    /// Reads the operations of a bulk request body.
+    pub fn from_body(body: serde_json::Value) -> Result<Vec<Self>, TodoError> {
+        serde_json::from_value(body).map_err(|e| TodoError::Validation(e.to_string()))
+    }
+
+    /// The condition rev puts on the stored todo.
+    pub fn condition(&self) -> Option<IfMatch> {
+        match self {
+            BulkOp::Create { .. } => None,
+            BulkOp::Update { rev, .. }
+            | BulkOp::Replace { rev, .. }
+            | BulkOp::Delete { rev, .. } => rev.map(|rev| IfMatch::Revisions(vec![rev])),
+        }
+    }
+}
+
+/// What a bulk request does when one of its operations fails, read from `?mode=`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+pub enum BulkMode {
+    /// None of the operations are applied.
+    #[default]
+    #[serde(rename = "all-or-nothing")]
+    AllOrNothing,
+    /// The other operations are applied all the same.
+    #[serde(rename = "best-effort")]
+    BestEffort,
+}
+impl BulkMode {
+    /// Parses a bulk query string, all-or-nothing unless it says otherwise.
+    pub fn from_query(query: &str) -> Result<Self, TodoError> {
+        #[derive(Deserialize)]
+        struct Query {
+            #[serde(default)]
+            mode: BulkMode,
         }
+        serde_urlencoded::from_str::<Query>(query)
+            .map(|query| query.mode)
+            .map_err(|e| TodoError::BadRequest(format!("Invalid bulk query: {}", e)))
     }
 }
 
-impl Delete<Value> for Todo {
-    fn delete(key: &str) -> Result<Self, Value> {
-        // TODO: use a named tree instead.
-        let config = Config::new().temporary(true);
-        if let Ok(t) = config.open() {
-            // TODO: get rid of these unwraps.
-            let encoded_stored = t.remove(key).unwrap().unwrap();
-            let decoded: Todo = serde_cbor::from_slice(&encoded_stored).unwrap();
-            Ok(decoded)
-        } else {
-            Err(json!("Could not open database."))
+/// Outcome of one bulk operation, the todo it wrote or deleted, or why it wasn't applied.
+#[derive(Debug, Serialize)]
+pub struct BulkResult {
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub todo: Option<Todo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<TodoError>,
+}
+impl From<Result<Todo, TodoError>> for BulkResult {
+    fn from(outcome: Result<Todo, TodoError>) -> Self {
+        match outcome {
+            Ok(todo) => BulkResult {
+                status: 200,
+                todo: Some(todo),
+                error: None,
+            },
+            Err(e) => BulkResult {
+                status: e.status(),
+                todo: None,
+                error: Some(e),
+            },
         }
     }
 }
 
+/// Answer to a bulk request, a result for every operation in request order.
+#[derive(Debug, Serialize)]
+pub struct BulkReport {
+    /// How many operations were applied.
+    pub applied: u64,
+    /// Index of the operation whose failure kept an all-or-nothing request from being applied.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed: Option<usize>,
+    pub results: Vec<BulkResult>,
+}
+impl BulkReport {
+    /// The status to answer the request with, 200 unless it was rolled back, then the status
+    /// of the operation which failed.
+    pub fn status(&self) -> u16 {
+        self.failed
+            .and_then(|index| self.results.get(index))
+            .map_or(200, |result| result.status)
+    }
+}
+
+pub trait List<T: Serialize, E: Serialize> {
+    /// Lists the page of T elements options ask for.
+    /// Returns anything for Error of type E which can be Serialized.
+    fn list(&self, options: &ListOptions) -> Result<Page<T>, E>;
+}
+pub trait Fetch<T: Serialize, E: Serialize> {
+    /// Fetch T by key.
+    /// Returns anything for Error of type E which can be Serialized.
+    fn fetch(&self, key: &str) -> Result<T, E>;
+}
+pub trait Create<I: DeserializeOwned, T: Serialize, E: Serialize> {
+    /// Create T based on I input, which mush be convertible to T with into().
+    /// Returns anything for Error of type E which can be Serialized.
+    fn create(&self, data: I) -> Result<T, E>
+    where
+        I: Into<T>;
+}
+pub trait Update<I: DeserializeOwned, T: Serialize, E: Serialize> {
+    /// Update T based on I input.
+    /// Returns anything for Error of type E which can be Serialized.
+    /// Note: To be able to implement this, the type T should have some key, or other unique identifier.
+    fn update(&self, data: I) -> Result<T, E>;
+}
+pub trait Replace<I: DeserializeOwned, T: Serialize, E: Serialize> {
+    /// Replace T based on I input, which mush be convertible to T with into().
+    /// Returns anything for Error of type E which can be Serialized.
+    /// Note: To be able to implement this, the type T should have some key, or other unique identifier.
+    fn replace(&self, data: I) -> Result<T, E>
+    where
+        I: Into<T>;
+}
+pub trait Delete<T: Serialize, E: Serialize> {
+    /// Delete T by key, returning the removed element.
+    /// Returns anything for Error of type E which can be Serialized.
+    /// Note: To be able to implement this, the type T should have some key, or other unique identifier.
+    fn delete(&self, key: &str) -> Result<T, E>;
+}
+
+mod error;
+mod format;
+mod index;
+pub mod keys;
+pub mod openapi;
+mod repository;
+mod schema;
+pub mod service;
+pub mod store;
+pub use error::{
+    ErrorKind, FieldError, ProblemDetails, TodoError, ERROR_CATALOGUE, PROBLEM_JSON_TYPE,
+};
+pub use format::Format;
+pub use repository::{
+    DbConfig, Finding, Fix, FsckMode, Migration, Problem, TodoRepository, Unmigrated, TENANT_HEADER,
+};
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn list_query() {
+        let options =
+            ListOptions::from_query("status=new,Complete&since=5&title=Milk%20run&sort=-timestamp")
+                .unwrap();
+        assert_eq!(options.status, vec![TodoStatus::New, TodoStatus::Complete]);
+        assert_eq!(options.since, Some(5));
+        assert_eq!(options.title.as_deref(), Some("Milk run"));
+        assert_eq!(options.sort, Some(Sort::TimestampDesc));
+        assert_eq!(
+            options.query(),
+            "limit=100&status=New%2CComplete&since=5&title=Milk+run&sort=-timestamp"
+        );
+        assert!(options.is_filtered());
+        assert!(!ListOptions::default().is_filtered());
+
+        assert!(ListOptions::from_query("status=done").is_err());
+        assert!(ListOptions::from_query("sort=priority").is_err());
+    }
+
+    #[test]
+    fn if_match() {
+        assert_eq!(IfMatch::parse(" * "), IfMatch::Any);
+        assert_eq!(
+            IfMatch::parse(r#""3", W/"4",  "5", "x""#),
+            IfMatch::Revisions(vec![3, 5])
+        );
+        assert!(IfMatch::parse(r#""3""#).matches(3));
+        assert!(!IfMatch::parse(r#"W/"3""#).matches(3));
+        assert!(IfMatch::Any.matches(0));
+        let todo = Todo {
+            _rev: 7,
+            ..Todo::new("x")
+        };
+        assert!(IfMatch::parse(&todo.etag()).matches(todo.rev()));
+    }
+
+    #[test]
+    fn status_transitions() {
+        use TodoStatus::*;
+        assert!(New.can_become(Complete));
+        assert!(Blocked.can_become(Started));
+        assert!(Complete.can_become(Complete));
+        assert!(!Complete.can_become(Started));
+        assert!(!Cancelled.can_become(Complete));
+        assert!(!Blocked.can_become(New));
+        assert_eq!("Cancelled".parse(), Ok(Cancelled));
+        assert_eq!("reopen".parse::<Action>().unwrap().status(), New);
+        assert_eq!("undo".parse::<Action>().unwrap_err().status(), 404);
+    }
+
+    #[test]
+    fn todo_fields() {
+        let metadata = serde_json::json!({"estimate": 3, "links": ["a"]});
+        let todo = Todo::new("milk")
+            .with_description("*oat*")
+            .with_due(Some(5))
+            .with_priority(Priority::High)
+            .with_tags(vec!["shop", "food", "shop"])
+            .with_metadata(metadata.as_object().unwrap().clone());
+        let json = serde_json::to_value(&todo).unwrap();
+        assert_eq!(json["tags"], serde_json::json!(["food", "shop"]));
+        assert_eq!(json["priority"], "High");
+        let decoded: Todo = serde_cbor::from_slice(&serde_cbor::to_vec(&todo).unwrap()).unwrap();
+        assert_eq!(decoded.description(), "*oat*");
+        assert_eq!(decoded.due(), Some(5));
+        assert_eq!(decoded.tags().len(), 2);
+        assert_eq!(decoded.metadata()["estimate"], 3);
+
+        // As stored before any of these fields existed.
+        let old = serde_json::json!({"_key": "a", "title": "x", "timestamp": 0, "status": "New"});
+        let old: Todo = serde_cbor::from_slice(&serde_cbor::to_vec(&old).unwrap()).unwrap();
+        assert_eq!((old.key(), old.title(), old.rev()), ("a", "x", 0));
+        assert_eq!((old.priority(), old.due()), (Priority::Normal, None));
+        assert!(old.description().is_empty() && old.tags().is_empty() && old.metadata().is_empty());
+    }
+
+    #[test]
+    fn edit_format() {
+        let ops = br#"[{"op": "test", "path": "/status", "value": "New"}]"#;
+        let patch = Edit::parse(Some("application/json-patch+json; charset=utf-8"), ops).unwrap();
+        assert!(matches!(patch, Edit::Patch(_)));
+        let merge = Edit::parse(Some("application/merge-patch+json"), br#"{"title": "x"}"#);
+        assert!(matches!(merge, Ok(Edit::Merge(_))));
+        assert!(matches!(Edit::parse(None, ops), Ok(Edit::Merge(_))));
+        let not_a_patch = Edit::parse(Some(JSON_PATCH_TYPE), br#"{"title": "x"}"#);
+        assert_eq!(not_a_patch.unwrap_err().status(), 422);
+        assert_eq!(Edit::parse(None, b"{").unwrap_err().status(), 400);
+    }
 }
@@ -0,0 +1,418 @@
+//! The OpenAPI 3 document of the api, made from ROUTES and the todo's own schemas so it describes
+//! what the servers serve, and the explorer page trying it out.
+use crate::error::PROBLEM_TYPE_PREFIX;
+use crate::service::{ApiRoute, Endpoint, EXPLORER_PATH, OPENAPI_PATH, ROUTES};
+use crate::{
+    Action, Format, Priority, Sort, TodoStatus, ERROR_CATALOGUE, ETAG_HEADER,
+    IDEMPOTENCY_KEY_HEADER, IF_MATCH_HEADER, JSON_PATCH_TYPE, MAX_BULK_OPERATIONS,
+    PROBLEM_JSON_TYPE, TENANT_HEADER, TODO_FIELDS,
+};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+/// Version of the OpenAPI specification the document follows.
+pub const OPENAPI_VERSION: &str = "3.0.3";
+
+/// The API explorer, a self-contained page reading the document from OPENAPI_PATH.
+pub const EXPLORER_PAGE: &str = include_str!("explorer.html");
+
+/// The OpenAPI document of every route in ROUTES.
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    for route in ROUTES.iter() {
+        let item = paths
+            .entry(route.path)
+            .or_insert_with(|| Value::Object(Map::new()));
+        item[route.method.to_ascii_lowercase()] = operation(route);
+    }
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": {
+            "title": "Todo api",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": format!(
+                "Every server serves this document at {} and an explorer trying it out at {}.",
+                OPENAPI_PATH, EXPLORER_PATH
+            ),
+        },
+        "paths": paths,
+        "components": {"schemas": schemas()},
+    })
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// The names value serializes its variants to.
+fn names<T: Serialize>(values: &[T]) -> Vec<Value> {
+    values
+        .iter()
+        .map(|value| serde_json::to_value(value).unwrap_or_default())
+        .collect()
+}
+
+fn header(name: &str, description: &str) -> Value {
+    json!({"name": name, "in": "header", "description": description,
+        "schema": {"type": "string"}})
+}
+
+fn query(name: &str, description: &str, schema: Value) -> Value {
+    json!({"name": name, "in": "query", "description": description, "schema": schema})
+}
+
+/// The segment a route captures under name.
+fn segment(name: &str) -> Value {
+    let schema = match name {
+        "action" => {
+            let actions: Vec<_> = Action::ALL.iter().map(|action| action.name()).collect();
+            json!({"type": "string", "enum": actions})
+        }
+        "rev" | "from" | "to" => json!({"type": "integer", "minimum": 0}),
+        _ => json!({"type": "string"}),
+    };
+    json!({"name": name, "in": "path", "required": true, "schema": schema})
+}
+
+/// The names of the segments a path template captures, in order.
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+}
+
+/// The query parameters of ListOptions, a listing of the trash can't be sorted.
+fn list_options(sortable: bool) -> Vec<Value> {
+    let millis = json!({"type": "integer", "format": "int64"});
+    let mut options = vec![
+        query(
+            "offset",
+            "How many todos to skip, counted from after when both are given.",
+            json!({"type": "integer", "minimum": 0}),
+        ),
+        query(
+            "limit",
+            "Page size, 100 when left out.",
+            json!({"type": "integer", "minimum": 0}),
+        ),
+        query(
+            "after",
+            "Cursor from a previous page's next_cursor.",
+            json!({"type": "string"}),
+        ),
+        json!({"name": "status", "in": "query", "style": "form", "explode": false,
+            "description": "Only todos in one of these states.",
+            "schema": {"type": "array", "items": schema_ref("TodoStatus")}}),
+        query(
+            "since",
+            "Only todos with a timestamp at or after this.",
+            millis.clone(),
+        ),
+        query(
+            "until",
+            "Only todos with a timestamp at or before this.",
+            millis,
+        ),
+        query(
+            "title",
+            "Only todos whose title contains this, ignoring case.",
+            json!({"type": "string"}),
+        ),
+    ];
+    if sortable {
+        options.push(query("sort", "Order of the listing.", schema_ref("Sort")));
+    }
+    options
+}
+
+/// Bodies of schema, in every Format.
+fn content(schema: Value) -> Value {
+    let formats: Map<String, Value> = Format::ALL
+        .iter()
+        .map(|format| (format.media_type().to_owned(), json!({ "schema": schema })))
+        .collect();
+    Value::Object(formats)
+}
+
+/// What an endpoint reads besides the segments of its route, and what it answers.
+struct Shape {
+    parameters: Vec<Value>,
+    body: Option<Value>,
+    answer: Value,
+    /// Whether the answer is a todo, sent with its revision in an ETag header.
+    tagged: bool,
+}
+
+fn shape(endpoint: &Endpoint) -> Shape {
+    let if_match = || {
+        header(
+            IF_MATCH_HEADER,
+            "Revisions the todo must be at, like \"3\", or * for any.",
+        )
+    };
+    let todo = || schema_ref("Todo");
+    let (parameters, body, answer) = match endpoint {
+        Endpoint::List => (list_options(true), None, schema_ref("TodoPage")),
+        Endpoint::Trash => (list_options(false), None, schema_ref("TrashedPage")),
+        Endpoint::Create => (
+            vec![header(
+                IDEMPOTENCY_KEY_HEADER,
+                "Retrying a create with the same key answers the todo created the first time.",
+            )],
+            Some(content(todo())),
+            todo(),
+        ),
+        Endpoint::Edit(_) => {
+            let mut patches = content(json!({"type": "object",
+                "description": "RFC 7396 merge patch, null fields are removed."}));
+            patches[JSON_PATCH_TYPE] = json!({"schema": schema_ref("JsonPatch")});
+            (vec![if_match()], Some(patches), todo())
+        }
+        Endpoint::Replace(_) => (vec![if_match()], Some(content(todo())), todo()),
+        Endpoint::Delete(_) | Endpoint::Act(..) | Endpoint::Rollback(..) => {
+            (vec![if_match()], None, todo())
+        }
+        Endpoint::Bulk => (
+            vec![query(
+                "mode",
+                "What happens when an operation fails.",
+                json!({"type": "string", "enum": ["all-or-nothing", "best-effort"],
+                    "default": "all-or-nothing"}),
+            )],
+            Some(content(
+                json!({"type": "array", "maxItems": MAX_BULK_OPERATIONS,
+                "items": schema_ref("BulkOperation")}),
+            )),
+            schema_ref("BulkReport"),
+        ),
+        Endpoint::Fetch(_) | Endpoint::Restore(_) | Endpoint::Revision(..) => {
+            (vec![], None, todo())
+        }
+        Endpoint::Purge(_) => (vec![], None, schema_ref("Trashed")),
+        Endpoint::History(_) => (vec![], None, json!({"type": "array", "items": todo()})),
+        Endpoint::Diff(..) => (vec![], None, schema_ref("JsonPatch")),
+        Endpoint::OpenApi | Endpoint::Explorer => (vec![], None, json!({})),
+    };
+    let tagged = matches!(
+        endpoint,
+        Endpoint::Fetch(_)
+            | Endpoint::Create
+            | Endpoint::Edit(_)
+            | Endpoint::Replace(_)
+            | Endpoint::Act(..)
+            | Endpoint::Restore(_)
+            | Endpoint::Rollback(..)
+    );
+    Shape {
+        parameters,
+        body,
+        answer,
+        tagged,
+    }
+}
+
+fn operation(route: &ApiRoute) -> Value {
+    // The endpoint of a route only depends on the names of its segments.
+    let endpoint = (route.endpoint)(&|name| format!("{{{}}}", name));
+    let shape = shape(&endpoint);
+    let mut parameters: Vec<Value> = segments(route.path).map(segment).collect();
+    parameters.push(header(
+        TENANT_HEADER,
+        "Tenant whose todos to work on, the default namespace when left out.",
+    ));
+    parameters.extend(shape.parameters);
+    let mut ok = json!({"description": "Success.", "content": content(shape.answer)});
+    if shape.tagged {
+        ok["headers"] = json!({ ETAG_HEADER: {
+            "description": "Revision of the todo, for If-Match.",
+            "schema": {"type": "string"},
+        }});
+    }
+    let mut operation = json!({
+        "operationId": route.operation,
+        "summary": route.summary,
+        "parameters": parameters,
+        "responses": {
+            "200": ok,
+            "default": {
+                "description": "An error, as problem details.",
+                "content": { PROBLEM_JSON_TYPE: { "schema": schema_ref("Problem") } },
+            },
+        },
+    });
+    if let Endpoint::Bulk = endpoint {
+        operation["responses"]["4XX"] = json!({
+            "description": "Rolled back, with the status of the operation which failed.",
+            "content": content(schema_ref("BulkReport")),
+        });
+    }
+    if let Some(content) = shape.body {
+        operation["requestBody"] = json!({"required": true, "content": content});
+    }
+    operation
+}
+
+fn schemas() -> Value {
+    let properties: Map<String, Value> = TODO_FIELDS
+        .iter()
+        .map(|field| (field.name.to_owned(), (field.schema)()))
+        .collect();
+    let required: Vec<_> = TODO_FIELDS
+        .iter()
+        .filter(|field| field.required)
+        .map(|field| field.name)
+        .collect();
+    let millis = json!({"type": "integer", "format": "int64"});
+    let page = |items: &str| {
+        json!({"type": "object", "required": ["items", "total", "next_cursor", "links"],
+        "properties": {
+            "items": {"type": "array", "items": schema_ref(items)},
+            "total": {"type": "integer", "minimum": 0,
+                "description": "Number of matches, across all pages."},
+            "next_cursor": {"type": "string", "nullable": true,
+                "description": "Pass as after to get the following page."},
+            "links": schema_ref("Links"),
+        }})
+    };
+    let rev = json!({"type": "integer", "minimum": 0,
+        "description": "Revision the todo must be at, like If-Match."});
+    let bulk_op = |op: &str, mut properties: Value, required: &[&str]| {
+        properties["op"] = json!({"type": "string", "enum": [op]});
+        let mut required = required.to_vec();
+        required.insert(0, "op");
+        json!({"type": "object", "required": required, "properties": properties})
+    };
+    let codes: Vec<_> = ERROR_CATALOGUE.iter().map(|kind| kind.code).collect();
+    json!({
+        "Todo": {"type": "object", "required": required, "properties": properties},
+        "TodoStatus": {"type": "string", "enum": names(&TodoStatus::ALL),
+            "description": "Where a todo is in its workflow, in workflow order."},
+        "Priority": {"type": "string", "enum": names(&Priority::ALL),
+            "description": "How urgent a todo is, least urgent first."},
+        "Sort": {"type": "string",
+            "enum": Sort::ALL.iter().map(|sort| sort.name()).collect::<Vec<_>>()},
+        "Trashed": {"type": "object", "required": ["deleted_at", "todo"], "properties": {
+            "deleted_at": millis,
+            "todo": schema_ref("Todo"),
+        }},
+        "Links": {"type": "object", "required": ["self", "first"], "properties": {
+            "self": {"type": "string"},
+            "first": {"type": "string"},
+            "next": {"type": "string"},
+            "prev": {"type": "string"},
+        }},
+        "TodoPage": page("Todo"),
+        "TrashedPage": page("Trashed"),
+        "BulkOperation": {"oneOf": [
+            bulk_op("create", json!({"todo": schema_ref("Todo")}), &["todo"]),
+            bulk_op("update", json!({"patch": {"type": "object"}, "rev": rev}), &["patch"]),
+            bulk_op("replace", json!({"todo": schema_ref("Todo"), "rev": rev}), &["todo"]),
+            bulk_op("delete", json!({"_key": {"type": "string"}, "rev": rev}), &["_key"]),
+        ]},
+        "BulkReport": {"type": "object", "required": ["applied", "results"], "properties": {
+            "applied": {"type": "integer", "minimum": 0},
+            "failed": {"type": "integer", "minimum": 0,
+                "description": "Index of the operation which kept all from being applied."},
+            "results": {"type": "array", "items": {
+                "type": "object", "required": ["status"], "properties": {
+                    "status": {"type": "integer"},
+                    "todo": schema_ref("Todo"),
+                    "error": schema_ref("Problem"),
+                },
+            }},
+        }},
+        "JsonPatch": {"type": "array", "description": "RFC 6902 JSON Patch.", "items": {
+            "type": "object", "required": ["op", "path"], "properties": {
+                "op": {"type": "string",
+                    "enum": ["add", "remove", "replace", "move", "copy", "test"]},
+                "path": {"type": "string"},
+                "from": {"type": "string"},
+                "value": {},
+            },
+        }},
+        "Problem": {"type": "object", "description": "RFC 7807 problem details.",
+            "required": ["type", "title", "status", "detail", "code"], "properties": {
+                "type": {"type": "string",
+                    "description": format!("{} followed by the code.", PROBLEM_TYPE_PREFIX)},
+                "title": {"type": "string"},
+                "status": {"type": "integer"},
+                "detail": {"type": "string"},
+                "instance": {"type": "string"},
+                "code": {"type": "string", "enum": codes},
+                "errors": {"type": "array", "items": schema_ref("FieldError")},
+            }},
+        "FieldError": {"type": "object", "required": ["field", "message"], "properties": {
+            "field": {"type": "string"},
+            "message": {"type": "string"},
+        }},
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every `$ref` in value.
+    fn refs<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+        match value {
+            Value::Object(fields) => {
+                if let Some(Value::String(target)) = fields.get("$ref") {
+                    found.push(target);
+                }
+                fields.values().for_each(|value| refs(value, found));
+            }
+            Value::Array(values) => values.iter().for_each(|value| refs(value, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn document_matches_routes() {
+        let doc = document();
+        assert_eq!(doc["openapi"], OPENAPI_VERSION);
+        for route in ROUTES.iter() {
+            let endpoint = (route.endpoint)(&|name| format!("{{{}}}", name));
+            assert_eq!(endpoint.path().as_deref(), Some(route.path));
+            let operation = &doc["paths"][route.path][route.method.to_ascii_lowercase()];
+            assert_eq!(operation["operationId"], route.operation);
+            let segments: Vec<_> = operation["parameters"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|parameter| parameter["in"] == "path")
+                .map(|parameter| parameter["name"].as_str().unwrap())
+                .collect();
+            assert_eq!(segments, super::segments(route.path).collect::<Vec<_>>());
+        }
+        let operations: usize = doc["paths"]
+            .as_object()
+            .unwrap()
+            .values()
+            .map(|item| item.as_object().unwrap().len())
+            .sum();
+        assert_eq!(operations, ROUTES.len());
+
+        let mut found = vec![];
+        refs(&doc, &mut found);
+        for target in found {
+            let name = target.trim_start_matches("#/components/schemas/");
+            assert!(
+                doc["components"]["schemas"].get(name).is_some(),
+                "{}",
+                target
+            );
+        }
+        // The enums are what the api reads.
+        for status in doc["components"]["schemas"]["TodoStatus"]["enum"]
+            .as_array()
+            .unwrap()
+        {
+            serde_json::from_value::<TodoStatus>(status.clone()).unwrap();
+        }
+        for sort in doc["components"]["schemas"]["Sort"]["enum"]
+            .as_array()
+            .unwrap()
+        {
+            serde_json::from_value::<Sort>(sort.clone()).unwrap();
+        }
+    }
+}
@@ -0,0 +1,1560 @@
+use crate::index::{self, Scan};
+use crate::keys::{is_slug, KeyGenerator, KeyStrategy, MAX_SLUG_LEN};
+use crate::schema;
+use crate::store::{
+    Backend, Entries, MemoryStore, SledStore, SqliteStore, StoreError, TodoStore, Transaction,
+    TxError, LEGACY_TREE,
+};
+use crate::{
+    Action, Create, Delete, Edit, Fetch, FieldError, IfMatch, List, ListOptions, Page, Replace,
+    Sort, Todo, TodoError, TodoStatus, Update,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Which backend holds the todos, where it lives on disk, and how new keys are made.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbConfig {
+    pub backend: Backend,
+    pub path: PathBuf,
+    pub keys: KeyStrategy,
+    /// How long deleted todos stay in the trash, None keeps them until they are purged.
+    pub trash_retention: Option<Duration>,
+    /// How long the todo a create made is replayed to retries with the same Idempotency-Key.
+    pub idempotency_window: Duration,
+}
+impl DbConfig {
+    /// Environment variable consulted when no `--db-path` flag is given.
+    pub const ENV_VAR: &'static str = "TODO_DB_PATH";
+    /// Environment variable consulted when no `--db-backend` flag is given.
+    pub const BACKEND_ENV_VAR: &'static str = "TODO_DB_BACKEND";
+    /// Environment variable consulted when no `--key-strategy` flag is given.
+    pub const KEYS_ENV_VAR: &'static str = "TODO_KEY_STRATEGY";
+    /// Environment variable consulted when no `--trash-retention-days` flag is given.
+    pub const TRASH_RETENTION_ENV_VAR: &'static str = "TODO_TRASH_RETENTION_DAYS";
+    /// Environment variable consulted when no `--idempotency-window-hours` flag is given.
+    pub const IDEMPOTENCY_WINDOW_ENV_VAR: &'static str = "TODO_IDEMPOTENCY_WINDOW_HOURS";
+    /// Used when neither the flag nor the environment variable is set.
+    pub const DEFAULT_PATH: &'static str = "todo.db";
+    /// Days deleted todos are kept when neither the flag nor the environment variable is set.
+    pub const DEFAULT_TRASH_RETENTION_DAYS: u64 = 30;
+    /// Hours creates are remembered when neither the flag nor the environment variable is set.
+    pub const DEFAULT_IDEMPOTENCY_WINDOW_HOURS: u64 = 24;
+
+    /// Reads the configuration from the process arguments and environment.
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_args(std::env::args().skip(1), |name| std::env::var(name).ok())
+    }
+
+    /// A `--db-path <path>` / `--db-backend <name>` / `--key-strategy <name>` /
+    /// `--trash-retention-days <days|never>` / `--idempotency-window-hours <hours>` flag
+    /// (or `--flag=value`) wins over the environment variable looked up with env, which wins
+    /// over the default.
+    pub fn from_args<I, F>(args: I, env: F) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        let args: Vec<String> = args.into_iter().collect();
+        let path = flag(&args, "--db-path")
+            .or_else(|| env(Self::ENV_VAR))
+            .unwrap_or_else(|| Self::DEFAULT_PATH.to_owned());
+        let backend = match flag(&args, "--db-backend").or_else(|| env(Self::BACKEND_ENV_VAR)) {
+            Some(name) => name.parse()?,
+            None => Backend::default(),
+        };
+        let keys = match flag(&args, "--key-strategy").or_else(|| env(Self::KEYS_ENV_VAR)) {
+            Some(name) => name.parse()?,
+            None => KeyStrategy::default(),
+        };
+        let trash_retention = match flag(&args, "--trash-retention-days")
+            .or_else(|| env(Self::TRASH_RETENTION_ENV_VAR))
+        {
+            Some(days) => retention(&days)?,
+            None => Some(DEFAULT_TRASH_RETENTION),
+        };
+        let idempotency_window = match flag(&args, "--idempotency-window-hours")
+            .or_else(|| env(Self::IDEMPOTENCY_WINDOW_ENV_VAR))
+        {
+            Some(window) => window.parse().ok().and_then(hours).ok_or_else(|| {
+                format!(
+                    "Idempotency window {:?} is not a number of hours, or too long.",
+                    window
+                )
+            })?,
+            None => DEFAULT_IDEMPOTENCY_WINDOW,
+        };
+        Ok(DbConfig {
+            backend,
+            path: PathBuf::from(path),
+            keys,
+            trash_retention,
+            idempotency_window,
+        })
+    }
+}
+
+const DEFAULT_TRASH_RETENTION: Duration =
+    Duration::from_secs(DbConfig::DEFAULT_TRASH_RETENTION_DAYS * 24 * 60 * 60);
+const DEFAULT_IDEMPOTENCY_WINDOW: Duration =
+    Duration::from_secs(DbConfig::DEFAULT_IDEMPOTENCY_WINDOW_HOURS * 60 * 60);
+
+/// None when days are too long to be counted in milliseconds like timestamps are.
+fn days(days: u64) -> Option<Duration> {
+    hours(days.checked_mul(24)?)
+}
+
+/// None when hours are too long to be counted in milliseconds like timestamps are.
+fn hours(hours: u64) -> Option<Duration> {
+    let millis = hours.checked_mul(60 * 60 * 1000)?;
+    i64::try_from(millis).ok()?;
+    Some(Duration::from_millis(millis))
+}
+
+/// A number of days, or `never` to keep trash forever.
+fn retention(value: &str) -> Result<Option<Duration>, String> {
+    if value.eq_ignore_ascii_case("never") {
+        return Ok(None);
+    }
+    let retention = value.parse().ok().and_then(days).ok_or_else(|| {
+        format!(
+            "Trash retention {:?} is neither a number of days nor \"never\", or too long.",
+            value
+        )
+    })?;
+    Ok(Some(retention))
+}
+
+/// Value of the last occurrence of `--name value` or `--name=value`.
+pub(crate) fn flag(args: &[String], name: &str) -> Option<String> {
+    let mut value = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == name {
+            value = args.next().cloned().or(value);
+        } else if let Some(v) = arg
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            value = Some(v.to_owned());
+        }
+    }
+    value
+}
+
+/// Request header naming the tenant whose todos a request works on.
+pub const TENANT_HEADER: &str = "X-Tenant";
+
+mod bulk;
+mod fsck;
+mod history;
+mod idempotency;
+mod migration;
+mod trash;
+pub use fsck::{Finding, Fix, FsckMode, Problem};
+pub use migration::{Migration, Unmigrated};
+
+/// Tree of the todo entity type, stores move their entries from before named trees here.
+const TODOS: &str = LEGACY_TREE;
+
+/// Tree of bookkeeping about the whole store, outside of every namespace.
+const META: &str = "meta";
+/// Key in META holding the index layout every namespace has been indexed with.
+const INDEX_VERSION: &[u8] = b"index_version";
+
+/// Handle to the todo database.
+/// Build it once at startup and share it, cloning is cheap and all clones use the same store.
+///
+/// Every entity type has its own tree. A repository scoped to a tenant uses a separate set of
+/// trees, so tenants never see each other's documents.
+#[derive(Clone)]
+pub struct TodoRepository {
+    store: Arc<dyn TodoStore>,
+    keys: Arc<dyn KeyGenerator>,
+    tenant: Option<String>,
+    trash_retention: Option<Duration>,
+    idempotency_window: Duration,
+}
+impl TodoRepository {
+    /// Opens the backend described by config.
+    /// Stores written before the indexes existed, or with an older layout, get reindexed first,
+    /// and trash older than the retention window and creates older than the idempotency window
+    /// are purged.
+    pub fn open(config: &DbConfig) -> Result<Self, TodoError> {
+        let repo = Self::open_as_is(config)?;
+        if repo.store.get(META, INDEX_VERSION)? != Some(vec![index::VERSION]) {
+            repo.reindex_all()?;
+        }
+        repo.purge_expired_all()?;
+        repo.purge_idempotency_keys_all()?;
+        Ok(repo)
+    }
+
+    /// Opens the backend described by config like open, but leaves the store as it finds it,
+    /// neither reindexing nor purging. For fsck, which has to see the store as it is.
+    pub fn open_as_is(config: &DbConfig) -> Result<Self, TodoError> {
+        let store: Arc<dyn TodoStore> = match config.backend {
+            Backend::Memory => Arc::new(MemoryStore::new()),
+            Backend::Sled => Arc::new(SledStore::open(&config.path)?),
+            Backend::Sqlite => Arc::new(SqliteStore::open(&config.path)?),
+        };
+        Ok(Self::new(store)
+            .with_key_generator(config.keys.generator())
+            .with_trash_retention(config.trash_retention)
+            .with_idempotency_window(config.idempotency_window))
+    }
+
+    /// Uses an already opened store, e.g. a MemoryStore in tests.
+    /// New documents get sequence keys unless another generator is set, deleted ones stay
+    /// in the trash and creates are remembered for the default windows.
+    pub fn new(store: Arc<dyn TodoStore>) -> Self {
+        TodoRepository {
+            store,
+            keys: KeyStrategy::default().generator(),
+            tenant: None,
+            trash_retention: Some(DEFAULT_TRASH_RETENTION),
+            idempotency_window: DEFAULT_IDEMPOTENCY_WINDOW,
+        }
+    }
+
+    /// Makes new documents get their keys from keys.
+    pub fn with_key_generator(self, keys: Arc<dyn KeyGenerator>) -> Self {
+        TodoRepository { keys, ..self }
+    }
+
+    /// Keeps deleted documents in the trash for retention, None keeps them until purged.
+    pub fn with_trash_retention(self, retention: Option<Duration>) -> Self {
+        TodoRepository {
+            trash_retention: retention,
+            ..self
+        }
+    }
+
+    /// Replays the todo a create made to retries with the same Idempotency-Key for window.
+    pub fn with_idempotency_window(self, window: Duration) -> Self {
+        TodoRepository {
+            idempotency_window: window,
+            ..self
+        }
+    }
+
+    /// A repository which forgets everything when dropped.
+    pub fn in_memory() -> Self {
+        Self::new(Arc::new(MemoryStore::new()))
+    }
+
+    /// A handle on the trees of tenant, which has to be a slug.
+    pub fn for_tenant(&self, tenant: &str) -> Result<Self, TodoError> {
+        if !is_slug(tenant) {
+            return Err(TodoError::BadRequest(format!(
+                "Tenant {:?} must be 1 to {} lowercase letters, digits, '-' or '_', starting with a letter or digit.",
+                tenant, MAX_SLUG_LEN
+            )));
+        }
+        Ok(TodoRepository {
+            tenant: Some(tenant.to_owned()),
+            ..self.clone()
+        })
+    }
+
+    /// for_tenant when a tenant is given, the default namespace otherwise.
+    pub fn scoped(&self, tenant: Option<&str>) -> Result<Self, TodoError> {
+        match tenant {
+            Some(tenant) => self.for_tenant(tenant),
+            None => Ok(self.clone()),
+        }
+    }
+
+    /// Name of the tree holding entity documents in this repository's namespace.
+    fn tree(&self, entity: &str) -> String {
+        match &self.tenant {
+            Some(tenant) => format!("tenant/{}/{}", tenant, entity),
+            None => entity.to_owned(),
+        }
+    }
+
+    /// The namespaces holding any entries, None being the default namespace which is always
+    /// included.
+    fn namespaces(&self) -> Result<Vec<Option<String>>, TodoError> {
+        let mut tenants: Vec<Option<String>> = self
+            .store
+            .tree_names()?
+            .iter()
+            .filter_map(|name| tenant_of(name))
+            .map(|tenant| Some(tenant.to_owned()))
+            .collect();
+        tenants.push(None);
+        tenants.sort();
+        tenants.dedup();
+        Ok(tenants)
+    }
+
+    /// This repository's store and settings, scoped to tenant.
+    fn namespace(&self, tenant: Option<String>) -> Self {
+        TodoRepository {
+            tenant,
+            ..self.clone()
+        }
+    }
+
+    /// Runs body in a single store transaction over the todos of this namespace, their indexes,
+    /// trash, history and remembered creates, flushing once it commits so an acknowledged write survives a restart.
+    /// When body fails none of its writes take effect and its error is returned.
+    fn transaction<T>(
+        &self,
+        body: impl Fn(&dyn Transaction) -> Result<T, TodoError>,
+    ) -> Result<T, TodoError> {
+        let trees: Vec<String> = [
+            TODOS,
+            index::BY_STATUS,
+            index::BY_TIMESTAMP,
+            trash::TRASH,
+            history::HISTORY,
+            idempotency::IDEMPOTENCY,
+        ]
+        .iter()
+        .map(|entity| self.tree(entity))
+        .collect();
+        let names: Vec<&str> = trees.iter().map(String::as_str).collect();
+        let outcome = RefCell::new(None);
+        let committed = self.store.transaction(&names, &|tx| {
+            let result = body(tx);
+            let failed = result.is_err();
+            outcome.replace(Some(result));
+            if failed {
+                Err(TxError::Abort)
+            } else {
+                Ok(())
+            }
+        })?;
+        if committed {
+            self.store.flush()?;
+        }
+        outcome.into_inner().unwrap_or_else(|| {
+            Err(TodoError::Internal(
+                "Transaction finished without running.".to_owned(),
+            ))
+        })
+    }
+
+    /// Moves the index entries of the document under key from old to new, None meaning absent.
+    fn reindex_doc(
+        &self,
+        tx: &dyn Transaction,
+        key: &[u8],
+        old: Option<&Todo>,
+        new: Option<&Todo>,
+    ) -> Result<(), StoreError> {
+        if let Some(old) = old {
+            for (entity, entry) in index::entries(key, old).iter() {
+                tx.remove(&self.tree(entity), entry)?;
+            }
+        }
+        if let Some(new) = new {
+            for (entity, entry) in index::entries(key, new).iter() {
+                tx.insert(&self.tree(entity), entry, vec![])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the indexes of this namespace from its documents, returning how many got indexed.
+    /// Writes made meanwhile may be left out, best run while no server is using the store.
+    pub fn reindex(&self) -> Result<u64, TodoError> {
+        for entity in &[index::BY_STATUS, index::BY_TIMESTAMP] {
+            let tree = self.tree(entity);
+            let entries = self.store.iter(&tree).collect::<Result<Vec<_>, _>>()?;
+            for (key, _) in entries {
+                self.store.remove(&tree, &key)?;
+            }
+        }
+        let mut indexed = 0;
+        for item in decoded(self.store.iter(&self.tree(TODOS))) {
+            let (key, doc) = item?;
+            for (entity, entry) in index::entries(&key, &doc).iter() {
+                self.store.insert(&self.tree(entity), entry, vec![])?;
+            }
+            indexed += 1;
+        }
+        self.store.flush()?;
+        Ok(indexed)
+    }
+
+    /// reindex for the default namespace and for every tenant, returning the number of
+    /// documents indexed per tenant.
+    pub fn reindex_all(&self) -> Result<Vec<(Option<String>, u64)>, TodoError> {
+        let mut indexed = vec![];
+        for tenant in self.namespaces()? {
+            let count = self.namespace(tenant.clone()).reindex()?;
+            indexed.push((tenant, count));
+        }
+        self.store
+            .insert(META, INDEX_VERSION, vec![index::VERSION])?;
+        self.store.flush()?;
+        Ok(indexed)
+    }
+}
+
+/// The tenant a tree belongs to, None for trees of the default namespace and bookkeeping.
+fn tenant_of(tree: &str) -> Option<&str> {
+    tree.strip_prefix("tenant/")
+        .and_then(|rest| rest.split('/').next())
+}
+
+fn encode<T: Serialize>(data: &T) -> Result<Vec<u8>, TodoError> {
+    serde_cbor::to_vec(data)
+        .map_err(|e| TodoError::Internal(format!("Could not encode todo: {}", e)))
+}
+
+/// Milliseconds since the epoch.
+fn now() -> i64 {
+    time::OffsetDateTime::now_utc().unix_timestamp() * 1000
+}
+
+fn not_found(key: &str) -> TodoError {
+    TodoError::NotFound(format!("No todo with _key {:?}.", key))
+}
+
+/// A write would move the todo under key to another `_key`.
+pub(crate) fn key_unchangeable(key: &str) -> TodoError {
+    TodoError::invalid_fields(vec![FieldError {
+        field: "_key".to_owned(),
+        message: format!("can't be changed from {:?}", key),
+    }])
+}
+
+/// Fails unless the stored document, None when there is none, satisfies condition.
+fn check_revision(
+    key: &str,
+    stored: Option<&Todo>,
+    condition: Option<&IfMatch>,
+) -> Result<(), TodoError> {
+    match (condition, stored) {
+        (None, _) => Ok(()),
+        (Some(condition), Some(stored)) if condition.matches(stored._rev) => Ok(()),
+        (Some(_), Some(stored)) => Err(TodoError::PreconditionFailed(format!(
+            "Todo {:?} is at revision {}, which If-Match doesn't name.",
+            key, stored._rev
+        ))),
+        (Some(_), None) => Err(TodoError::PreconditionFailed(format!(
+            "No todo with _key {:?} to match If-Match against.",
+            key
+        ))),
+    }
+}
+
+/// How many generated keys create tries before giving up on collisions.
+const KEY_ATTEMPTS: usize = 8;
+
+impl List<Todo, TodoError> for TodoRepository {
+    fn list(&self, options: &ListOptions) -> Result<Page<Todo>, TodoError> {
+        match options.sort {
+            None => self.list_in_key_order(options),
+            Some(sort) => self.list_sorted(sort, options),
+        }
+    }
+}
+
+impl TodoRepository {
+    /// Keys of the documents the indexes find for the status and timestamp filters of options,
+    /// in key order. None when options filter on neither.
+    fn indexed_keys(&self, options: &ListOptions) -> Result<Option<BTreeSet<Vec<u8>>>, TodoError> {
+        let scans = match index::scans(options) {
+            Some(scans) => scans,
+            None => return Ok(None),
+        };
+        let mut keys = BTreeSet::new();
+        for Scan { entity, start, end } in scans {
+            for entry in self.store.range(&self.tree(entity), &start, &end) {
+                let (index_key, _) = entry?;
+                keys.insert(index::doc_key(entity, &index_key).to_vec());
+            }
+        }
+        Ok(Some(keys))
+    }
+
+    /// The todo entries stored under keys, skipping keys which hold nothing.
+    fn load<'a>(&'a self, keys: impl Iterator<Item = Vec<u8>> + 'a) -> Entries<'a> {
+        let tree = self.tree(TODOS);
+        Box::new(keys.filter_map(move |key| {
+            self.store
+                .get(&tree, &key)
+                .transpose()
+                .map(|value| value.map(|value| (key, value)))
+        }))
+    }
+
+    /// Walks the tree, or the keys the indexes found, from the cursor on, so a page costs no more
+    /// than the documents it skips. Except for unfiltered lists, counting the total reads every
+    /// candidate, since index entries may point at todos which are gone or have changed.
+    fn list_in_key_order(&self, options: &ListOptions) -> Result<Page<Todo>, TodoError> {
+        let tree = self.tree(TODOS);
+        let candidates = self.indexed_keys(options)?;
+        let after = match &options.after {
+            Some(cursor) => Some(decode_cursor(cursor)?),
+            None => None,
+        };
+        let collection = match (&candidates, after) {
+            (Some(keys), Some(after)) => {
+                self.load(keys.range((Excluded(after), Unbounded)).cloned())
+            }
+            (Some(keys), None) => self.load(keys.iter().cloned()),
+            (None, Some(after)) => self.store.range_after(&tree, &after),
+            (None, None) => self.store.iter(&tree),
+        };
+        let docs = decoded(collection).filter(|item| match item {
+            Ok((_, doc)) => options.matches(doc),
+            Err(_) => true,
+        });
+        let (items, last_key) = take_page(docs, options)?;
+        let next_cursor = last_key.map(|key| encode_cursor(&key));
+        let total = match &candidates {
+            None if !options.is_filtered() => self.store.count(&tree)?,
+            _ => {
+                let collection = match &candidates {
+                    Some(keys) => self.load(keys.iter().cloned()),
+                    None => self.store.iter(&tree),
+                };
+                let mut total = 0;
+                for item in decoded(collection) {
+                    if options.matches(&item?.1) {
+                        total += 1;
+                    }
+                }
+                total
+            }
+        };
+        Ok(Page::new(items, total, next_cursor, options))
+    }
+
+    /// Has to read every matching document to order them.
+    fn list_sorted(&self, sort: Sort, options: &ListOptions) -> Result<Page<Todo>, TodoError> {
+        let candidates = self.indexed_keys(options)?;
+        let collection = match &candidates {
+            Some(keys) => self.load(keys.iter().cloned()),
+            None => self.store.iter(&self.tree(TODOS)),
+        };
+        let mut matching = vec![];
+        for item in decoded(collection) {
+            let (_, doc) = item?;
+            if options.matches(&doc) {
+                matching.push(doc);
+            }
+        }
+        let total = matching.len() as u64;
+        matching.sort_by(|a, b| compare(sort, &sort_position(sort, a), &sort_position(sort, b)));
+
+        let after = match &options.after {
+            Some(cursor) => Some(
+                serde_json::from_slice::<SortPosition>(&decode_cursor(cursor)?)
+                    .map_err(|_| TodoError::BadRequest(format!("Invalid cursor {:?}.", cursor)))?,
+            ),
+            None => None,
+        };
+        let docs = matching
+            .into_iter()
+            .map(|doc| (sort_position(sort, &doc), doc))
+            .filter(|(position, _)| {
+                after
+                    .as_ref()
+                    .is_none_or(|after| compare(sort, position, after) == Ordering::Greater)
+            })
+            .map(Ok);
+        let (items, last_position) = take_page(docs, options)?;
+        let next_cursor = match last_position {
+            Some(position) => Some(encode_cursor(
+                &serde_json::to_vec(&position).map_err(|e| TodoError::Internal(e.to_string()))?,
+            )),
+            None => None,
+        };
+        Ok(Page::new(items, total, next_cursor, options))
+    }
+}
+
+/// Decodes the documents of entries, skipping those which don't decode rather than failing the
+/// whole listing.
+fn decoded<'a>(
+    entries: Entries<'a>,
+) -> impl Iterator<Item = Result<(Vec<u8>, Todo), StoreError>> + 'a {
+    entries.filter_map(|item| match item {
+        Ok((key, value)) => schema::decode(&value).ok().map(|doc| Ok((key, doc))),
+        Err(e) => Some(Err(e)),
+    })
+}
+
+/// Skips offset documents and takes up to limit of the rest.
+/// Also returns the position of the last one taken, if more documents follow it.
+fn take_page<P, T>(
+    docs: impl Iterator<Item = Result<(P, T), StoreError>>,
+    options: &ListOptions,
+) -> Result<(Vec<T>, Option<P>), TodoError> {
+    let mut docs = docs.skip(usize::try_from(options.offset.unwrap_or(0)).unwrap_or(usize::MAX));
+    let limit = usize::try_from(options.limit()).unwrap_or(usize::MAX);
+    let mut items = vec![];
+    let mut last = None;
+    while items.len() < limit {
+        match docs.next() {
+            Some(item) => {
+                let (position, doc) = item?;
+                last = Some(position);
+                items.push(doc);
+            }
+            None => break,
+        }
+    }
+    match docs.next() {
+        Some(_) => Ok((items, last)),
+        None => Ok((items, None)),
+    }
+}
+
+/// Where a document falls in a sorted listing, sorted cursors carry it.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+enum SortValue {
+    Timestamp(i64),
+    Title(String),
+    Status(TodoStatus),
+}
+type SortPosition = (SortValue, String);
+
+fn sort_position(sort: Sort, doc: &Todo) -> SortPosition {
+    let value = match sort {
+        Sort::Timestamp | Sort::TimestampDesc => SortValue::Timestamp(doc.timestamp),
+        Sort::Title => SortValue::Title(doc.title.to_lowercase()),
+        Sort::Status => SortValue::Status(doc.status),
+    };
+    (value, doc._key.clone())
+}
+
+/// Orders by the sorted value, then by key.
+fn compare(sort: Sort, a: &SortPosition, b: &SortPosition) -> Ordering {
+    let by_value = a.0.cmp(&b.0);
+    let by_value = match sort {
+        Sort::TimestampDesc => by_value.reverse(),
+        _ => by_value,
+    };
+    by_value.then_with(|| a.1.cmp(&b.1))
+}
+
+/// Cursors are hex encoded, of the key of the last document on a page in key order and of its
+/// SortPosition in sorted listings.
+fn encode_cursor(key: &[u8]) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_cursor(cursor: &str) -> Result<Vec<u8>, TodoError> {
+    let invalid = || TodoError::BadRequest(format!("Invalid cursor {:?}.", cursor));
+    if !cursor.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    cursor
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(invalid),
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+impl Fetch<Todo, TodoError> for TodoRepository {
+    fn fetch(&self, key: &str) -> Result<Todo, TodoError> {
+        let encoded_stored = self
+            .store
+            .get(&self.tree(TODOS), key.as_bytes())?
+            .ok_or_else(|| not_found(key))?;
+        schema::decode(&encoded_stored)
+    }
+}
+
+impl Create<Todo, Todo, TodoError> for TodoRepository {
+    fn create(&self, data: Todo) -> Result<Todo, TodoError> {
+        self.create_idempotent(data, None)
+    }
+}
+
+impl Update<Value, Todo, TodoError> for TodoRepository {
+    fn update(&self, data: Value) -> Result<Todo, TodoError> {
+        self.update_if(data, None)
+    }
+}
+
+impl Replace<Todo, Todo, TodoError> for TodoRepository {
+    fn replace(&self, data: Todo) -> Result<Todo, TodoError> {
+        self.replace_if(data, None)
+    }
+}
+
+impl Delete<Todo, TodoError> for TodoRepository {
+    fn delete(&self, key: &str) -> Result<Todo, TodoError> {
+        self.delete_if(key, None)
+    }
+}
+
+/// Writes which only go ahead while the stored document is at a revision the client has seen.
+/// Checking and writing happen in one transaction, a compare-and-swap of the document.
+impl TodoRepository {
+    /// create, remembering the todo it makes under idempotency_key for the idempotency window.
+    /// A retry with the same key and data gets the remembered todo back instead of another one,
+    /// a retry with other data is a conflict. Failed creates aren't remembered.
+    pub fn create_idempotent(
+        &self,
+        data: Todo,
+        idempotency_key: Option<&str>,
+    ) -> Result<Todo, TodoError> {
+        let remembered = self.tree(idempotency::IDEMPOTENCY);
+        let now = now();
+        if let Some(idempotency_key) = idempotency_key {
+            idempotency::check_key(idempotency_key)?;
+            // Most retries are answered before a key is used up on them.
+            let record = self.store.get(&remembered, idempotency_key.as_bytes())?;
+            if let Some(replayed) = self.replay(idempotency_key, record, &data, now)? {
+                return Ok(replayed);
+            }
+        }
+        let mut todo = data.clone();
+        for _ in 0..KEY_ATTEMPTS {
+            todo._key = self.keys.generate(&*self.store, &data._key)?;
+            todo._rev = self.first_rev(&todo._key)?;
+            // Only claims a vacant key, so concurrent creates never overwrite each other.
+            let created = self.transaction(|tx| {
+                if let Some(idempotency_key) = idempotency_key {
+                    let record = tx.get(&remembered, idempotency_key.as_bytes())?;
+                    if let Some(replayed) = self.replay(idempotency_key, record, &data, now)? {
+                        return Ok(Some(replayed));
+                    }
+                }
+                let created = self.create_in(tx, &todo)?;
+                if let (Some(idempotency_key), Some(created)) = (idempotency_key, &created) {
+                    self.remember(tx, idempotency_key, &data, created, now)?;
+                }
+                Ok(created)
+            })?;
+            if let Some(created) = created {
+                return Ok(created);
+            }
+            if !self.keys.retry_on_collision() {
+                break;
+            }
+        }
+        Err(TodoError::Conflict(format!(
+            "_key {:?} is already taken.",
+            todo._key
+        )))
+    }
+
+    /// update, as long as the stored document satisfies condition.
+    pub fn update_if(&self, data: Value, condition: Option<&IfMatch>) -> Result<Todo, TodoError> {
+        self.transaction(|tx| self.update_in(tx, &data, condition))
+    }
+
+    /// Applies the RFC 6902 patch to the todo under key, as long as the stored document
+    /// satisfies condition. A failing `test` operation leaves the todo as it was.
+    pub fn patch_if(
+        &self,
+        key: &str,
+        patch: &json_patch::Patch,
+        condition: Option<&IfMatch>,
+    ) -> Result<Todo, TodoError> {
+        self.transaction(|tx| {
+            self.change_in(tx, key, condition, |decoded_val| {
+                json_patch::patch(decoded_val, patch).map_err(|e| match e {
+                    json_patch::PatchError::TestFailed => {
+                        TodoError::Conflict(format!("Todo {:?} failed a patch test.", key))
+                    }
+                    json_patch::PatchError::InvalidPointer => {
+                        TodoError::Validation(format!("Patch of todo {:?}: {}.", key, e))
+                    }
+                })
+            })
+        })
+    }
+
+    /// Applies edit to the todo under key, the way its format calls for.
+    /// Without a key, merge patches name the todo by their _key, JSON Patches need one.
+    pub fn edit_if(
+        &self,
+        key: Option<&str>,
+        edit: Edit,
+        condition: Option<&IfMatch>,
+    ) -> Result<Todo, TodoError> {
+        match (edit, key) {
+            (Edit::Merge(mut data), Some(key)) => {
+                match data.get("_key") {
+                    None => {}
+                    Some(body_key) if body_key == key => {}
+                    Some(_) => return Err(key_unchangeable(key)),
+                }
+                if let Some(fields) = data.as_object_mut() {
+                    fields.insert("_key".to_owned(), json!(key));
+                }
+                self.update_if(data, condition)
+            }
+            (Edit::Merge(data), None) => self.update_if(data, condition),
+            (Edit::Patch(patch), Some(key)) => self.patch_if(key, &patch, condition),
+            (Edit::Patch(_), None) => Err(TodoError::Validation(
+                "A JSON Patch needs the _key of the todo in the path.".to_owned(),
+            )),
+        }
+    }
+
+    /// replace, as long as the stored document satisfies condition.
+    /// Without a condition the document is created when there is none under its key.
+    pub fn replace_if(&self, data: Todo, condition: Option<&IfMatch>) -> Result<Todo, TodoError> {
+        let first_rev = self.first_rev(&data._key)?;
+        self.transaction(|tx| self.replace_in(tx, &data, first_rev, condition))
+    }
+
+    /// Moves the todo under key to the status of action, as long as it satisfies condition and
+    /// its status may become that one.
+    pub fn act(
+        &self,
+        key: &str,
+        action: Action,
+        condition: Option<&IfMatch>,
+    ) -> Result<Todo, TodoError> {
+        self.transaction(|tx| {
+            self.change_in(tx, key, condition, |decoded_val| {
+                decoded_val["status"] = json!(action.status());
+                Ok(())
+            })
+        })
+    }
+
+    /// delete, as long as the stored document satisfies condition.
+    pub fn delete_if(&self, key: &str, condition: Option<&IfMatch>) -> Result<Todo, TodoError> {
+        self.transaction(|tx| self.delete_in(tx, key, condition))
+    }
+}
+
+/// The writes as parts of a transaction, each one checks everything before it writes anything.
+impl TodoRepository {
+    /// Stores data, keyed and at its first revision, unless its key is taken.
+    /// Returns the stored todo, None when the key is taken.
+    fn create_in(&self, tx: &dyn Transaction, data: &Todo) -> Result<Option<Todo>, TodoError> {
+        let tree = self.tree(TODOS);
+        let key = data._key.as_bytes();
+        if tx.get(&tree, key)?.is_some() {
+            return Ok(None);
+        }
+        let mut stored = data.clone();
+        stored.follow(None, now())?;
+        tx.insert(&tree, key, schema::encode(&stored)?)?;
+        self.reindex_doc(tx, key, None, Some(&stored))?;
+        Ok(Some(stored))
+    }
+
+    /// Merges data into the todo its _key names.
+    fn update_in(
+        &self,
+        tx: &dyn Transaction,
+        data: &Value,
+        condition: Option<&IfMatch>,
+    ) -> Result<Todo, TodoError> {
+        let key = data["_key"].as_str().ok_or_else(|| {
+            TodoError::invalid_fields(vec![FieldError {
+                field: "_key".to_owned(),
+                message: "is required".to_owned(),
+            }])
+        })?;
+        self.change_in(tx, key, condition, |decoded_val| {
+            json_patch::merge(decoded_val, data);
+            Ok(())
+        })
+    }
+
+    /// Makes change to the todo under key as JSON, as long as it satisfies condition.
+    /// change may not touch _key, _rev only moves forward whatever it does.
+    fn change_in(
+        &self,
+        tx: &dyn Transaction,
+        key: &str,
+        condition: Option<&IfMatch>,
+        change: impl Fn(&mut Value) -> Result<(), TodoError>,
+    ) -> Result<Todo, TodoError> {
+        let tree = self.tree(TODOS);
+        let encoded_stored = tx
+            .get(&tree, key.as_bytes())?
+            .ok_or_else(|| not_found(key))?;
+        let previous = schema::decode(&encoded_stored)?;
+        check_revision(key, Some(&previous), condition)?;
+        let mut decoded_val =
+            serde_json::to_value(&previous).map_err(|e| TodoError::Internal(e.to_string()))?;
+        change(&mut decoded_val)?;
+        // Do not let _key change, and only let _rev move forward.
+        let fields = decoded_val.as_object_mut().ok_or_else(|| {
+            TodoError::Validation(format!("Todo {:?} would no longer be a document.", key))
+        })?;
+        if fields.get("_key") != Some(&json!(key)) {
+            return Err(key_unchangeable(key));
+        }
+        fields.insert("_rev".to_owned(), json!(previous._rev + 1));
+
+        let mut decoded = Todo::from_json(decoded_val)?;
+        decoded.follow(Some(&previous), now())?;
+        self.archive(tx, key.as_bytes(), previous._rev, &encoded_stored)?;
+        tx.insert(&tree, key.as_bytes(), schema::encode(&decoded)?)?;
+        self.reindex_doc(tx, key.as_bytes(), Some(&previous), Some(&decoded))?;
+        Ok(decoded)
+    }
+
+    /// Stores data over the todo under its key, or at first_rev when there is none.
+    fn replace_in(
+        &self,
+        tx: &dyn Transaction,
+        data: &Todo,
+        first_rev: u64,
+        condition: Option<&IfMatch>,
+    ) -> Result<Todo, TodoError> {
+        let tree = self.tree(TODOS);
+        let key = data._key.as_bytes();
+        let encoded_stored = tx.get(&tree, key)?;
+        // The entries of a document which doesn't decode can't be found, reindex drops them.
+        let previous = encoded_stored
+            .as_ref()
+            .and_then(|old| schema::decode(old).ok());
+        check_revision(&data._key, previous.as_ref(), condition)?;
+        let mut stored = Todo {
+            _rev: previous
+                .as_ref()
+                .map_or(first_rev, |previous| previous._rev + 1),
+            ..data.clone()
+        };
+        stored.follow(previous.as_ref(), now())?;
+        if let (Some(previous), Some(encoded)) = (&previous, &encoded_stored) {
+            self.archive(tx, key, previous._rev, encoded)?;
+        }
+        tx.insert(&tree, key, schema::encode(&stored)?)?;
+        self.reindex_doc(tx, key, previous.as_ref(), Some(&stored))?;
+        Ok(stored)
+    }
+
+    /// Moves the todo under key to the trash.
+    /// A document which doesn't decode couldn't be restored, so it is left in place for fsck to
+    /// quarantine or repair, and the delete fails.
+    fn delete_in(
+        &self,
+        tx: &dyn Transaction,
+        key: &str,
+        condition: Option<&IfMatch>,
+    ) -> Result<Todo, TodoError> {
+        let tree = self.tree(TODOS);
+        let encoded_stored = tx
+            .get(&tree, key.as_bytes())?
+            .ok_or_else(|| not_found(key))?;
+        let doc = schema::decode(&encoded_stored)?;
+        check_revision(key, Some(&doc), condition)?;
+        tx.remove(&tree, key.as_bytes())?;
+        self.reindex_doc(tx, key.as_bytes(), Some(&doc), None)?;
+        self.archive(tx, key.as_bytes(), doc._rev, &encoded_stored)?;
+        self.trash(tx, key.as_bytes(), &doc)?;
+        Ok(doc)
+    }
+}
+
+/// An empty repository on each backend, for tests which should pass on all of them.
+#[cfg(test)]
+pub(crate) fn every_backend() -> Vec<TodoRepository> {
+    vec![
+        TodoRepository::in_memory(),
+        TodoRepository::new(Arc::new(SledStore::temporary().unwrap())),
+        TodoRepository::new(Arc::new(SqliteStore::in_memory().unwrap())),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyed(key: &str, title: &str) -> Todo {
+        Todo {
+            _key: key.to_owned(),
+            ..Todo::new(title)
+        }
+    }
+
+    fn listed(repo: &TodoRepository, limit: u64) -> usize {
+        let options = ListOptions {
+            limit: Some(limit),
+            ..ListOptions::default()
+        };
+        repo.list(&options).unwrap().items.len()
+    }
+
+    fn exercise(repo: TodoRepository) {
+        repo.replace(keyed("1", "first")).unwrap();
+        let created = repo.create(Todo::new("second")).unwrap();
+        assert_eq!(created._key, "00000000000000000000");
+
+        // A clone sees the same data.
+        let other = repo.clone();
+        assert_eq!(other.fetch(&created._key).unwrap().title, "second");
+        assert_eq!(listed(&other, 10), 2);
+        assert_eq!(listed(&other, 1), 1);
+
+        let updated = repo
+            .update(json!({"_key": "1", "title": "edited"}))
+            .unwrap();
+        assert_eq!(updated.title, "edited");
+
+        repo.delete("1").unwrap();
+        assert_eq!(listed(&repo, 10), 1);
+    }
+
+    #[test]
+    fn operations_go_through_every_backend() {
+        for repo in every_backend() {
+            exercise(repo);
+        }
+    }
+
+    fn paging(repo: TodoRepository) {
+        for key in &["a", "b", "c", "d", "e"] {
+            repo.replace(keyed(key, key)).unwrap();
+        }
+        let titles = |page: &Page<Todo>| -> Vec<String> {
+            page.items.iter().map(|todo| todo.title.clone()).collect()
+        };
+
+        let first = repo
+            .list(&ListOptions::from_query("limit=2").unwrap())
+            .unwrap();
+        assert_eq!(titles(&first), vec!["a", "b"]);
+        assert_eq!(first.total, 5);
+        let cursor = first.next_cursor.clone().unwrap();
+        assert_eq!(
+            first.links.next.as_deref(),
+            Some(format!("/v1/todos?limit=2&after={}", cursor).as_str())
+        );
+
+        let query = format!("limit=2&after={}", cursor);
+        let second = repo
+            .list(&ListOptions::from_query(&query).unwrap())
+            .unwrap();
+        assert_eq!(titles(&second), vec!["c", "d"]);
+        let query = format!("limit=2&after={}", second.next_cursor.unwrap());
+        let last = repo
+            .list(&ListOptions::from_query(&query).unwrap())
+            .unwrap();
+        assert_eq!(titles(&last), vec!["e"]);
+        assert_eq!(last.next_cursor, None);
+        assert_eq!(last.links.next, None);
+
+        let skipped = repo
+            .list(&ListOptions::from_query("offset=3&limit=5").unwrap())
+            .unwrap();
+        assert_eq!(titles(&skipped), vec!["d", "e"]);
+        assert_eq!(
+            skipped.links.prev.as_deref(),
+            Some("/v1/todos?limit=5&offset=0")
+        );
+        let query = format!("offset=1&after={}", cursor);
+        let both = repo
+            .list(&ListOptions::from_query(&query).unwrap())
+            .unwrap();
+        assert_eq!(titles(&both), vec!["d", "e"]);
+
+        let bad_cursor = ListOptions::from_query("after=zz").unwrap();
+        assert_eq!(repo.list(&bad_cursor).unwrap_err().status(), 400);
+        assert_eq!(
+            ListOptions::from_query("limit=lots").unwrap_err().status(),
+            400
+        );
+    }
+
+    #[test]
+    fn filtering_and_sorting() {
+        let repo = TodoRepository::in_memory();
+        let todos = vec![
+            ("a", "Buy milk", 30, TodoStatus::New),
+            ("b", "walk the dog", 10, TodoStatus::Complete),
+            ("c", "buy eggs", 20, TodoStatus::Started),
+            ("d", "Call mum", 40, TodoStatus::New),
+        ];
+        for (key, title, timestamp, status) in todos {
+            repo.replace(Todo {
+                timestamp,
+                status,
+                ..keyed(key, title)
+            })
+            .unwrap();
+        }
+        let keys = |query: &str| -> (Vec<String>, u64) {
+            let page = repo.list(&ListOptions::from_query(query).unwrap()).unwrap();
+            let keys = page.items.into_iter().map(|todo| todo._key).collect();
+            (keys, page.total)
+        };
+
+        assert_eq!(keys("status=New").0, vec!["a", "d"]);
+        assert_eq!(keys("status=new,started").0, vec!["a", "c", "d"]);
+        assert_eq!(keys("since=20&until=30").0, vec!["a", "c"]);
+        assert_eq!(keys("title=BUY").0, vec!["a", "c"]);
+        assert_eq!(keys("title=buy&limit=1"), (vec!["a".to_owned()], 2));
+        assert_eq!(keys("sort=timestamp").0, vec!["b", "c", "a", "d"]);
+        assert_eq!(keys("sort=-timestamp").0, vec!["d", "a", "c", "b"]);
+        assert_eq!(keys("sort=title").0, vec!["c", "a", "d", "b"]);
+        assert_eq!(keys("sort=status").0, vec!["a", "d", "c", "b"]);
+        assert_eq!(keys("sort=-timestamp&status=New").0, vec!["d", "a"]);
+
+        // Cursors keep the sort order and the filters.
+        let first = repo
+            .list(&ListOptions::from_query("sort=-timestamp&limit=2&title=u").unwrap())
+            .unwrap();
+        let next = first.links.next.unwrap();
+        assert!(next.contains("sort=-timestamp") && next.contains("title=u"));
+        let query = format!(
+            "sort=-timestamp&limit=2&title=u&after={}",
+            first.next_cursor.unwrap()
+        );
+        assert_eq!(keys(&query), (vec!["c".to_owned()], 3));
+    }
+
+    #[test]
+    fn paging_through_every_backend() {
+        for repo in every_backend() {
+            paging(repo);
+        }
+    }
+
+    fn indexing(repo: TodoRepository) {
+        let entries = |entity: &str| repo.store.iter(&repo.tree(entity)).count();
+        let keys = |query: &str| -> Vec<String> {
+            let page = repo.list(&ListOptions::from_query(query).unwrap()).unwrap();
+            page.items.into_iter().map(|todo| todo._key).collect()
+        };
+        repo.replace(Todo {
+            timestamp: -5,
+            ..keyed("a", "a")
+        })
+        .unwrap();
+        repo.replace(Todo {
+            timestamp: 10,
+            ..keyed("b", "b")
+        })
+        .unwrap();
+        let created = repo
+            .create(Todo {
+                timestamp: 20,
+                ..Todo::new("c")
+            })
+            .unwrap();
+        assert_eq!(entries(index::BY_STATUS), 3);
+        assert_eq!(entries(index::BY_TIMESTAMP), 3);
+        assert_eq!(keys("until=10"), vec!["a", "b"]);
+
+        // Old entries make way for new ones.
+        repo.update(json!({"_key": "b", "status": "Complete", "timestamp": 30}))
+            .unwrap();
+        repo.replace(Todo {
+            status: TodoStatus::Started,
+            ..keyed("a", "a")
+        })
+        .unwrap();
+        repo.delete(&created._key).unwrap();
+        assert_eq!(entries(index::BY_STATUS), 2);
+        assert_eq!(entries(index::BY_TIMESTAMP), 2);
+        assert_eq!(keys("status=complete&since=30&until=30"), vec!["b"]);
+        assert_eq!(keys("status=new,started"), vec!["a"]);
+        assert!(keys("until=10").is_empty());
+
+        // A failed write leaves the indexes alone.
+        assert!(repo.update(json!({"_key": "b", "status": "Lost"})).is_err());
+        assert_eq!(keys("status=complete"), vec!["b"]);
+
+        for entry in repo
+            .store
+            .iter(&repo.tree(index::BY_STATUS))
+            .collect::<Vec<_>>()
+        {
+            repo.store
+                .remove(&repo.tree(index::BY_STATUS), &entry.unwrap().0)
+                .unwrap();
+        }
+        assert!(keys("status=complete").is_empty());
+        assert_eq!(repo.reindex().unwrap(), 2);
+        assert_eq!(keys("status=complete"), vec!["b"]);
+        assert_eq!(entries(index::BY_TIMESTAMP), 2);
+
+        // Index entries left behind by a todo which is gone don't count.
+        repo.store.remove(&repo.tree(TODOS), b"b").unwrap();
+        let complete = ListOptions::from_query("status=complete&since=0").unwrap();
+        assert_eq!(repo.list(&complete).unwrap().total, 0);
+    }
+
+    #[test]
+    fn indexes_through_every_backend() {
+        for repo in every_backend() {
+            indexing(repo);
+        }
+    }
+
+    #[test]
+    fn stores_without_indexes_get_reindexed() {
+        let repo = TodoRepository::in_memory();
+        let acme = repo.for_tenant("acme").unwrap();
+        for (repo, key) in &[(&repo, "1"), (&acme, "2"), (&acme, "3")] {
+            repo.store
+                .insert(
+                    &repo.tree(TODOS),
+                    key.as_bytes(),
+                    encode(&keyed(key, key)).unwrap(),
+                )
+                .unwrap();
+        }
+        assert!(acme
+            .list(&ListOptions::from_query("status=new").unwrap())
+            .unwrap()
+            .items
+            .is_empty());
+
+        let indexed = repo.reindex_all().unwrap();
+        assert_eq!(indexed, vec![(None, 1), (Some("acme".to_owned()), 2)]);
+        assert_eq!(
+            acme.list(&ListOptions::from_query("status=new").unwrap())
+                .unwrap()
+                .total,
+            2
+        );
+        assert_eq!(
+            repo.store.get(META, INDEX_VERSION).unwrap(),
+            Some(vec![index::VERSION])
+        );
+        assert_eq!(tenant_of("tenant/acme/todos.by_status"), Some("acme"));
+        assert_eq!(tenant_of(TODOS), None);
+    }
+
+    fn revisions(repo: TodoRepository) {
+        let created = repo.create(Todo::new("a")).unwrap();
+        let key = created._key.clone();
+        assert_eq!(created.rev(), 1);
+        assert_eq!(repo.fetch(&key).unwrap().etag(), "\"1\"");
+        let stale = IfMatch::parse("\"1\"");
+
+        // _rev in the body is not the client's to set.
+        let patch = json!({"_key": key, "title": "b", "_rev": 99});
+        assert_eq!(repo.update_if(patch, Some(&stale)).unwrap().rev(), 2);
+        let patch = json!({"_key": key, "title": "c"});
+        assert_eq!(
+            repo.update_if(patch, Some(&stale)).unwrap_err().status(),
+            412
+        );
+        assert_eq!(repo.fetch(&key).unwrap().title, "b");
+
+        let replacement = || Todo {
+            _key: key.clone(),
+            ..Todo::new("d")
+        };
+        assert_eq!(
+            repo.replace_if(replacement(), Some(&stale))
+                .unwrap_err()
+                .code(),
+            "precondition_failed"
+        );
+        let current = IfMatch::parse("\"7\", \"2\"");
+        assert_eq!(
+            repo.replace_if(replacement(), Some(&current))
+                .unwrap()
+                .rev(),
+            3
+        );
+        assert_eq!(
+            repo.replace_if(keyed("new", "e"), Some(&IfMatch::Any))
+                .unwrap_err()
+                .status(),
+            412
+        );
+        assert_eq!(repo.replace(keyed("new", "e")).unwrap().rev(), 1);
+
+        assert_eq!(
+            repo.delete_if(&key, Some(&stale)).unwrap_err().status(),
+            412
+        );
+        assert_eq!(repo.fetch(&key).unwrap().title, "d");
+        assert_eq!(repo.delete_if(&key, Some(&IfMatch::Any)).unwrap().rev(), 3);
+        assert_eq!(listed(&repo, 10), 1);
+    }
+
+    #[test]
+    fn revisions_through_every_backend() {
+        for repo in every_backend() {
+            revisions(repo);
+        }
+
+        // Documents from before revisions existed start out at 0.
+        let repo = TodoRepository::in_memory();
+        let old = json!({"_key": "old", "title": "x", "timestamp": 0, "status": "New"});
+        repo.store
+            .insert(TODOS, b"old", serde_cbor::to_vec(&old).unwrap())
+            .unwrap();
+        assert_eq!(repo.fetch("old").unwrap().rev(), 0);
+        let patch = json!({"_key": "old", "title": "y"});
+        assert_eq!(
+            repo.update_if(patch, Some(&IfMatch::parse("\"0\"")))
+                .unwrap()
+                .rev(),
+            1
+        );
+    }
+
+    #[test]
+    fn json_patch_edits() {
+        let repo = TodoRepository::in_memory();
+        repo.replace(keyed("a", "milk")).unwrap();
+        let patch = |ops: Value| Edit::Patch(json_patch::from_value(ops).unwrap());
+
+        let started = patch(json!([
+            {"op": "test", "path": "/status", "value": "New"},
+            {"op": "replace", "path": "/status", "value": "Started"},
+            {"op": "replace", "path": "/_rev", "value": 40}
+        ]));
+        let edited = repo.edit_if(Some("a"), started.clone(), None).unwrap();
+        assert_eq!((edited.status, edited._rev), (TodoStatus::Started, 2));
+        // The test no longer passes, nothing changes.
+        assert_eq!(
+            repo.edit_if(Some("a"), started, None).unwrap_err().status(),
+            409
+        );
+        assert_eq!(repo.fetch("a").unwrap()._rev, 2);
+
+        let rekey = patch(json!([{"op": "replace", "path": "/_key", "value": "b"}]));
+        assert_eq!(
+            repo.edit_if(Some("a"), rekey.clone(), None)
+                .unwrap_err()
+                .status(),
+            422
+        );
+        assert_eq!(repo.edit_if(None, rekey, None).unwrap_err().status(), 422);
+        let unknown = patch(json!([{"op": "remove", "path": "/nothing"}]));
+        assert_eq!(
+            repo.edit_if(Some("a"), unknown, None).unwrap_err().status(),
+            422
+        );
+        let stale = IfMatch::parse("\"1\"");
+        let renamed = patch(json!([{"op": "replace", "path": "/title", "value": "oat milk"}]));
+        assert_eq!(
+            repo.edit_if(Some("a"), renamed, Some(&stale))
+                .unwrap_err()
+                .status(),
+            412
+        );
+
+        // Merge patches may name the key, as long as it is the one edited.
+        let merge = Edit::Merge(json!({"title": "oat milk"}));
+        assert_eq!(repo.edit_if(Some("a"), merge, None).unwrap()._rev, 3);
+        let moved = Edit::Merge(json!({"_key": "b", "title": "eggs"}));
+        assert_eq!(
+            repo.edit_if(Some("a"), moved, None).unwrap_err().status(),
+            422
+        );
+        assert_eq!(repo.fetch("a").unwrap().title, "oat milk");
+    }
+
+    #[test]
+    fn status_changes() {
+        let repo = TodoRepository::in_memory();
+        repo.replace(keyed("a", "milk")).unwrap();
+
+        let started = repo.act("a", Action::Start, None).unwrap();
+        let started_at = started.started_at.unwrap();
+        assert_eq!(started.completed_at, None);
+        let blocked = repo.act("a", Action::Block, None).unwrap();
+        assert_eq!(
+            repo.act("a", Action::Complete, None).unwrap_err().status(),
+            409
+        );
+        repo.act("a", Action::Start, None).unwrap();
+        let done = repo
+            .act(
+                "a",
+                Action::Complete,
+                Some(&IfMatch::parse(&blocked.etag())),
+            )
+            .unwrap_err();
+        assert_eq!(done.status(), 412);
+        let done = repo.act("a", Action::Complete, None).unwrap();
+        assert_eq!(done.started_at, Some(started_at));
+        assert!(done.completed_at.is_some());
+
+        // Edits follow the same rules, and can't touch the timestamps.
+        let back = json!({"_key": "a", "status": "Started"});
+        assert_eq!(repo.update(back).unwrap_err().status(), 409);
+        let forged = json!({"_key": "a", "title": "oat milk", "completed_at": 1});
+        assert_eq!(repo.update(forged).unwrap().completed_at, done.completed_at);
+        let reopened = repo.act("a", Action::Reopen, None).unwrap();
+        assert_eq!((reopened.started_at, reopened.completed_at), (None, None));
+        assert_eq!(
+            repo.act("nothing", Action::Start, None)
+                .unwrap_err()
+                .status(),
+            404
+        );
+
+        let created = repo
+            .create(Todo {
+                status: TodoStatus::Started,
+                started_at: Some(1),
+                ..Todo::new("eggs")
+            })
+            .unwrap();
+        assert_ne!(created.started_at, Some(1));
+        let cancelled = Todo {
+            status: TodoStatus::Cancelled,
+            ..created.clone()
+        };
+        repo.replace(cancelled).unwrap();
+        assert_eq!(repo.replace(created).unwrap_err().status(), 409);
+    }
+
+    #[test]
+    fn create_on_an_empty_store() {
+        for strategy in &[KeyStrategy::Sequence, KeyStrategy::Uuid, KeyStrategy::Ulid] {
+            let repo = TodoRepository::in_memory().with_key_generator(strategy.generator());
+            let a = repo.create(Todo::new("a")).unwrap();
+            let b = repo.create(Todo::new("b")).unwrap();
+            assert_ne!(a._key, b._key);
+            assert_eq!(listed(&repo, 10), 2);
+        }
+    }
+
+    #[test]
+    fn client_supplied_keys_do_not_overwrite() {
+        let repo =
+            TodoRepository::in_memory().with_key_generator(KeyStrategy::ClientSupplied.generator());
+        repo.create(keyed("groceries", "buy milk")).unwrap();
+        assert!(repo.create(keyed("groceries", "buy eggs")).is_err());
+        assert!(repo.create(keyed("Not A Slug", "buy eggs")).is_err());
+        assert_eq!(repo.fetch("groceries").unwrap().title, "buy milk");
+    }
+
+    #[test]
+    fn typed_errors() {
+        let repo = TodoRepository::in_memory();
+        assert_eq!(repo.fetch("7").unwrap_err(), not_found("7"));
+        assert_eq!(repo.delete("7").unwrap_err().status(), 404);
+        assert_eq!(
+            repo.update(json!({"_key": "7", "title": "x"}))
+                .unwrap_err()
+                .status(),
+            404
+        );
+        assert_eq!(
+            repo.update(json!({"title": "x"})).unwrap_err().status(),
+            422
+        );
+        assert_eq!(repo.for_tenant("NO").err().map(|e| e.status()), Some(400));
+    }
+
+    #[test]
+    fn corrupt_records_are_errors() {
+        let repo = TodoRepository::in_memory();
+        repo.store
+            .insert(TODOS, b"rotten", b"\xff not cbor".to_vec())
+            .unwrap();
+        assert_eq!(repo.fetch("rotten").unwrap_err().code(), "decode");
+        assert_eq!(
+            repo.update(json!({"_key": "rotten", "title": "x"}))
+                .unwrap_err()
+                .code(),
+            "decode"
+        );
+        assert_eq!(listed(&repo, 10), 0);
+        assert_eq!(repo.delete("rotten").unwrap_err().code(), "decode");
+        assert!(repo.store.get(TODOS, b"rotten").unwrap().is_some());
+
+        repo.replace(keyed("1", "first")).unwrap();
+        assert_eq!(
+            repo.update(json!({"_key": "1", "title": 5}))
+                .unwrap_err()
+                .status(),
+            422
+        );
+        assert_eq!(repo.fetch("1").unwrap().title, "first");
+    }
+
+    #[test]
+    fn tenants_are_isolated() {
+        let repo = TodoRepository::in_memory();
+        let acme = repo.for_tenant("acme").unwrap();
+        let initech = repo.scoped(Some("initech")).unwrap();
+
+        repo.replace(keyed("1", "default")).unwrap();
+        acme.replace(keyed("1", "acme")).unwrap();
+
+        assert_eq!(repo.fetch("1").unwrap().title, "default");
+        assert_eq!(acme.fetch("1").unwrap().title, "acme");
+        assert_eq!(listed(&initech, 10), 0);
+        assert_eq!(listed(&repo.scoped(None).unwrap(), 10), 1);
+
+        acme.delete("1").unwrap();
+        assert_eq!(repo.fetch("1").unwrap().title, "default");
+
+        assert!(repo.for_tenant("../etc").is_err());
+        assert!(repo.for_tenant("").is_err());
+    }
+
+    #[test]
+    fn config_resolution() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let no_env = |_: &str| None;
+        let env = |name: &str| match name {
+            DbConfig::ENV_VAR => Some("/var/lib/todo".to_owned()),
+            DbConfig::BACKEND_ENV_VAR => Some("sqlite".to_owned()),
+            _ => None,
+        };
+
+        let default = DbConfig::from_args(args(&[]), no_env).unwrap();
+        assert_eq!(default.path, PathBuf::from(DbConfig::DEFAULT_PATH));
+        assert_eq!(default.backend, Backend::Sled);
+        assert_eq!(default.keys, KeyStrategy::Sequence);
+        assert_eq!(default.trash_retention, days(30));
+        assert_eq!(default.idempotency_window, Duration::from_secs(24 * 3600));
+
+        let from_env = DbConfig::from_args(args(&[]), env).unwrap();
+        assert_eq!(from_env.path, PathBuf::from("/var/lib/todo"));
+        assert_eq!(from_env.backend, Backend::Sqlite);
+
+        let flags = DbConfig::from_args(
+            args(&[
+                "--db-path",
+                "/tmp/a",
+                "--db-backend=memory",
+                "--key-strategy=uuid",
+            ]),
+            env,
+        )
+        .unwrap();
+        assert_eq!(flags.path, PathBuf::from("/tmp/a"));
+        assert_eq!(flags.backend, Backend::Memory);
+        assert_eq!(flags.keys, KeyStrategy::Uuid);
+
+        let kept = DbConfig::from_args(args(&["--trash-retention-days=never"]), no_env).unwrap();
+        assert_eq!(kept.trash_retention, None);
+        let week = DbConfig::from_args(args(&["--trash-retention-days", "7"]), no_env).unwrap();
+        assert_eq!(week.trash_retention, Some(Duration::from_secs(7 * 86400)));
+        let env = |name: &str| match name {
+            DbConfig::IDEMPOTENCY_WINDOW_ENV_VAR => Some("2".to_owned()),
+            _ => None,
+        };
+        let window = DbConfig::from_args(args(&[]), env).unwrap();
+        assert_eq!(window.idempotency_window, Duration::from_secs(7200));
+
+        assert!(DbConfig::from_args(args(&["--db-backend", "csv"]), no_env).is_err());
+        assert!(DbConfig::from_args(args(&["--trash-retention-days=soon"]), no_env).is_err());
+        assert!(DbConfig::from_args(args(&["--idempotency-window-hours=-1"]), no_env).is_err());
+        let forever = format!("--trash-retention-days={}", u64::MAX / 24 + 1);
+        assert!(DbConfig::from_args(args(&[&forever]), no_env).is_err());
+        let forever = format!("--idempotency-window-hours={}", u64::MAX / 3600);
+        assert!(DbConfig::from_args(args(&[&forever]), no_env).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+//! Many writes sent as one request, applied in a single transaction over the namespace.
+use super::TodoRepository;
+use crate::store::Transaction;
+use crate::{BulkMode, BulkOp, BulkReport, BulkResult, Todo, TodoError, MAX_BULK_OPERATIONS};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+impl TodoRepository {
+    /// Applies ops in order, later operations see what earlier ones wrote.
+    /// Depending on mode a failing operation keeps all of them from being applied, or only
+    /// itself. A store failure fails the whole request either way.
+    pub fn bulk(&self, ops: &[BulkOp], mode: BulkMode) -> Result<BulkReport, TodoError> {
+        if ops.len() > MAX_BULK_OPERATIONS {
+            return Err(TodoError::Validation(format!(
+                "A bulk request may carry at most {} operations.",
+                MAX_BULK_OPERATIONS
+            )));
+        }
+        let prepared: Vec<_> = ops.iter().map(|op| self.prepare(op)).collect();
+        let failed = RefCell::new(None);
+        let outcomes = self.transaction(|tx| {
+            // The revision every key written so far was left at, a todo new under it goes on
+            // from there.
+            let mut revs = HashMap::new();
+            let mut outcomes = Vec::with_capacity(ops.len());
+            for (index, (op, prepared)) in ops.iter().zip(&prepared).enumerate() {
+                let outcome = prepared
+                    .clone()
+                    .and_then(|prepared| self.apply(tx, op, prepared, &revs));
+                match outcome {
+                    Ok(todo) => {
+                        revs.insert(todo._key.clone(), todo._rev);
+                        outcomes.push(Ok(todo));
+                    }
+                    Err(e @ TodoError::StorageUnavailable(_)) => return Err(e),
+                    Err(e) if mode == BulkMode::AllOrNothing => {
+                        failed.replace(Some((index, e.clone())));
+                        return Err(e);
+                    }
+                    Err(e) => outcomes.push(Err(e)),
+                }
+            }
+            Ok(outcomes)
+        });
+        let outcomes = match (outcomes, failed.into_inner()) {
+            (Ok(outcomes), _) => outcomes,
+            (Err(_), Some((index, e))) => return Ok(none_applied(ops.len(), index, e)),
+            (Err(e), None) => return Err(e),
+        };
+        let applied = outcomes.iter().filter(|outcome| outcome.is_ok()).count() as u64;
+        Ok(BulkReport {
+            applied,
+            failed: None,
+            results: outcomes.into_iter().map(BulkResult::from).collect(),
+        })
+    }
+
+    /// The todo a create or replace stores, keyed and at the revision a todo new under its
+    /// key starts at. Keys and history are read ahead, the transaction may run more than once.
+    fn prepare(&self, op: &BulkOp) -> Result<Option<Todo>, TodoError> {
+        match op {
+            BulkOp::Create { todo } => {
+                let key = self.keys.generate(&*self.store, &todo._key)?;
+                Ok(Some(Todo {
+                    _rev: self.first_rev(&key)?,
+                    _key: key,
+                    ..todo.clone()
+                }))
+            }
+            BulkOp::Replace { todo, .. } => Ok(Some(Todo {
+                _rev: self.first_rev(&todo._key)?,
+                ..todo.clone()
+            })),
+            BulkOp::Update { .. } | BulkOp::Delete { .. } => Ok(None),
+        }
+    }
+
+    /// Applies op as part of tx, prepared as prepare left it.
+    fn apply(
+        &self,
+        tx: &dyn Transaction,
+        op: &BulkOp,
+        prepared: Option<Todo>,
+        revs: &HashMap<String, u64>,
+    ) -> Result<Todo, TodoError> {
+        let condition = op.condition();
+        let prepared = prepared.map(|todo| Todo {
+            _rev: revs
+                .get(&todo._key)
+                .map_or(todo._rev, |rev| todo._rev.max(rev + 1)),
+            ..todo
+        });
+        match (op, prepared) {
+            (BulkOp::Create { .. }, Some(todo)) => self.create_in(tx, &todo)?.ok_or_else(|| {
+                TodoError::Conflict(format!("_key {:?} is already taken.", todo._key))
+            }),
+            (BulkOp::Replace { .. }, Some(todo)) => {
+                self.replace_in(tx, &todo, todo._rev, condition.as_ref())
+            }
+            (BulkOp::Update { patch, .. }, _) => self.update_in(tx, patch, condition.as_ref()),
+            (BulkOp::Delete { key, .. }, _) => self.delete_in(tx, key, condition.as_ref()),
+            (_, None) => Err(TodoError::Internal(
+                "Bulk operation wasn't prepared.".to_owned(),
+            )),
+        }
+    }
+}
+
+/// The report of an all-or-nothing request whose operation at index failed with e.
+fn none_applied(len: usize, index: usize, e: TodoError) -> BulkReport {
+    let mut failure = Some(e);
+    let results = (0..len)
+        .map(|i| {
+            let e = if i == index { failure.take() } else { None };
+            BulkResult::from(Err(e.unwrap_or_else(|| {
+                TodoError::Conflict(format!("Not applied, operation {} failed.", index))
+            })))
+        })
+        .collect();
+    BulkReport {
+        applied: 0,
+        failed: Some(index),
+        results,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::every_backend;
+    use crate::{Fetch, Replace};
+    use serde_json::json;
+
+    fn ops(body: serde_json::Value) -> Vec<BulkOp> {
+        BulkOp::from_body(body).unwrap()
+    }
+
+    fn statuses(report: &BulkReport) -> Vec<u16> {
+        report.results.iter().map(|result| result.status).collect()
+    }
+
+    #[test]
+    fn bulk_through_every_backend() {
+        for repo in every_backend() {
+            failures_by_mode(repo);
+        }
+    }
+
+    fn failures_by_mode(repo: TodoRepository) {
+        repo.replace(Todo {
+            _key: "a".to_owned(),
+            ..Todo::new("milk")
+        })
+        .unwrap();
+        let todo = |key: &str, title: &str| json!({"_key": key, "title": title, "timestamp": 0, "status": "New"});
+
+        let failing = ops(json!([
+            {"op": "create", "todo": todo("", "eggs")},
+            {"op": "update", "patch": {"_key": "a", "title": "oat milk"}},
+            {"op": "delete", "_key": "nothing"},
+            {"op": "replace", "todo": todo("b", "bread")}
+        ]));
+        let report = repo.bulk(&failing, BulkMode::AllOrNothing).unwrap();
+        assert_eq!(
+            (report.applied, statuses(&report)),
+            (0, vec![409, 409, 404, 409])
+        );
+        assert_eq!((report.failed, report.status()), (Some(2), 404));
+        assert_eq!(repo.fetch("a").unwrap().title, "milk");
+        assert_eq!(repo.fetch("b").unwrap_err().status(), 404);
+
+        let report = repo.bulk(&failing, BulkMode::BestEffort).unwrap();
+        assert_eq!(
+            (report.applied, statuses(&report)),
+            (3, vec![200, 200, 404, 200])
+        );
+        assert_eq!(report.status(), 200);
+        assert_eq!(repo.fetch("a").unwrap().title, "oat milk");
+        assert_eq!(repo.fetch("b").unwrap().title, "bread");
+
+        // Later operations see earlier ones, a key deleted and stored again carries on counting.
+        let again = ops(json!([
+            {"op": "delete", "_key": "b", "rev": 1},
+            {"op": "replace", "todo": todo("b", "rye bread")},
+            {"op": "update", "patch": {"_key": "b", "status": "Started"}, "rev": 2},
+            {"op": "delete", "_key": "a", "rev": 1}
+        ]));
+        let report = repo.bulk(&again, BulkMode::BestEffort).unwrap();
+        assert_eq!(statuses(&report), vec![200, 200, 200, 412]);
+        let b = repo.fetch("b").unwrap();
+        assert_eq!((b.title.as_str(), b._rev), ("rye bread", 3));
+        assert_eq!(repo.history("b").unwrap().len(), 3);
+
+        let too_many = vec![failing[2].clone(); MAX_BULK_OPERATIONS + 1];
+        assert_eq!(
+            repo.bulk(&too_many, BulkMode::BestEffort)
+                .unwrap_err()
+                .status(),
+            422
+        );
+        assert_eq!(
+            BulkMode::from_query("mode=best-effort").unwrap(),
+            BulkMode::BestEffort
+        );
+        assert_eq!(BulkMode::from_query("").unwrap(), BulkMode::AllOrNothing);
+        assert!(BulkOp::from_body(json!([{"op": "drop"}])).is_err());
+    }
+}
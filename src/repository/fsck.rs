@@ -0,0 +1,449 @@
+//! Checks the trees of a namespace against each other, and optionally sets aside or repairs
+//! what doesn't add up, so nothing is silently skipped forever.
+use super::history::{parse_history_key, HISTORY};
+use super::idempotency::{rewrite_remembered, IDEMPOTENCY};
+use super::trash::{decode_trashed, encode_trashed, TRASH};
+use super::{TodoRepository, TODOS};
+use crate::store::Entry;
+use crate::{index, schema, Todo, TodoError};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Entity name of the tree bad entries are moved to, keyed by the entity they came from, a zero
+/// byte, their key, another zero byte and an id from the store, so an entry quarantined again
+/// doesn't replace the copy set aside before. Values are kept as they were.
+const QUARANTINE: &str = "todos.quarantine";
+
+/// What fsck does about the problems it finds.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FsckMode {
+    /// Only reports them.
+    #[default]
+    Check,
+    /// Moves bad entries to the quarantine tree and fixes the indexes.
+    Quarantine,
+    /// Puts right what can be, and quarantines the rest.
+    Repair,
+}
+
+/// Something wrong with an entry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// The value doesn't decode, or the key isn't one the tree uses.
+    Undecodable(String),
+    /// The todo in the value carries this `_key` instead of the key it is stored under.
+    KeyMismatch(String),
+    /// An index entry for a todo which isn't stored, or isn't stored the way the entry says.
+    StaleIndexEntry,
+    /// An index entry a stored todo should have.
+    MissingIndexEntry,
+    /// History of a key which has neither a todo nor one in the trash.
+    OrphanHistory,
+}
+
+/// What fsck did about a problem.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fix {
+    Quarantined,
+    Repaired,
+}
+
+/// A problem with the entry under key in tree, and what was done about it, None for nothing.
+/// Index entries are named by the key of their todo, history entries by it and the revision.
+#[derive(Debug)]
+pub struct Finding {
+    pub tree: String,
+    pub key: String,
+    pub problem: Problem,
+    pub fix: Option<Fix>,
+}
+
+/// Runs one fsck over a namespace, collecting what it finds.
+struct Checker<'a> {
+    repo: &'a TodoRepository,
+    mode: FsckMode,
+    findings: Vec<Finding>,
+}
+
+impl<'a> Checker<'a> {
+    fn found(&mut self, entity: &str, key: &[u8], problem: Problem, fix: Option<Fix>) {
+        let key = match parse_history_key(key) {
+            Some((key, rev)) if entity == HISTORY => format!("{} rev {}", to_key(key), rev),
+            _ => to_key(key),
+        };
+        self.findings.push(Finding {
+            tree: self.repo.tree(entity),
+            key,
+            problem,
+            fix,
+        });
+    }
+
+    /// Reports a bad entry, and moves it out of the way unless only checking.
+    fn set_aside(
+        &mut self,
+        entity: &str,
+        key: &[u8],
+        value: Vec<u8>,
+        problem: Problem,
+    ) -> Result<(), TodoError> {
+        let fix = match self.mode {
+            FsckMode::Check => None,
+            FsckMode::Quarantine | FsckMode::Repair => {
+                let store = &self.repo.store;
+                let id = store.generate_id()?.to_be_bytes();
+                let quarantined = [entity.as_bytes(), &[0], key, &[0], &id].concat();
+                let quarantine = self.repo.tree(QUARANTINE);
+                let tree = self.repo.tree(entity);
+                store.transaction(&[&quarantine, &tree], &|tx| {
+                    tx.insert(&quarantine, &quarantined, value.clone())?;
+                    tx.remove(&tree, key)?;
+                    Ok(())
+                })?;
+                store.flush()?;
+                Some(Fix::Quarantined)
+            }
+        };
+        self.found(entity, key, problem, fix);
+        Ok(())
+    }
+
+    /// Reports the todo under key carrying another _key, and when repairing stores encoded,
+    /// the todo keyed right, in its place.
+    fn rekey(
+        &mut self,
+        entity: &str,
+        key: &[u8],
+        value: Vec<u8>,
+        embedded: &str,
+        encoded: impl FnOnce() -> Result<Vec<u8>, TodoError>,
+    ) -> Result<(), TodoError> {
+        let problem = Problem::KeyMismatch(embedded.to_owned());
+        if self.mode != FsckMode::Repair {
+            return self.set_aside(entity, key, value, problem);
+        }
+        self.repo
+            .store
+            .insert(&self.repo.tree(entity), key, encoded()?)?;
+        self.found(entity, key, problem, Some(Fix::Repaired));
+        Ok(())
+    }
+
+    /// Checks the todos, returning those still stored by key.
+    fn todos(&mut self) -> Result<BTreeMap<Vec<u8>, Todo>, TodoError> {
+        let mut todos = BTreeMap::new();
+        for (key, value) in self.entries(TODOS)? {
+            let mut todo = match schema::decode(&value) {
+                Ok(todo) => todo,
+                Err(e) => {
+                    self.set_aside(TODOS, &key, value, Problem::Undecodable(e.message().into()))?;
+                    continue;
+                }
+            };
+            if todo._key.as_bytes() != &key[..] {
+                let embedded = std::mem::replace(&mut todo._key, to_key(&key));
+                self.rekey(TODOS, &key, value, &embedded, || schema::encode(&todo))?;
+                if self.mode == FsckMode::Quarantine {
+                    continue;
+                }
+            }
+            todos.insert(key, todo);
+        }
+        Ok(todos)
+    }
+
+    /// Checks that the indexes have exactly the entries of todos.
+    fn indexes(&mut self, todos: &BTreeMap<Vec<u8>, Todo>) -> Result<(), TodoError> {
+        let mut expected: BTreeSet<(&str, Vec<u8>)> = todos
+            .iter()
+            .flat_map(|(key, todo)| index::entries(key, todo))
+            .collect();
+        for entity in [index::BY_STATUS, index::BY_TIMESTAMP] {
+            for (entry, _) in self.entries(entity)? {
+                if expected.remove(&(entity, entry.clone())) {
+                    continue;
+                }
+                if self.mode != FsckMode::Check {
+                    self.repo.store.remove(&self.repo.tree(entity), &entry)?;
+                }
+                let fix = self.index_fix();
+                let key = index::doc_key(entity, &entry).to_vec();
+                self.found(entity, &key, Problem::StaleIndexEntry, fix);
+            }
+        }
+        for (entity, entry) in expected {
+            if self.mode != FsckMode::Check {
+                self.repo
+                    .store
+                    .insert(&self.repo.tree(entity), &entry, vec![])?;
+            }
+            let fix = self.index_fix();
+            let key = index::doc_key(entity, &entry).to_vec();
+            self.found(entity, &key, Problem::MissingIndexEntry, fix);
+        }
+        Ok(())
+    }
+
+    /// Index entries are only derived data, quarantining them would keep nothing worth having.
+    fn index_fix(&self) -> Option<Fix> {
+        match self.mode {
+            FsckMode::Check => None,
+            FsckMode::Quarantine | FsckMode::Repair => Some(Fix::Repaired),
+        }
+    }
+
+    /// Checks the trash, returning the keys it holds todos of.
+    fn trash(&mut self) -> Result<BTreeSet<Vec<u8>>, TodoError> {
+        let mut trashed_keys = BTreeSet::new();
+        for (key, value) in self.entries(TRASH)? {
+            let mut trashed = match decode_trashed(&value) {
+                Ok(trashed) => trashed,
+                Err(e) => {
+                    self.set_aside(TRASH, &key, value, Problem::Undecodable(e.message().into()))?;
+                    continue;
+                }
+            };
+            if trashed.todo._key.as_bytes() != &key[..] {
+                let embedded = std::mem::replace(&mut trashed.todo._key, to_key(&key));
+                self.rekey(TRASH, &key, value, &embedded, || encode_trashed(trashed))?;
+                if self.mode == FsckMode::Quarantine {
+                    continue;
+                }
+            }
+            trashed_keys.insert(key);
+        }
+        Ok(trashed_keys)
+    }
+
+    /// Checks the history, which has to belong to a todo which is stored or in the trash.
+    fn history(
+        &mut self,
+        todos: &BTreeMap<Vec<u8>, Todo>,
+        trashed: &BTreeSet<Vec<u8>>,
+    ) -> Result<(), TodoError> {
+        for (history_key, value) in self.entries(HISTORY)? {
+            let owner = match parse_history_key(&history_key) {
+                Some((owner, _)) => owner.to_vec(),
+                None => {
+                    let problem = Problem::Undecodable("Not a history key.".to_owned());
+                    self.set_aside(HISTORY, &history_key, value, problem)?;
+                    continue;
+                }
+            };
+            let mut todo = match schema::decode(&value) {
+                Ok(todo) => todo,
+                Err(e) => {
+                    let problem = Problem::Undecodable(e.message().to_owned());
+                    self.set_aside(HISTORY, &history_key, value, problem)?;
+                    continue;
+                }
+            };
+            if !todos.contains_key(&owner) && !trashed.contains(&owner) {
+                // Purging the trash would have dropped it, so repairing does.
+                if self.mode == FsckMode::Repair {
+                    self.repo
+                        .store
+                        .remove(&self.repo.tree(HISTORY), &history_key)?;
+                    let fix = Some(Fix::Repaired);
+                    self.found(HISTORY, &history_key, Problem::OrphanHistory, fix);
+                } else {
+                    self.set_aside(HISTORY, &history_key, value, Problem::OrphanHistory)?;
+                }
+                continue;
+            }
+            if todo._key.as_bytes() != &owner[..] {
+                let embedded = std::mem::replace(&mut todo._key, to_key(&owner));
+                self.rekey(HISTORY, &history_key, value, &embedded, || {
+                    schema::encode(&todo)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the remembered creates decode, the todos they made may well be gone since.
+    fn remembered(&mut self) -> Result<(), TodoError> {
+        for (key, value) in self.entries(IDEMPOTENCY)? {
+            if let Err(e) = rewrite_remembered(&value) {
+                let problem = Problem::Undecodable(e.message().to_owned());
+                self.set_aside(IDEMPOTENCY, &key, value, problem)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&self, entity: &str) -> Result<Vec<Entry>, TodoError> {
+        Ok(self
+            .repo
+            .store
+            .iter(&self.repo.tree(entity))
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+}
+
+fn to_key(key: &[u8]) -> String {
+    String::from_utf8_lossy(key).into_owned()
+}
+
+impl TodoRepository {
+    /// Checks every tree of this namespace: that stored values decode and carry the key they
+    /// are stored under, that the indexes match the todos, and that all history belongs to a
+    /// todo. mode decides whether problems are only reported, or also quarantined or repaired.
+    /// Best run while no server is using the store.
+    pub fn fsck(&self, mode: FsckMode) -> Result<Vec<Finding>, TodoError> {
+        let mut checker = Checker {
+            repo: self,
+            mode,
+            findings: vec![],
+        };
+        let todos = checker.todos()?;
+        checker.indexes(&todos)?;
+        let trashed = checker.trash()?;
+        checker.history(&todos, &trashed)?;
+        checker.remembered()?;
+        self.store.flush()?;
+        Ok(checker.findings)
+    }
+
+    /// fsck for the default namespace and for every tenant, their trees tell the findings
+    /// apart.
+    pub fn fsck_all(&self, mode: FsckMode) -> Result<Vec<Finding>, TodoError> {
+        let mut found = vec![];
+        for tenant in self.namespaces()? {
+            found.extend(self.namespace(tenant).fsck(mode)?);
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::every_backend;
+    use crate::store::TodoStore;
+    use crate::{Fetch, List, ListOptions, Replace};
+
+    fn keyed(key: &str, title: &str) -> Todo {
+        Todo {
+            _key: key.to_owned(),
+            ..Todo::new(title)
+        }
+    }
+
+    fn problems(findings: &[Finding]) -> Vec<(&str, &str, &Problem, Option<Fix>)> {
+        findings
+            .iter()
+            .map(|f| (f.tree.as_str(), f.key.as_str(), &f.problem, f.fix))
+            .collect()
+    }
+
+    #[test]
+    fn fsck_through_every_backend() {
+        for repo in every_backend() {
+            damaged(repo);
+        }
+    }
+
+    /// Breaks the store of repo in every way fsck knows, in a tenant's trees.
+    fn damaged(repo: TodoRepository) {
+        let acme = repo.for_tenant("acme").unwrap();
+        let store = &acme.store;
+        let todos = acme.tree(TODOS);
+        acme.replace(keyed("milk", "milk")).unwrap();
+        acme.replace(keyed("eggs", "eggs")).unwrap();
+        acme.replace(keyed("eggs", "brown eggs")).unwrap();
+        store.insert(&todos, b"rotten", b"\xff".to_vec()).unwrap();
+        let rye = schema::encode(&keyed("rye", "bread")).unwrap();
+        store.insert(&todos, b"bread", rye.clone()).unwrap();
+        // Leaves eggs' index entries and history behind.
+        store.remove(&todos, b"eggs").unwrap();
+
+        let checked = &repo.fsck_all(FsckMode::Check).unwrap();
+        let undecodable = match &checked[1].problem {
+            Problem::Undecodable(e) => e.clone(),
+            problem => panic!("{:?}", problem),
+        };
+        let by_status = acme.tree(index::BY_STATUS);
+        let by_timestamp = acme.tree(index::BY_TIMESTAMP);
+        assert_eq!(
+            problems(checked),
+            vec![
+                (
+                    todos.as_str(),
+                    "bread",
+                    &Problem::KeyMismatch("rye".to_owned()),
+                    None
+                ),
+                (
+                    todos.as_str(),
+                    "rotten",
+                    &Problem::Undecodable(undecodable),
+                    None
+                ),
+                (by_status.as_str(), "eggs", &Problem::StaleIndexEntry, None),
+                (
+                    by_timestamp.as_str(),
+                    "eggs",
+                    &Problem::StaleIndexEntry,
+                    None
+                ),
+                (
+                    by_status.as_str(),
+                    "bread",
+                    &Problem::MissingIndexEntry,
+                    None
+                ),
+                (
+                    by_timestamp.as_str(),
+                    "bread",
+                    &Problem::MissingIndexEntry,
+                    None
+                ),
+                (
+                    acme.tree(HISTORY).as_str(),
+                    "eggs rev 1",
+                    &Problem::OrphanHistory,
+                    None
+                ),
+            ]
+        );
+
+        // Repairing keys bread by where it is stored, quarantines what doesn't decode and drops
+        // the orphaned history.
+        let repaired = acme.fsck(FsckMode::Repair).unwrap();
+        assert_eq!(repaired.len(), checked.len());
+        assert_eq!(repaired[1].fix, Some(Fix::Quarantined));
+        assert!(repaired.iter().all(|finding| finding.fix.is_some()));
+        assert!(acme.fsck(FsckMode::Check).unwrap().is_empty());
+        assert_eq!(acme.fetch("bread").unwrap().key(), "bread");
+        assert_eq!(acme.fetch("rotten").unwrap_err().status(), 404);
+        let quarantine = acme.tree(QUARANTINE);
+        let rotten = |store: &dyn TodoStore| -> Vec<Vec<u8>> {
+            let copies = store.range(&quarantine, b"todos\0rotten\0", b"todos\0rotten\x01");
+            copies.map(|entry| entry.unwrap().1).collect()
+        };
+        assert_eq!(rotten(&**store), vec![b"\xff".to_vec()]);
+        let new = ListOptions::from_query("status=new").unwrap();
+        assert_eq!(acme.list(&new).unwrap().total, 2);
+        assert_eq!(acme.history("eggs").unwrap_err().status(), 404);
+
+        // Quarantining sets aside even what could be repaired.
+        store.insert(&todos, b"bread", rye).unwrap();
+        let quarantined = acme.fsck(FsckMode::Quarantine).unwrap();
+        assert_eq!(
+            problems(&quarantined)[0],
+            (
+                todos.as_str(),
+                "bread",
+                &Problem::KeyMismatch("rye".to_owned()),
+                Some(Fix::Quarantined)
+            )
+        );
+        assert_eq!(acme.fetch("bread").unwrap_err().status(), 404);
+        assert!(acme.fsck(FsckMode::Check).unwrap().is_empty());
+
+        // Quarantining the same key again keeps the copy set aside before.
+        store.insert(&todos, b"rotten", b"\xfe".to_vec()).unwrap();
+        acme.fsck(FsckMode::Quarantine).unwrap();
+        assert_eq!(rotten(&**store), vec![b"\xff".to_vec(), b"\xfe".to_vec()]);
+    }
+}
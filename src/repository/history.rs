@@ -0,0 +1,261 @@
+//! Every version of a todo which stops being current, through an edit, a replace, a rollback or
+//! a delete, is kept as it was stored in a history tree of its namespace. Earlier revisions can
+//! be fetched, compared and rolled back to.
+use super::{check_revision, decoded, not_found, now, TodoRepository, TODOS};
+use crate::schema;
+use crate::store::{StoreError, Transaction};
+use crate::{Fetch, IfMatch, Todo, TodoError};
+use std::convert::TryInto;
+
+/// Entity name of the history tree.
+pub(super) const HISTORY: &str = "todos.history";
+
+/// History keys start with the todo's key, prefixed by its length so no key's history is part of
+/// another's, and end with the revision.
+fn prefix(key: &[u8]) -> Vec<u8> {
+    [&(key.len() as u32).to_be_bytes()[..], key].concat()
+}
+
+fn history_key(key: &[u8], rev: u64) -> Vec<u8> {
+    [prefix(key), rev.to_be_bytes().to_vec()].concat()
+}
+
+/// The key of the todo a history key belongs to and the revision, None when it isn't a history
+/// key.
+pub(super) fn parse_history_key(history_key: &[u8]) -> Option<(&[u8], u64)> {
+    let len = u32::from_be_bytes(history_key.get(..4)?.try_into().ok()?) as usize;
+    let key = history_key.get(4..4 + len)?;
+    let rev = history_key.get(4 + len..)?.try_into().ok()?;
+    Some((key, u64::from_be_bytes(rev)))
+}
+
+fn no_revision(key: &str, rev: u64) -> TodoError {
+    TodoError::NotFound(format!("Todo {:?} has no revision {}.", key, rev))
+}
+
+impl TodoRepository {
+    /// Keeps encoded, the version of the todo under key at rev, as part of tx.
+    pub(super) fn archive(
+        &self,
+        tx: &dyn Transaction,
+        key: &[u8],
+        rev: u64,
+        encoded: &[u8],
+    ) -> Result<(), StoreError> {
+        tx.insert(
+            &self.tree(HISTORY),
+            &history_key(key, rev),
+            encoded.to_vec(),
+        )?;
+        Ok(())
+    }
+
+    /// The kept versions of key, oldest first, skipping those which don't decode.
+    fn archived(&self, key: &[u8]) -> Result<Vec<Todo>, TodoError> {
+        let start = prefix(key);
+        let end = [&start[..], &[0xff; 9]].concat();
+        let mut versions = vec![];
+        for item in decoded(self.store.range(&self.tree(HISTORY), &start, &end)) {
+            versions.push(item?.1);
+        }
+        Ok(versions)
+    }
+
+    /// Revision a todo newly stored under key starts at.
+    /// It follows the history the key may have, so an old ETag never matches the new todo.
+    pub(super) fn first_rev(&self, key: &str) -> Result<u64, TodoError> {
+        let archived = self.archived(key.as_bytes())?;
+        Ok(archived.last().map_or(1, |last| last._rev + 1))
+    }
+
+    /// The keys of the kept versions of key, to hand to purge_history.
+    pub(super) fn history_keys(&self, key: &[u8]) -> Result<Vec<Vec<u8>>, TodoError> {
+        let start = prefix(key);
+        let end = [&start[..], &[0xff; 9]].concat();
+        let mut keys = vec![];
+        for item in self.store.range(&self.tree(HISTORY), &start, &end) {
+            keys.push(item?.0);
+        }
+        Ok(keys)
+    }
+
+    /// Drops history_keys, the history of key, as part of tx, unless a todo is stored under key
+    /// again.
+    pub(super) fn purge_history(
+        &self,
+        tx: &dyn Transaction,
+        key: &[u8],
+        history_keys: &[Vec<u8>],
+    ) -> Result<(), StoreError> {
+        if tx.get(&self.tree(TODOS), key)?.is_some() {
+            return Ok(());
+        }
+        let tree = self.tree(HISTORY);
+        for history_key in history_keys {
+            tx.remove(&tree, history_key)?;
+        }
+        Ok(())
+    }
+
+    /// Every revision of key still known, oldest first and ending with the current one.
+    /// The history of a deleted todo stays until it is purged from the trash.
+    pub fn history(&self, key: &str) -> Result<Vec<Todo>, TodoError> {
+        let mut versions = self.archived(key.as_bytes())?;
+        match self.fetch(key) {
+            Ok(current) => versions.push(current),
+            Err(TodoError::NotFound(_)) if !versions.is_empty() => {}
+            Err(e) => return Err(e),
+        }
+        Ok(versions)
+    }
+
+    /// key as it was at rev.
+    pub fn fetch_revision(&self, key: &str, rev: u64) -> Result<Todo, TodoError> {
+        match self.fetch(key) {
+            Ok(current) if current._rev == rev => return Ok(current),
+            Ok(_) | Err(TodoError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        let encoded = self
+            .store
+            .get(&self.tree(HISTORY), &history_key(key.as_bytes(), rev))?
+            .ok_or_else(|| no_revision(key, rev))?;
+        schema::decode(&encoded)
+    }
+
+    /// The JSON Patch which turns revision from of key into revision to.
+    pub fn diff(&self, key: &str, from: u64, to: u64) -> Result<json_patch::Patch, TodoError> {
+        let as_json =
+            |todo: Todo| serde_json::to_value(todo).map_err(|e| TodoError::Internal(e.to_string()));
+        let from = as_json(self.fetch_revision(key, from)?)?;
+        let to = as_json(self.fetch_revision(key, to)?)?;
+        Ok(json_patch::diff(&from, &to))
+    }
+
+    /// Makes revision rev of key current again, as its next revision, as long as the current
+    /// todo satisfies condition and may move to the status rev had. A deleted todo has to be
+    /// restored before it can be rolled back.
+    pub fn rollback(
+        &self,
+        key: &str,
+        rev: u64,
+        condition: Option<&IfMatch>,
+    ) -> Result<Todo, TodoError> {
+        let target = self.fetch_revision(key, rev)?;
+        let todos = self.tree(TODOS);
+        self.transaction(|tx| {
+            let encoded_stored = tx
+                .get(&todos, key.as_bytes())?
+                .ok_or_else(|| not_found(key))?;
+            let current = schema::decode(&encoded_stored)?;
+            check_revision(key, Some(&current), condition)?;
+            let mut rolled_back = Todo {
+                _key: key.to_owned(),
+                _rev: current._rev + 1,
+                ..target.clone()
+            };
+            rolled_back.follow(Some(&current), now())?;
+            self.archive(tx, key.as_bytes(), current._rev, &encoded_stored)?;
+            tx.insert(&todos, key.as_bytes(), schema::encode(&rolled_back)?)?;
+            self.reindex_doc(tx, key.as_bytes(), Some(&current), Some(&rolled_back))?;
+            Ok(rolled_back)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Create, Delete, Replace, Update};
+    use serde_json::json;
+
+    #[test]
+    fn every_version_is_kept() {
+        let repo = TodoRepository::in_memory();
+        let key = repo.create(Todo::new("milk")).unwrap()._key;
+        repo.update(json!({"_key": key, "title": "oat milk"}))
+            .unwrap();
+        repo.update(json!({"_key": key, "status": "Complete"}))
+            .unwrap();
+
+        let titles = |versions: Vec<Todo>| -> Vec<(u64, String)> {
+            versions.into_iter().map(|t| (t._rev, t.title)).collect()
+        };
+        assert_eq!(
+            titles(repo.history(&key).unwrap()),
+            vec![
+                (1, "milk".to_owned()),
+                (2, "oat milk".to_owned()),
+                (3, "oat milk".to_owned())
+            ]
+        );
+        assert_eq!(repo.fetch_revision(&key, 1).unwrap().title, "milk");
+        assert_eq!(repo.fetch_revision(&key, 9).unwrap_err().status(), 404);
+        assert_eq!(repo.history("nothing").unwrap_err().status(), 404);
+
+        let patch = serde_json::to_value(repo.diff(&key, 1, 3).unwrap()).unwrap();
+        assert!(patch
+            .as_array()
+            .unwrap()
+            .contains(&json!({"op": "replace", "path": "/title", "value": "oat milk"})));
+
+        let stale = IfMatch::parse("\"2\"");
+        assert_eq!(
+            repo.rollback(&key, 1, Some(&stale)).unwrap_err().status(),
+            412
+        );
+        let rolled_back = repo.rollback(&key, 1, None).unwrap();
+        assert_eq!((rolled_back._rev, rolled_back.title.as_str()), (4, "milk"));
+        assert_eq!(repo.fetch(&key).unwrap().status, crate::TodoStatus::New);
+        assert_eq!(repo.history(&key).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn rollbacks_follow_the_workflow() {
+        let repo = TodoRepository::in_memory();
+        let key = repo.create(Todo::new("milk")).unwrap()._key;
+        repo.update(json!({"_key": key, "status": "Started"}))
+            .unwrap();
+        repo.update(json!({"_key": key, "status": "Complete"}))
+            .unwrap();
+
+        // A complete todo can only be reopened, not started again.
+        assert_eq!(repo.rollback(&key, 2, None).unwrap_err().status(), 409);
+        assert_eq!(repo.fetch(&key).unwrap()._rev, 3);
+
+        let reopened = repo.rollback(&key, 1, None).unwrap();
+        assert_eq!(
+            (reopened.status, reopened.started_at, reopened.completed_at),
+            (crate::TodoStatus::New, None, None)
+        );
+        let started = repo.rollback(&key, 2, None).unwrap();
+        assert_eq!(started.status, crate::TodoStatus::Started);
+        assert!(started.started_at.is_some());
+    }
+
+    #[test]
+    fn history_outlives_the_todo_until_purged() {
+        let repo = TodoRepository::in_memory();
+        repo.replace(Todo {
+            _key: "a".to_owned(),
+            ..Todo::new("first")
+        })
+        .unwrap();
+        repo.delete("a").unwrap();
+        assert_eq!(repo.history("a").unwrap().len(), 1);
+        assert_eq!(repo.rollback("a", 1, None).unwrap_err().status(), 404);
+
+        // A new todo under the key carries on counting.
+        let again = repo
+            .replace(Todo {
+                _key: "a".to_owned(),
+                ..Todo::new("second")
+            })
+            .unwrap();
+        assert_eq!(again._rev, 2);
+        repo.delete("a").unwrap();
+        repo.purge("a").unwrap();
+        assert_eq!(repo.history("a").unwrap_err().status(), 404);
+        assert_eq!(repo.first_rev("a").unwrap(), 1);
+    }
+}
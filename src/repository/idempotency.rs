@@ -0,0 +1,211 @@
+//! Creates sent with an Idempotency-Key header are remembered in a tree of their namespace for
+//! the idempotency window, so a retried create gets the todo the first one made instead of a
+//! duplicate.
+use super::{encode, now, TodoRepository};
+use crate::store::Transaction;
+use crate::{Todo, TodoError};
+use serde::{Deserialize, Serialize};
+
+/// Entity name of the tree of remembered creates, keyed by their Idempotency-Key.
+pub(super) const IDEMPOTENCY: &str = "todos.idempotency";
+
+/// Longest Idempotency-Key accepted.
+const MAX_KEY_LEN: usize = 255;
+
+/// A create as it was requested, and the todo it made.
+#[derive(Serialize, Deserialize)]
+struct Remembered {
+    /// When the todo was created, in milliseconds.
+    created_at: i64,
+    #[serde(with = "crate::schema")]
+    request: Todo,
+    #[serde(with = "crate::schema")]
+    todo: Todo,
+}
+
+/// Fails unless key is 1 to MAX_KEY_LEN visible ASCII characters.
+pub(super) fn check_key(key: &str) -> Result<(), TodoError> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN || !key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(TodoError::BadRequest(format!(
+            "Idempotency-Key must be 1 to {} visible ASCII characters.",
+            MAX_KEY_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// The record of a remembered create as the current schema version stores it.
+pub(super) fn rewrite_remembered(record: &[u8]) -> Result<Vec<u8>, TodoError> {
+    encode(&serde_cbor::from_slice::<Remembered>(record)?)
+}
+
+impl TodoRepository {
+    /// Whether remembered is past the idempotency window at now and as good as forgotten.
+    fn is_forgotten(&self, remembered: &Remembered, now: i64) -> bool {
+        now.saturating_sub(remembered.created_at) >= self.idempotency_window.as_millis() as i64
+    }
+
+    /// The todo to answer a create of request with, from the record remembered under key.
+    /// None when there is none, or when it is past the window or doesn't decode.
+    pub(super) fn replay(
+        &self,
+        key: &str,
+        record: Option<Vec<u8>>,
+        request: &Todo,
+        now: i64,
+    ) -> Result<Option<Todo>, TodoError> {
+        let remembered = match record
+            .and_then(|record| serde_cbor::from_slice::<Remembered>(&record).ok())
+            .filter(|remembered| !self.is_forgotten(remembered, now))
+        {
+            Some(remembered) => remembered,
+            None => return Ok(None),
+        };
+        if encode(&remembered.request)? != encode(request)? {
+            return Err(TodoError::Conflict(format!(
+                "Idempotency-Key {:?} was used for another todo.",
+                key
+            )));
+        }
+        Ok(Some(remembered.todo))
+    }
+
+    /// Remembers todo as what request, sent with key, created at now, as part of tx.
+    pub(super) fn remember(
+        &self,
+        tx: &dyn Transaction,
+        key: &str,
+        request: &Todo,
+        todo: &Todo,
+        now: i64,
+    ) -> Result<(), TodoError> {
+        let remembered = Remembered {
+            created_at: now,
+            request: request.clone(),
+            todo: todo.clone(),
+        };
+        tx.insert(
+            &self.tree(IDEMPOTENCY),
+            key.as_bytes(),
+            encode(&remembered)?,
+        )?;
+        Ok(())
+    }
+
+    /// Forgets every create of this namespace past the idempotency window, in one transaction.
+    /// Records which don't decode are left for fsck. Returns how many were forgotten.
+    pub fn purge_idempotency_keys(&self) -> Result<u64, TodoError> {
+        let tree = self.tree(IDEMPOTENCY);
+        let now = now();
+        let is_forgotten = |value: &[u8]| {
+            serde_cbor::from_slice::<Remembered>(value)
+                .is_ok_and(|remembered| self.is_forgotten(&remembered, now))
+        };
+        let mut forgotten = vec![];
+        for item in self.store.iter(&tree) {
+            let (key, value) = item?;
+            if is_forgotten(&value) {
+                forgotten.push(key);
+            }
+        }
+        if forgotten.is_empty() {
+            return Ok(0);
+        }
+        self.transaction(|tx| {
+            let mut purged = 0;
+            for key in &forgotten {
+                // Used again meanwhile.
+                if !tx
+                    .get(&tree, key)?
+                    .is_some_and(|value| is_forgotten(&value))
+                {
+                    continue;
+                }
+                tx.remove(&tree, key)?;
+                purged += 1;
+            }
+            Ok(purged)
+        })
+    }
+
+    /// purge_idempotency_keys for the default namespace and for every tenant.
+    pub fn purge_idempotency_keys_all(&self) -> Result<u64, TodoError> {
+        let mut purged = 0;
+        for tenant in self.namespaces()? {
+            purged += self.namespace(tenant).purge_idempotency_keys()?;
+        }
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Create, Fetch, List, ListOptions};
+    use std::time::Duration;
+
+    #[test]
+    fn retried_creates_are_replayed() {
+        let repo = TodoRepository::in_memory();
+        let milk = repo
+            .create_idempotent(Todo::new("milk"), Some("req-1"))
+            .unwrap();
+        let mut retry = Todo::new("milk");
+        retry.timestamp = milk.timestamp;
+        let replayed = repo.create_idempotent(retry, Some("req-1")).unwrap();
+        assert_eq!(replayed._key, milk._key);
+        assert_eq!(repo.list(&ListOptions::default()).unwrap().total, 1);
+
+        let eggs = Todo::new("eggs");
+        assert_eq!(
+            repo.create_idempotent(eggs.clone(), Some("req-1"))
+                .unwrap_err()
+                .status(),
+            409
+        );
+        assert_eq!(
+            repo.create_idempotent(eggs.clone(), Some(""))
+                .unwrap_err()
+                .status(),
+            400
+        );
+        // Keys are per namespace, and plain creates are never remembered.
+        let acme = repo.for_tenant("acme").unwrap();
+        acme.create_idempotent(eggs.clone(), Some("req-1")).unwrap();
+        repo.create(eggs).unwrap();
+        assert_eq!(repo.list(&ListOptions::default()).unwrap().total, 2);
+        assert_eq!(repo.fetch(&milk._key).unwrap().title, "milk");
+    }
+
+    #[test]
+    fn old_creates_are_forgotten() {
+        let repo = TodoRepository::in_memory().with_idempotency_window(Duration::from_secs(60));
+        let old = Remembered {
+            created_at: now() - 61_000,
+            request: Todo::new("milk"),
+            todo: Todo::new("milk"),
+        };
+        repo.store
+            .insert(&repo.tree(IDEMPOTENCY), b"old", encode(&old).unwrap())
+            .unwrap();
+        repo.create_idempotent(Todo::new("eggs"), Some("new"))
+            .unwrap();
+
+        // A key past the window may be used again.
+        assert_eq!(
+            repo.create_idempotent(Todo::new("bread"), Some("old"))
+                .unwrap()
+                .title,
+            "bread"
+        );
+        repo.store
+            .insert(&repo.tree(IDEMPOTENCY), b"old", encode(&old).unwrap())
+            .unwrap();
+        repo.store
+            .insert(&repo.tree(IDEMPOTENCY), b"rotten", b"\xff".to_vec())
+            .unwrap();
+        assert_eq!(repo.purge_idempotency_keys_all().unwrap(), 1);
+        // Records which don't decode are left for fsck.
+        assert_eq!(repo.store.count(&repo.tree(IDEMPOTENCY)).unwrap(), 2);
+    }
+}
@@ -0,0 +1,151 @@
+//! Rewrites every record holding a todo in the current schema version, so upgrades no longer
+//! run when it is read.
+use super::history::HISTORY;
+use super::idempotency::{rewrite_remembered, IDEMPOTENCY};
+use super::trash::{decode_trashed, encode_trashed, TRASH};
+use super::{TodoRepository, TODOS};
+use crate::schema;
+use crate::TodoError;
+
+/// What migrate did to a namespace.
+#[derive(Debug, Default)]
+pub struct Migration {
+    /// Records rewritten in the current schema version.
+    pub migrated: u64,
+    /// Records left as they were, because they don't decode or upgrade.
+    pub failed: Vec<Unmigrated>,
+}
+
+/// A record migrate couldn't rewrite.
+#[derive(Debug)]
+pub struct Unmigrated {
+    pub tree: String,
+    pub key: String,
+    pub error: TodoError,
+}
+
+/// The record as the current schema version stores it, or why it can't be.
+type Rewrite = fn(&[u8]) -> Result<Vec<u8>, TodoError>;
+
+fn rewrite_todo(record: &[u8]) -> Result<Vec<u8>, TodoError> {
+    schema::encode(&schema::decode(record)?)
+}
+
+fn rewrite_trashed(record: &[u8]) -> Result<Vec<u8>, TodoError> {
+    encode_trashed(decode_trashed(record)?)
+}
+
+impl TodoRepository {
+    /// Rewrites the records of this namespace which aren't stored the way the current schema
+    /// version stores them: its todos, their history, trash and remembered creates.
+    /// Writes made meanwhile may be undone, best run while no server is using the store.
+    pub fn migrate(&self) -> Result<Migration, TodoError> {
+        let mut migration = Migration::default();
+        let rewrites: [(&str, Rewrite); 4] = [
+            (TODOS, rewrite_todo),
+            (HISTORY, rewrite_todo),
+            (TRASH, rewrite_trashed),
+            (IDEMPOTENCY, rewrite_remembered),
+        ];
+        for (entity, rewrite) in rewrites.iter() {
+            let tree = self.tree(entity);
+            let entries = self.store.iter(&tree).collect::<Result<Vec<_>, _>>()?;
+            for (key, record) in entries {
+                match rewrite(&record) {
+                    Ok(rewritten) if rewritten == record => {}
+                    Ok(rewritten) => {
+                        self.store.insert(&tree, &key, rewritten)?;
+                        migration.migrated += 1;
+                    }
+                    Err(error) => migration.failed.push(Unmigrated {
+                        tree: tree.clone(),
+                        key: String::from_utf8_lossy(&key).into_owned(),
+                        error,
+                    }),
+                }
+            }
+        }
+        self.store.flush()?;
+        Ok(migration)
+    }
+
+    /// migrate for the default namespace and for every tenant.
+    pub fn migrate_all(&self) -> Result<Vec<(Option<String>, Migration)>, TodoError> {
+        let mut migrated = vec![];
+        for tenant in self.namespaces()? {
+            let migration = self.namespace(tenant.clone()).migrate()?;
+            migrated.push((tenant, migration));
+        }
+        Ok(migrated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::every_backend;
+    use crate::{Delete, Fetch, List, ListOptions, Priority, Replace, Todo};
+    use serde_json::json;
+
+    #[test]
+    fn migrate_through_every_backend() {
+        for repo in every_backend() {
+            old_records(repo);
+        }
+    }
+
+    fn old_records(repo: TodoRepository) {
+        let acme = repo.for_tenant("acme").unwrap();
+        let bare = |title: &str| {
+            let doc =
+                json!({"_key": title, "_rev": 1, "title": title, "timestamp": 0, "status": "New"});
+            serde_cbor::to_vec(&doc).unwrap()
+        };
+        for namespace in &[&repo, &acme] {
+            let todos = namespace.tree(TODOS);
+            namespace
+                .store
+                .insert(&todos, b"milk", bare("milk"))
+                .unwrap();
+            namespace
+                .store
+                .insert(&todos, b"eggs", bare("eggs"))
+                .unwrap();
+            namespace
+                .store
+                .insert(&todos, b"rotten", b"\xff".to_vec())
+                .unwrap();
+        }
+        acme.delete("eggs").unwrap();
+        acme.replace(Todo {
+            _key: "bread".to_owned(),
+            ..Todo::new("bread")
+        })
+        .unwrap();
+
+        // Old records read upgraded before and after they are migrated.
+        assert_eq!(repo.fetch("milk").unwrap().priority(), Priority::Normal);
+        let migrated = repo.migrate_all().unwrap();
+        let counts: Vec<_> = migrated
+            .iter()
+            .map(|(tenant, migration)| (tenant.as_deref(), migration.migrated))
+            .collect();
+        // Deleting acme's eggs kept it in its history as it was stored.
+        assert_eq!(counts, vec![(None, 2), (Some("acme"), 2)]);
+        for (_, migration) in &migrated {
+            let failed = &migration.failed;
+            assert_eq!((failed.len(), failed[0].key.as_str()), (1, "rotten"));
+            assert_eq!(failed[0].error.code(), "decode");
+        }
+
+        assert_eq!(repo.migrate().unwrap().migrated, 0);
+        let milk = repo.store.get(TODOS, b"milk").unwrap().unwrap();
+        assert_eq!(
+            serde_cbor::from_slice::<serde_json::Value>(&milk).unwrap()["schema"],
+            schema::VERSION
+        );
+        assert_eq!(repo.fetch("milk").unwrap().title(), "milk");
+        assert_eq!(acme.list(&ListOptions::default()).unwrap().items.len(), 2);
+        assert_eq!(acme.restore("eggs").unwrap().rev(), 2);
+    }
+}
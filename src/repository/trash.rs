@@ -0,0 +1,289 @@
+//! Deleted todos wait in a trash tree of their namespace until they are restored, purged by
+//! hand, or outlive the retention window.
+use super::{decode_cursor, encode, encode_cursor, now, take_page, TodoRepository, TODOS};
+use crate::store::{Entries, StoreError, Transaction};
+use crate::{schema, ListOptions, Page, Todo, TodoError, Trashed, TRASH_PATH};
+use serde::{Deserialize, Serialize};
+
+/// Entity name of the trash tree, keyed by the key a todo was deleted from.
+/// Deleting a key again replaces the version trashed before.
+pub(super) const TRASH: &str = "todos.trash";
+
+/// Trashed as stored, with its todo versioned.
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    deleted_at: i64,
+    #[serde(with = "crate::schema")]
+    todo: Todo,
+}
+
+pub(super) fn encode_trashed(trashed: Trashed) -> Result<Vec<u8>, TodoError> {
+    encode(&Stored {
+        deleted_at: trashed.deleted_at,
+        todo: trashed.todo,
+    })
+}
+
+pub(super) fn decode_trashed(record: &[u8]) -> Result<Trashed, TodoError> {
+    let stored: Stored = serde_cbor::from_slice(record)?;
+    Ok(Trashed {
+        deleted_at: stored.deleted_at,
+        todo: stored.todo,
+    })
+}
+
+fn not_in_trash(key: &str) -> TodoError {
+    TodoError::NotFound(format!("No todo with _key {:?} in the trash.", key))
+}
+
+impl TodoRepository {
+    /// Puts doc, just deleted from key, into the trash as part of tx.
+    pub(super) fn trash(
+        &self,
+        tx: &dyn Transaction,
+        key: &[u8],
+        doc: &Todo,
+    ) -> Result<(), TodoError> {
+        let trashed = Trashed {
+            deleted_at: now(),
+            todo: doc.clone(),
+        };
+        tx.insert(&self.tree(TRASH), key, encode_trashed(trashed)?)?;
+        Ok(())
+    }
+
+    /// Whether trashed is past the retention window at now and as good as purged.
+    fn is_expired(&self, trashed: &Trashed, now: i64) -> bool {
+        self.trash_retention.is_some_and(|retention| {
+            now.saturating_sub(trashed.deleted_at) >= retention.as_millis() as i64
+        })
+    }
+
+    /// The trashed todos of entries which are still within the retention window and pass the
+    /// filters of options, skipping entries which don't decode.
+    fn visible<'a>(
+        &'a self,
+        entries: Entries<'a>,
+        options: &'a ListOptions,
+        now: i64,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Trashed), StoreError>> + 'a {
+        entries.filter_map(move |item| match item {
+            Ok((key, value)) => decode_trashed(&value)
+                .ok()
+                .filter(|trashed| !self.is_expired(trashed, now))
+                .filter(|trashed| options.matches(&trashed.todo))
+                .map(|trashed| Ok((key, trashed))),
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Lists the trash in key order, paged and filtered like the todo list.
+    pub fn list_trash(&self, options: &ListOptions) -> Result<Page<Trashed>, TodoError> {
+        if options.sort.is_some() {
+            return Err(TodoError::BadRequest(
+                "The trash is listed in key order and can't be sorted.".to_owned(),
+            ));
+        }
+        let tree = self.tree(TRASH);
+        let now = now();
+        let collection = match &options.after {
+            Some(cursor) => self.store.range_after(&tree, &decode_cursor(cursor)?),
+            None => self.store.iter(&tree),
+        };
+        let (items, last_key) = take_page(self.visible(collection, options, now), options)?;
+        let next_cursor = last_key.map(|key| encode_cursor(&key));
+        let mut total = 0;
+        for item in self.visible(self.store.iter(&tree), options, now) {
+            item?;
+            total += 1;
+        }
+        Ok(Page::linked(TRASH_PATH, items, total, next_cursor, options))
+    }
+
+    /// Moves the todo deleted from key back to key, at its next revision.
+    /// Its history carries on where it left off.
+    /// Fails with a conflict when key has been taken again meanwhile.
+    pub fn restore(&self, key: &str) -> Result<Todo, TodoError> {
+        let todos = self.tree(TODOS);
+        let trash = self.tree(TRASH);
+        let now = now();
+        self.transaction(|tx| {
+            let encoded_trashed = tx
+                .get(&trash, key.as_bytes())?
+                .ok_or_else(|| not_in_trash(key))?;
+            let trashed = decode_trashed(&encoded_trashed)?;
+            if self.is_expired(&trashed, now) {
+                return Err(not_in_trash(key));
+            }
+            if tx.get(&todos, key.as_bytes())?.is_some() {
+                return Err(TodoError::Conflict(format!(
+                    "_key {:?} has been taken again, the trashed todo can't go back.",
+                    key
+                )));
+            }
+            let restored = Todo {
+                _rev: trashed.todo._rev + 1,
+                ..trashed.todo
+            };
+            tx.remove(&trash, key.as_bytes())?;
+            tx.insert(&todos, key.as_bytes(), schema::encode(&restored)?)?;
+            self.reindex_doc(tx, key.as_bytes(), None, Some(&restored))?;
+            Ok(restored)
+        })
+    }
+
+    /// Removes the todo deleted from key from the trash for good, along with its history.
+    /// A trashed todo which doesn't decode stays for fsck.
+    pub fn purge(&self, key: &str) -> Result<Trashed, TodoError> {
+        let history_keys = self.history_keys(key.as_bytes())?;
+        let tree = self.tree(TRASH);
+        self.transaction(|tx| {
+            let encoded_trashed = tx
+                .remove(&tree, key.as_bytes())?
+                .ok_or_else(|| not_in_trash(key))?;
+            let trashed = decode_trashed(&encoded_trashed)?;
+            self.purge_history(tx, key.as_bytes(), &history_keys)?;
+            Ok(trashed)
+        })
+    }
+
+    /// Purges every trashed todo of this namespace past the retention window along with its
+    /// history, in one transaction. Trash which doesn't decode is left for fsck.
+    /// Returns how many were purged.
+    pub fn purge_expired(&self) -> Result<u64, TodoError> {
+        let tree = self.tree(TRASH);
+        let now = now();
+        let is_expired = |value: &[u8]| {
+            decode_trashed(value).is_ok_and(|trashed| self.is_expired(&trashed, now))
+        };
+        let mut expired = vec![];
+        for item in self.store.iter(&tree) {
+            let (key, value) = item?;
+            if is_expired(&value) {
+                expired.push((self.history_keys(&key)?, key));
+            }
+        }
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        self.transaction(|tx| {
+            let mut purged = 0;
+            for (history_keys, key) in &expired {
+                // Restored or deleted again meanwhile.
+                if !tx.get(&tree, key)?.is_some_and(|value| is_expired(&value)) {
+                    continue;
+                }
+                tx.remove(&tree, key)?;
+                self.purge_history(tx, key, history_keys)?;
+                purged += 1;
+            }
+            Ok(purged)
+        })
+    }
+
+    /// purge_expired for the default namespace and for every tenant.
+    pub fn purge_expired_all(&self) -> Result<u64, TodoError> {
+        let mut purged = 0;
+        for tenant in self.namespaces()? {
+            purged += self.namespace(tenant).purge_expired()?;
+        }
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Delete, Fetch, List, Replace};
+    use std::time::Duration;
+
+    fn keyed(key: &str, title: &str) -> Todo {
+        Todo {
+            _key: key.to_owned(),
+            ..Todo::new(title)
+        }
+    }
+
+    #[test]
+    fn deleted_todos_can_come_back() {
+        let repo = TodoRepository::in_memory();
+        repo.replace(keyed("a", "milk")).unwrap();
+        repo.replace(keyed("b", "eggs")).unwrap();
+        repo.delete("a").unwrap();
+        repo.delete("b").unwrap();
+        assert_eq!(repo.fetch("a").unwrap_err().status(), 404);
+
+        let trash = repo
+            .list_trash(&ListOptions::from_query("limit=1").unwrap())
+            .unwrap();
+        assert_eq!(trash.total, 2);
+        assert_eq!(trash.items[0].todo.title, "milk");
+        assert!(trash.links.next.unwrap().starts_with("/v1/trash?"));
+        let filtered = ListOptions::from_query("title=EGG").unwrap();
+        assert_eq!(repo.list_trash(&filtered).unwrap().total, 1);
+        assert_eq!(
+            repo.list_trash(&ListOptions::from_query("sort=title").unwrap())
+                .unwrap_err()
+                .status(),
+            400
+        );
+
+        let restored = repo.restore("a").unwrap();
+        assert_eq!((restored.title.as_str(), restored.rev()), ("milk", 2));
+        assert_eq!(repo.fetch("a").unwrap().title, "milk");
+        assert_eq!(repo.restore("a").unwrap_err().status(), 404);
+        let by_status = ListOptions::from_query("status=new").unwrap();
+        assert_eq!(repo.list(&by_status).unwrap().total, 1);
+
+        // A key taken again keeps its new todo.
+        repo.replace(keyed("b", "bacon")).unwrap();
+        assert_eq!(repo.restore("b").unwrap_err().status(), 409);
+        assert_eq!(repo.purge("b").unwrap().todo.title, "eggs");
+        assert_eq!(repo.purge("b").unwrap_err().status(), 404);
+        assert_eq!(repo.list_trash(&ListOptions::default()).unwrap().total, 0);
+    }
+
+    #[test]
+    fn old_trash_is_purged() {
+        let repo = TodoRepository::in_memory().with_trash_retention(Some(Duration::from_secs(60)));
+        let acme = repo.for_tenant("acme").unwrap();
+        let old = Trashed {
+            deleted_at: now() - 61_000,
+            todo: keyed("old", "old"),
+        };
+        acme.store
+            .insert(&acme.tree(TRASH), b"old", encode(&old).unwrap())
+            .unwrap();
+        acme.replace(keyed("new", "new")).unwrap();
+        acme.delete("new").unwrap();
+
+        // Expired trash is out of sight before it is purged.
+        assert_eq!(acme.list_trash(&ListOptions::default()).unwrap().total, 1);
+        assert_eq!(acme.restore("old").unwrap_err().status(), 404);
+        assert_eq!(repo.purge_expired_all().unwrap(), 1);
+        assert_eq!(acme.store.count(&acme.tree(TRASH)).unwrap(), 1);
+
+        let forever = acme.with_trash_retention(None);
+        forever
+            .store
+            .insert(&forever.tree(TRASH), b"old", encode(&old).unwrap())
+            .unwrap();
+        assert_eq!(forever.purge_expired().unwrap(), 0);
+        assert_eq!(forever.restore("old").unwrap().title, "old");
+    }
+
+    #[test]
+    fn undecodable_trash_is_left_for_fsck() {
+        let repo = TodoRepository::in_memory().with_trash_retention(Some(Duration::from_secs(0)));
+        repo.store
+            .insert(&repo.tree(TRASH), b"rotten", b"\xff".to_vec())
+            .unwrap();
+        assert_eq!(repo.purge_expired().unwrap(), 0);
+        assert_eq!(repo.purge("rotten").unwrap_err().status(), 500);
+        assert!(repo
+            .store
+            .get(&repo.tree(TRASH), b"rotten")
+            .unwrap()
+            .is_some());
+    }
+}
@@ -0,0 +1,139 @@
+//! Stored todos carry the version of the schema they were written with, and documents of an
+//! older version are upgraded when they are read, so changing `Todo` never strands old data.
+//!
+//! A record is the CBOR map `{"schema": <version>, "todo": <document>}`. Records written before
+//! versioning are the bare document and count as version 1.
+//!
+//! Changing the shape of `Todo` means bumping VERSION and appending an upgrade from the previous
+//! version to UPGRADES. `admin migrate` rewrites a store to the current version.
+use crate::{Todo, TodoError};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Map, Value};
+
+/// Schema version records are written with.
+pub(crate) const VERSION: u64 = 2;
+
+/// Turns a document of one version into one of the next, or says why it can't.
+type Upgrade = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// The upgrade from version `i + 1` is at index i.
+const UPGRADES: [Upgrade; VERSION as usize - 1] = [v1_fill_in_details];
+
+/// Version 2 gave todos a description, due date, priority, tags and metadata.
+fn v1_fill_in_details(doc: &mut Map<String, Value>) -> Result<(), String> {
+    for (field, default) in [
+        ("description", json!("")),
+        ("priority", json!("Normal")),
+        ("tags", json!([])),
+        ("metadata", json!({})),
+    ] {
+        doc.entry(field).or_insert(default);
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    schema: u64,
+    todo: &'a Todo,
+}
+
+/// The record todo is stored as.
+pub(crate) fn encode(todo: &Todo) -> Result<Vec<u8>, TodoError> {
+    serde_cbor::to_vec(&Record {
+        schema: VERSION,
+        todo,
+    })
+    .map_err(|e| TodoError::Internal(format!("Could not encode todo: {}", e)))
+}
+
+/// The todo stored as record, upgraded to the current version.
+pub(crate) fn decode(record: &[u8]) -> Result<Todo, TodoError> {
+    upgrade(serde_cbor::from_slice(record)?)
+}
+
+/// The version record was written with and its document.
+fn split(record: Value) -> Result<(u64, Map<String, Value>), TodoError> {
+    let mut fields = match record {
+        Value::Object(fields) => fields,
+        _ => return Err(TodoError::Decode("Stored todo isn't a map.".to_owned())),
+    };
+    let version = match fields.get("schema") {
+        None => return Ok((1, fields)),
+        Some(version) => version.as_u64().ok_or_else(|| {
+            TodoError::Decode(format!("Stored todo has schema version {}.", version))
+        })?,
+    };
+    match fields.remove("todo") {
+        Some(Value::Object(doc)) => Ok((version, doc)),
+        _ => Err(TodoError::Decode(
+            "Stored todo has a schema version but no document.".to_owned(),
+        )),
+    }
+}
+
+fn upgrade(record: Value) -> Result<Todo, TodoError> {
+    let (mut version, mut doc) = split(record)?;
+    if version == 0 || version > VERSION {
+        return Err(TodoError::Decode(format!(
+            "Stored todo has schema version {}, this build knows 1 to {}.",
+            version, VERSION
+        )));
+    }
+    while version < VERSION {
+        UPGRADES[version as usize - 1](&mut doc).map_err(|e| {
+            TodoError::Decode(format!(
+                "Stored todo doesn't upgrade from schema version {}: {}",
+                version, e
+            ))
+        })?;
+        version += 1;
+    }
+    serde_json::from_value(Value::Object(doc))
+        .map_err(|e| TodoError::Decode(format!("Stored document is unreadable: {}", e)))
+}
+
+/// Lets records which embed a todo, `#[serde(with = "crate::schema")]`, store it versioned.
+pub(crate) fn serialize<S: Serializer>(todo: &Todo, serializer: S) -> Result<S::Ok, S::Error> {
+    Record {
+        schema: VERSION,
+        todo,
+    }
+    .serialize(serializer)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Todo, D::Error> {
+    upgrade(Value::deserialize(deserializer)?).map_err(|e| de::Error::custom(e.message()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Priority;
+
+    #[test]
+    fn old_records_are_upgraded() {
+        let todo = Todo::new("milk").with_priority(Priority::High);
+        let decoded = decode(&encode(&todo).unwrap()).unwrap();
+        assert_eq!(
+            (decoded.title(), decoded.priority()),
+            ("milk", Priority::High)
+        );
+
+        let bare = json!({"_key": "a", "_rev": 3, "title": "x", "timestamp": 0, "status": "New"});
+        let upgraded = decode(&serde_cbor::to_vec(&bare).unwrap()).unwrap();
+        assert_eq!((upgraded.key(), upgraded.rev()), ("a", 3));
+        assert_eq!(upgraded.priority(), Priority::Normal);
+
+        for broken in [
+            json!({"schema": VERSION + 1, "todo": bare}),
+            json!({"schema": 0, "todo": bare}),
+            json!({"schema": 1}),
+            json!({"schema": 1, "todo": {"title": 5}}),
+            json!(["not", "a", "todo"]),
+        ] {
+            let record = serde_cbor::to_vec(&broken).unwrap();
+            assert_eq!(decode(&record).unwrap_err().code(), "decode", "{}", broken);
+        }
+    }
+}
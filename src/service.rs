@@ -0,0 +1,613 @@
+//! What every server does with a request once its framework has routed it: reading the headers,
+//! query and body, calling the repository and rendering the outcome. The binaries only adapt
+//! their framework's requests and responses to these, so they all answer alike.
+use crate::openapi;
+use crate::repository::{flag, key_unchangeable};
+use crate::{
+    BulkMode, BulkOp, Edit, Fetch, Format, IfMatch, List, ListOptions, Todo, TodoError,
+    TodoRepository, ETAG_HEADER, IDEMPOTENCY_KEY_HEADER, IF_MATCH_HEADER, PROBLEM_JSON_TYPE,
+    TENANT_HEADER,
+};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Environment variable consulted when no `--listen` flag is given.
+pub const LISTEN_ENV_VAR: &str = "TODO_LISTEN";
+
+/// Largest request body read, servers stop reading after one more byte.
+pub const MAX_BODY_LEN: usize = 1024 * 1024;
+
+/// Header naming the format of the body, of requests as well as responses.
+pub const CONTENT_TYPE_HEADER: &str = "Content-Type";
+
+/// Request header naming the formats the response body may be in.
+pub const ACCEPT_HEADER: &str = "Accept";
+
+/// Response header naming the request headers the response depends on, for caches.
+pub const VARY_HEADER: &str = "Vary";
+
+/// Response header marking a route as deprecated, see RFC 9745.
+pub const DEPRECATION_HEADER: &str = "Deprecation";
+
+/// Response header pointing from a deprecated route to the route replacing it.
+pub const LINK_HEADER: &str = "Link";
+
+/// When the routes outside of `/v1` were deprecated, in seconds since the epoch (2026-10-18).
+pub const LEGACY_DEPRECATED_AT: i64 = 1_792_281_600;
+
+/// Prefix of the canonical routes.
+pub const API_PREFIX: &str = "/v1";
+
+/// Path every server serves the OpenAPI document of ROUTES under.
+pub const OPENAPI_PATH: &str = "/openapi.json";
+
+/// Path every server serves the API explorer under, a page trying out ROUTES.
+pub const EXPLORER_PATH: &str = "/explorer";
+
+/// The address a server listens on, from a `--listen <addr>` flag (or `--listen=<addr>`), the
+/// TODO_LISTEN environment variable, or default.
+pub fn listen_address(default: &str) -> Result<SocketAddr, String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let addr = flag(&args, "--listen")
+        .or_else(|| std::env::var(LISTEN_ENV_VAR).ok())
+        .unwrap_or_else(|| default.to_owned());
+    addr.parse()
+        .map_err(|_| format!("Listen address {:?} is not an ip:port.", addr))
+}
+
+/// The endpoints of the todo api, with the segments their routes capture.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    List,
+    Fetch(String),
+    Create,
+    /// An edit naming its todo by the `_key` in the body, or by the key in the route.
+    Edit(Option<String>),
+    /// A replace naming its todo by the `_key` in the body, or by the key in the route.
+    Replace(Option<String>),
+    Delete(String),
+    /// A todo's key and the action to take on it.
+    Act(String, String),
+    Bulk,
+    Trash,
+    Restore(String),
+    Purge(String),
+    History(String),
+    /// A todo's key and revision.
+    Revision(String, String),
+    /// A todo's key and the revisions to compare.
+    Diff(String, String, String),
+    /// A todo's key and the revision to go back to.
+    Rollback(String, String),
+    /// The OpenAPI document.
+    OpenApi,
+    /// The API explorer page.
+    Explorer,
+}
+impl Endpoint {
+    /// The canonical route of the endpoint, None for endpoints which have none since they take
+    /// the todo's key from the body.
+    pub fn path(&self) -> Option<String> {
+        let todo = |key: &str| format!("{}/todos/{}", API_PREFIX, key);
+        let history = |key: &str| format!("{}/history", todo(key));
+        let trashed = |key: &str| format!("{}/trash/{}", API_PREFIX, key);
+        Some(match self {
+            Endpoint::List | Endpoint::Create => format!("{}/todos", API_PREFIX),
+            Endpoint::Edit(None) | Endpoint::Replace(None) => return None,
+            Endpoint::Fetch(key)
+            | Endpoint::Edit(Some(key))
+            | Endpoint::Replace(Some(key))
+            | Endpoint::Delete(key) => todo(key),
+            Endpoint::Act(key, action) => format!("{}/{}", todo(key), action),
+            Endpoint::Bulk => format!("{}/todos/bulk", API_PREFIX),
+            Endpoint::Trash => format!("{}/trash", API_PREFIX),
+            Endpoint::Restore(key) => format!("{}/restore", trashed(key)),
+            Endpoint::Purge(key) => trashed(key),
+            Endpoint::History(key) => history(key),
+            Endpoint::Revision(key, rev) => format!("{}/{}", history(key), rev),
+            Endpoint::Diff(key, from, to) => format!("{}/diff/{}/{}", history(key), from, to),
+            Endpoint::Rollback(key, rev) => format!("{}/{}/rollback", history(key), rev),
+            Endpoint::OpenApi => OPENAPI_PATH.to_owned(),
+            Endpoint::Explorer => EXPLORER_PATH.to_owned(),
+        })
+    }
+}
+
+/// The endpoint a route serves, made of the segments the route captured, looked up by name.
+pub type Route = fn(&dyn Fn(&str) -> String) -> Endpoint;
+
+/// A canonical route of the api.
+pub struct ApiRoute {
+    pub method: &'static str,
+    /// Path template, with the segments it captures in braces like `/v1/todos/{key}`.
+    pub path: &'static str,
+    /// Unique name of what the route does.
+    pub operation: &'static str,
+    pub summary: &'static str,
+    pub endpoint: Route,
+}
+
+/// Every canonical route, as every server serves them and the OpenAPI document describes them.
+pub const ROUTES: [ApiRoute; 15] = [
+    ApiRoute {
+        method: "GET",
+        path: "/v1/todos",
+        operation: "list",
+        summary: "Lists a page of todos.",
+        endpoint: |_| Endpoint::List,
+    },
+    ApiRoute {
+        method: "POST",
+        path: "/v1/todos",
+        operation: "create",
+        summary: "Creates a todo.",
+        endpoint: |_| Endpoint::Create,
+    },
+    ApiRoute {
+        method: "POST",
+        path: "/v1/todos/bulk",
+        operation: "bulk",
+        summary: "Applies up to 1000 writes in one transaction.",
+        endpoint: |_| Endpoint::Bulk,
+    },
+    ApiRoute {
+        method: "GET",
+        path: "/v1/todos/{key}",
+        operation: "fetch",
+        summary: "Fetches a todo.",
+        endpoint: |segment| Endpoint::Fetch(segment("key")),
+    },
+    ApiRoute {
+        method: "PATCH",
+        path: "/v1/todos/{key}",
+        operation: "edit",
+        summary: "Edits a todo with a merge patch or a JSON Patch.",
+        endpoint: |segment| Endpoint::Edit(Some(segment("key"))),
+    },
+    ApiRoute {
+        method: "PUT",
+        path: "/v1/todos/{key}",
+        operation: "replace",
+        summary: "Replaces a todo, creating it when there is none.",
+        endpoint: |segment| Endpoint::Replace(Some(segment("key"))),
+    },
+    ApiRoute {
+        method: "DELETE",
+        path: "/v1/todos/{key}",
+        operation: "delete",
+        summary: "Moves a todo to the trash.",
+        endpoint: |segment| Endpoint::Delete(segment("key")),
+    },
+    ApiRoute {
+        method: "POST",
+        path: "/v1/todos/{key}/{action}",
+        operation: "act",
+        summary: "Moves a todo along its workflow.",
+        endpoint: |segment| Endpoint::Act(segment("key"), segment("action")),
+    },
+    ApiRoute {
+        method: "GET",
+        path: "/v1/todos/{key}/history",
+        operation: "history",
+        summary: "Lists every revision of a todo, oldest first.",
+        endpoint: |segment| Endpoint::History(segment("key")),
+    },
+    ApiRoute {
+        method: "GET",
+        path: "/v1/todos/{key}/history/{rev}",
+        operation: "revision",
+        summary: "Fetches a revision of a todo.",
+        endpoint: |segment| Endpoint::Revision(segment("key"), segment("rev")),
+    },
+    ApiRoute {
+        method: "GET",
+        path: "/v1/todos/{key}/history/diff/{from}/{to}",
+        operation: "diff",
+        summary: "Answers the JSON Patch between two revisions of a todo.",
+        endpoint: |segment| Endpoint::Diff(segment("key"), segment("from"), segment("to")),
+    },
+    ApiRoute {
+        method: "POST",
+        path: "/v1/todos/{key}/history/{rev}/rollback",
+        operation: "rollback",
+        summary: "Makes a revision of a todo current again.",
+        endpoint: |segment| Endpoint::Rollback(segment("key"), segment("rev")),
+    },
+    ApiRoute {
+        method: "GET",
+        path: "/v1/trash",
+        operation: "trash",
+        summary: "Lists a page of deleted todos.",
+        endpoint: |_| Endpoint::Trash,
+    },
+    ApiRoute {
+        method: "POST",
+        path: "/v1/trash/{key}/restore",
+        operation: "restore",
+        summary: "Puts a deleted todo back.",
+        endpoint: |segment| Endpoint::Restore(segment("key")),
+    },
+    ApiRoute {
+        method: "DELETE",
+        path: "/v1/trash/{key}",
+        operation: "purge",
+        summary: "Purges a deleted todo for good.",
+        endpoint: |segment| Endpoint::Purge(segment("key")),
+    },
+];
+
+/// Everything about a request the endpoints read, besides the segments of its route.
+#[derive(Debug, Default, Clone)]
+pub struct Request {
+    pub tenant: Option<String>,
+    pub if_match: Option<String>,
+    pub idempotency_key: Option<String>,
+    /// The format of the body.
+    pub content_type: Option<String>,
+    /// The formats the response may be in.
+    pub accept: Option<String>,
+    /// The path of the request, errors name it as their instance.
+    pub path: String,
+    /// The raw query string, without `?`.
+    pub query: String,
+    pub body: Vec<u8>,
+}
+impl Request {
+    /// Takes the headers the endpoints read from header, which looks one up by name.
+    pub fn from_headers(
+        header: impl Fn(&str) -> Option<String>,
+        path: String,
+        query: String,
+        body: Vec<u8>,
+    ) -> Self {
+        Request {
+            tenant: header(TENANT_HEADER),
+            if_match: header(IF_MATCH_HEADER),
+            idempotency_key: header(IDEMPOTENCY_KEY_HEADER),
+            content_type: header(CONTENT_TYPE_HEADER),
+            accept: header(ACCEPT_HEADER),
+            path,
+            query,
+            body,
+        }
+    }
+}
+
+/// What a server answers, for its framework to send as it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+impl Response {
+    /// e as problem details, with the status it maps to. Also how servers answer errors their
+    /// framework raises.
+    pub fn error(e: &TodoError) -> Self {
+        Self::problem(e, None)
+    }
+
+    /// error, for a request to the path instance. Only errors of the server are logged as such,
+    /// those of the client are debug output.
+    pub fn problem(e: &TodoError, instance: Option<&str>) -> Self {
+        let status = e.status();
+        if status >= 500 {
+            log::error!("{}", e);
+        } else {
+            log::debug!("{}", e);
+        }
+        Response {
+            status,
+            headers: vec![(CONTENT_TYPE_HEADER, PROBLEM_JSON_TYPE.to_owned())],
+            body: serde_json::to_vec(&e.problem(instance)).unwrap_or_default(),
+        }
+    }
+
+    /// The answer to a request no endpoint's route matches.
+    pub fn no_route() -> Self {
+        Self::error(&TodoError::NotFound("No such route.".to_owned()))
+    }
+
+    /// The answer to a request whose body is larger than MAX_BODY_LEN.
+    pub fn body_too_large() -> Self {
+        Self::error(&too_large())
+    }
+
+    /// The response, answering a legacy route of endpoint: marked deprecated and linking to the
+    /// canonical route, if there is one.
+    pub fn deprecated(mut self, endpoint: &Endpoint) -> Self {
+        self.headers
+            .push((DEPRECATION_HEADER, format!("@{}", LEGACY_DEPRECATED_AT)));
+        if let Some(path) = endpoint.path() {
+            self.headers.push((
+                LINK_HEADER,
+                format!("<{}>; rel=\"successor-version\"", path),
+            ));
+        }
+        self
+    }
+
+    /// The value of the header called name, if there is one.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn too_large() -> TodoError {
+    TodoError::PayloadTooLarge(format!(
+        "Request body is larger than {} bytes.",
+        MAX_BODY_LEN
+    ))
+}
+
+/// The body of a successful response, and the revision of the todo it is, if it is one.
+struct Reply {
+    /// 200, unless the body reports a failure like a rolled back bulk request.
+    status: u16,
+    body: Body,
+    etag: Option<String>,
+}
+impl Reply {
+    /// The response to send for the reply, its data in format.
+    fn response(self, format: Format) -> Result<Response, TodoError> {
+        let mut headers = vec![];
+        let body = match self.body {
+            Body::Data(value) => {
+                headers.push((CONTENT_TYPE_HEADER, format.media_type().to_owned()));
+                headers.push((VARY_HEADER, ACCEPT_HEADER.to_owned()));
+                format.encode(&value)?
+            }
+            Body::Page(page) => {
+                headers.push((CONTENT_TYPE_HEADER, "text/html; charset=utf-8".to_owned()));
+                page.as_bytes().to_vec()
+            }
+        };
+        headers.extend(self.etag.map(|etag| (ETAG_HEADER, etag)));
+        Ok(Response {
+            status: self.status,
+            headers,
+            body,
+        })
+    }
+}
+
+/// What a successful response carries.
+enum Body {
+    /// Data, sent in the format the request accepts.
+    Data(serde_json::Value),
+    /// An HTML page, sent whatever the request accepts.
+    Page(&'static str),
+}
+
+fn reply<T: Serialize>(value: T) -> Result<Reply, TodoError> {
+    let value = serde_json::to_value(value).map_err(|e| TodoError::Internal(e.to_string()))?;
+    Ok(Reply {
+        status: 200,
+        body: Body::Data(value),
+        etag: None,
+    })
+}
+
+fn reply_todo(todo: Todo) -> Result<Reply, TodoError> {
+    let etag = todo.etag();
+    Ok(Reply {
+        etag: Some(etag),
+        ..reply(todo)?
+    })
+}
+
+/// The body of request, in the format its Content-Type names.
+fn body_value(request: &Request) -> Result<serde_json::Value, TodoError> {
+    Format::of_body(request.content_type.as_deref())?.decode(&request.body)
+}
+
+/// The body of request as a Todo, a body which doesn't describe one isn't valid.
+/// With a key from the route, the body's `_key` may be left out but not differ.
+fn todo_body(request: &Request, key: Option<&str>) -> Result<Todo, TodoError> {
+    let mut json = body_value(request)?;
+    if let (Some(key), Some(fields)) = (key, json.as_object_mut()) {
+        match fields.get("_key") {
+            None => {}
+            Some(body_key) if body_key == key => {}
+            Some(_) => return Err(key_unchangeable(key)),
+        }
+        fields.insert("_key".to_owned(), key.into());
+    }
+    Todo::from_json(json)
+}
+
+fn revision(segment: &str) -> Result<u64, TodoError> {
+    segment
+        .parse()
+        .map_err(|_| TodoError::BadRequest(format!("Revision {:?} is not a number.", segment)))
+}
+
+/// Serves the todo api from a shared repository.
+/// Build it once at startup and share it, cloning is cheap and all clones use the same store.
+#[derive(Clone)]
+pub struct TodoService {
+    repo: TodoRepository,
+}
+impl TodoService {
+    pub fn new(repo: TodoRepository) -> Self {
+        TodoService { repo }
+    }
+
+    /// Answers request to endpoint.
+    /// A panicking endpoint is answered with a logged 500 instead of a dropped connection.
+    pub fn handle(&self, endpoint: &Endpoint, request: &Request) -> Response {
+        // Refused before anything is written. The explorer is a page, whatever is accepted.
+        let format = match (Format::negotiate(request.accept.as_deref()), endpoint) {
+            (Err(_), Endpoint::Explorer) => Ok(Format::Json),
+            (format, _) => format,
+        };
+        let outcome = if request.body.len() > MAX_BODY_LEN {
+            Err(too_large())
+        } else {
+            format.and_then(|format| {
+                panic::catch_unwind(AssertUnwindSafe(|| self.call(endpoint, request)))
+                    .unwrap_or_else(|cause| Err(TodoError::panicked(&*cause)))?
+                    .response(format)
+            })
+        };
+        outcome.unwrap_or_else(|e| {
+            let instance = Some(request.path.as_str()).filter(|path| !path.is_empty());
+            Response::problem(&e, instance)
+        })
+    }
+
+    fn call(&self, endpoint: &Endpoint, request: &Request) -> Result<Reply, TodoError> {
+        // The documentation isn't kept in a namespace, so any tenant is fine for it.
+        let repo = || self.repo.scoped(request.tenant.as_deref());
+        let condition = request.if_match.as_deref().map(IfMatch::parse);
+        let condition = condition.as_ref();
+        match endpoint {
+            Endpoint::List => reply(repo()?.list(&ListOptions::from_query(&request.query)?)?),
+            Endpoint::Fetch(key) => reply_todo(repo()?.fetch(key)?),
+            Endpoint::Create => reply_todo(repo()?.create_idempotent(
+                todo_body(request, None)?,
+                request.idempotency_key.as_deref(),
+            )?),
+            Endpoint::Edit(key) => {
+                let edit = Edit::parse(request.content_type.as_deref(), &request.body)?;
+                reply_todo(repo()?.edit_if(key.as_deref(), edit, condition)?)
+            }
+            Endpoint::Replace(key) => {
+                reply_todo(repo()?.replace_if(todo_body(request, key.as_deref())?, condition)?)
+            }
+            Endpoint::Delete(key) => reply(repo()?.delete_if(key, condition)?),
+            Endpoint::Act(key, action) => {
+                reply_todo(repo()?.act(key, action.parse()?, condition)?)
+            }
+            Endpoint::Bulk => {
+                let mode = BulkMode::from_query(&request.query)?;
+                let report = repo()?.bulk(&BulkOp::from_body(body_value(request)?)?, mode)?;
+                Ok(Reply {
+                    status: report.status(),
+                    ..reply(report)?
+                })
+            }
+            Endpoint::Trash => {
+                reply(repo()?.list_trash(&ListOptions::from_query(&request.query)?)?)
+            }
+            Endpoint::Restore(key) => reply_todo(repo()?.restore(key)?),
+            Endpoint::Purge(key) => reply(repo()?.purge(key)?),
+            Endpoint::History(key) => reply(repo()?.history(key)?),
+            Endpoint::Revision(key, rev) => reply(repo()?.fetch_revision(key, revision(rev)?)?),
+            Endpoint::Diff(key, from, to) => {
+                reply(repo()?.diff(key, revision(from)?, revision(to)?)?)
+            }
+            Endpoint::Rollback(key, rev) => {
+                reply_todo(repo()?.rollback(key, revision(rev)?, condition)?)
+            }
+            Endpoint::OpenApi => reply(openapi::document()),
+            Endpoint::Explorer => Ok(Reply {
+                status: 200,
+                body: Body::Page(openapi::EXPLORER_PAGE),
+                etag: None,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn body(response: &Response) -> serde_json::Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[test]
+    fn endpoints_answer_alike() {
+        let service = TodoService::new(TodoRepository::in_memory());
+        let created = service.handle(
+            &Endpoint::Create,
+            &Request {
+                body: br#"{"_key": "", "title": "milk", "timestamp": 0, "status": "New"}"#.to_vec(),
+                ..Request::default()
+            },
+        );
+        assert_eq!(created.status, 200);
+        assert_eq!(created.header("etag"), Some("\"1\""));
+        assert_eq!(created.header("content-type"), Some("application/json"));
+        let key = body(&created)["_key"].as_str().unwrap().to_owned();
+
+        let renamed = Request {
+            body: br#"{"_key": "other", "title": "milk", "timestamp": 0, "status": "New"}"#
+                .to_vec(),
+            ..Request::default()
+        };
+        let replaced = service.handle(&Endpoint::Replace(Some(key.clone())), &renamed);
+        assert_eq!(replaced.status, 422);
+        assert_eq!(body(&replaced)["errors"][0]["field"], "_key");
+        let untitled = Request {
+            body: br#"{"_key": "", "title": 5, "status": "Done"}"#.to_vec(),
+            ..Request::default()
+        };
+        let fields: Vec<_> = body(&service.handle(&Endpoint::Create, &untitled))["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| error["field"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(fields, vec!["title", "timestamp", "status"]);
+
+        let stale = Request {
+            if_match: Some("\"7\"".to_owned()),
+            path: format!("/v1/todos/{}", key),
+            ..Request::default()
+        };
+        let deleted = service.handle(&Endpoint::Delete(key.clone()), &stale);
+        assert_eq!(deleted.status, 412);
+        assert_eq!(deleted.header("etag"), None);
+        assert_eq!(
+            deleted.header("content-type"),
+            Some("application/problem+json")
+        );
+        assert_eq!(body(&deleted)["code"], "precondition_failed");
+        assert_eq!(body(&deleted)["instance"], stale.path);
+
+        let revision = Endpoint::Revision(key.clone(), "first".to_owned());
+        assert_eq!(service.handle(&revision, &Request::default()).status, 400);
+        let malformed = Request {
+            body: b"{".to_vec(),
+            ..Request::default()
+        };
+        assert_eq!(service.handle(&Endpoint::Create, &malformed).status, 400);
+        let tenant = Request {
+            tenant: Some("Not A Slug".to_owned()),
+            ..Request::default()
+        };
+        assert_eq!(service.handle(&Endpoint::List, &tenant).status, 400);
+        let huge = Request {
+            body: vec![b' '; MAX_BODY_LEN + 1],
+            ..Request::default()
+        };
+        assert_eq!(
+            service.handle(&Endpoint::Bulk, &huge),
+            Response::body_too_large()
+        );
+        let legacy = Response::no_route().deprecated(&Endpoint::Revision(key, "2".to_owned()));
+        assert_eq!(legacy.header("deprecation"), Some("@1792281600"));
+        assert_eq!(
+            legacy.header("link"),
+            Some(r#"</v1/todos/00000000000000000000/history/2>; rel="successor-version""#)
+        );
+        assert_eq!(
+            body(&Response::no_route()),
+            json!({
+                "type": "urn:various-micro-services:problem:not_found",
+                "title": "Nothing was found.",
+                "status": 404,
+                "detail": "No such route.",
+                "code": "not_found"
+            })
+        );
+    }
+}
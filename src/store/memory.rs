@@ -0,0 +1,206 @@
+use super::{Entries, Entry, StoreError, TodoStore, Transaction, TxBody, TxError};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+type Tree = BTreeMap<Vec<u8>, Vec<u8>>;
+type Trees = HashMap<String, Tree>;
+/// Pending writes of a transaction by tree and key, None removes the key.
+type Writes = BTreeMap<(String, Vec<u8>), Option<Vec<u8>>>;
+
+/// Keeps every tree in a BTreeMap, nothing survives the process.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    trees: RwLock<Trees>,
+    ids: AtomicU64,
+}
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, Trees>, StoreError> {
+        self.trees
+            .read()
+            .map_err(|_| StoreError("In-memory store is poisoned.".to_owned()))
+    }
+
+    /// Copies the entries of tree within bounds, so no lock is held while the caller iterates.
+    fn snapshot(&self, tree: &str, bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Entries<'_> {
+        match self.read() {
+            Ok(trees) => {
+                let snapshot: Vec<Entry> = trees
+                    .get(tree)
+                    .map(|entries| {
+                        entries
+                            .range(bounds)
+                            .map(|(k, v)| (k.clone(), v.clone()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Box::new(snapshot.into_iter().map(Ok))
+            }
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, Trees>, StoreError> {
+        self.trees
+            .write()
+            .map_err(|_| StoreError("In-memory store is poisoned.".to_owned()))
+    }
+}
+
+impl TodoStore for MemoryStore {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .read()?
+            .get(tree)
+            .and_then(|entries| entries.get(key))
+            .cloned())
+    }
+
+    fn insert(
+        &self,
+        tree: &str,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .write()?
+            .entry(tree.to_owned())
+            .or_default()
+            .insert(key.to_vec(), value))
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .write()?
+            .get_mut(tree)
+            .and_then(|entries| entries.remove(key)))
+    }
+
+    fn iter(&self, tree: &str) -> Entries<'_> {
+        self.snapshot(tree, (Unbounded, Unbounded))
+    }
+
+    fn range_after(&self, tree: &str, after: &[u8]) -> Entries<'_> {
+        self.snapshot(tree, (Excluded(after.to_vec()), Unbounded))
+    }
+
+    fn range(&self, tree: &str, start: &[u8], end: &[u8]) -> Entries<'_> {
+        if start >= end {
+            return Box::new(std::iter::empty());
+        }
+        self.snapshot(tree, (Included(start.to_vec()), Excluded(end.to_vec())))
+    }
+
+    fn count(&self, tree: &str) -> Result<u64, StoreError> {
+        Ok(self
+            .read()?
+            .get(tree)
+            .map_or(0, |entries| entries.len() as u64))
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>, StoreError> {
+        Ok(self
+            .read()?
+            .iter()
+            .filter(|(_, entries)| !entries.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+
+    fn transaction(&self, trees: &[&str], body: &TxBody<'_>) -> Result<bool, StoreError> {
+        // Holding the write lock throughout makes transactions run one after the other.
+        let mut guard = self.write()?;
+        let tx = MemoryTransaction {
+            trees: &guard,
+            names: trees,
+            writes: RefCell::new(BTreeMap::new()),
+        };
+        match body(&tx) {
+            Ok(()) => {
+                let writes = tx.writes.into_inner();
+                for ((tree, key), value) in writes {
+                    let entries = guard.entry(tree).or_default();
+                    match value {
+                        Some(value) => entries.insert(key, value),
+                        None => entries.remove(&key),
+                    };
+                }
+                Ok(true)
+            }
+            Err(TxError::Abort) => Ok(false),
+            Err(TxError::Store(e)) => Err(e),
+        }
+    }
+
+    fn generate_id(&self) -> Result<u64, StoreError> {
+        Ok(self.ids.fetch_add(1, Ordering::SeqCst))
+    }
+
+    fn flush(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// Collects the writes of a transaction until it commits.
+struct MemoryTransaction<'a> {
+    trees: &'a Trees,
+    names: &'a [&'a str],
+    writes: RefCell<Writes>,
+}
+impl MemoryTransaction<'_> {
+    fn check(&self, tree: &str) -> Result<(), StoreError> {
+        if self.names.contains(&tree) {
+            Ok(())
+        } else {
+            Err(StoreError(format!(
+                "Tree {:?} is not part of the transaction.",
+                tree
+            )))
+        }
+    }
+
+    fn write(
+        &self,
+        tree: &str,
+        key: &[u8],
+        value: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        let previous = self.get(tree, key)?;
+        self.writes
+            .borrow_mut()
+            .insert((tree.to_owned(), key.to_vec()), value);
+        Ok(previous)
+    }
+}
+impl Transaction for MemoryTransaction<'_> {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        self.check(tree)?;
+        if let Some(written) = self.writes.borrow().get(&(tree.to_owned(), key.to_vec())) {
+            return Ok(written.clone());
+        }
+        Ok(self
+            .trees
+            .get(tree)
+            .and_then(|entries| entries.get(key))
+            .cloned())
+    }
+
+    fn insert(
+        &self,
+        tree: &str,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        self.write(tree, key, Some(value))
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        self.write(tree, key, None)
+    }
+}
@@ -0,0 +1,266 @@
+//! Storage backends the TodoRepository persists documents into.
+//!
+//! A backend is a set of named, ordered key-value trees, the repository takes care of encoding
+//! documents and of picking the tree for each entity type and tenant.
+use std::fmt;
+
+mod memory;
+mod sled_store;
+mod sqlite;
+
+pub use memory::MemoryStore;
+pub use sled_store::SledStore;
+pub use sqlite::SqliteStore;
+
+/// A key-value pair as stored by a backend.
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// Iterator over entries in ascending key order.
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<Entry, StoreError>> + 'a>;
+
+/// Failure reported by a storage backend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreError(pub String);
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl std::error::Error for StoreError {}
+
+/// Reads and writes of a TodoStore::transaction, either all of the writes take effect or none.
+pub trait Transaction {
+    /// Value stored under key in tree, including the transaction's own writes.
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError>;
+    /// Stores value under key in tree, returning the previous value.
+    fn insert(&self, tree: &str, key: &[u8], value: Vec<u8>)
+        -> Result<Option<Vec<u8>>, StoreError>;
+    /// Removes key from tree, returning the value it had.
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError>;
+}
+
+/// Why a transaction body stopped before committing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxError {
+    /// The body decided not to commit, the caller knows why.
+    Abort,
+    Store(StoreError),
+}
+impl From<StoreError> for TxError {
+    fn from(e: StoreError) -> Self {
+        TxError::Store(e)
+    }
+}
+
+/// Body of a transaction.
+/// Backends may run it more than once, so it must not have effects outside the transaction.
+pub type TxBody<'a> = dyn Fn(&dyn Transaction) -> Result<(), TxError> + 'a;
+
+/// Tree which receives the entries stores held before they were split into named trees.
+pub const LEGACY_TREE: &str = "todos";
+
+/// A backend of named, ordered key-value trees.
+/// Trees spring into existence on first use.
+/// Implementations have to be safe to share between request handler threads.
+pub trait TodoStore: Send + Sync {
+    /// Value stored under key in tree, if any.
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError>;
+    /// Stores value under key in tree, returning the previous value.
+    fn insert(&self, tree: &str, key: &[u8], value: Vec<u8>)
+        -> Result<Option<Vec<u8>>, StoreError>;
+    /// Removes key from tree, returning the value it had.
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError>;
+    /// All entries of tree in ascending key order.
+    fn iter(&self, tree: &str) -> Entries<'_>;
+    /// Entries of tree with a key greater than after, in ascending key order.
+    fn range_after(&self, tree: &str, after: &[u8]) -> Entries<'_>;
+    /// Entries of tree with a key from start up to, but excluding, end in ascending key order.
+    fn range(&self, tree: &str, start: &[u8], end: &[u8]) -> Entries<'_>;
+    /// Number of entries in tree.
+    fn count(&self, tree: &str) -> Result<u64, StoreError>;
+    /// Names of the trees holding entries, in no particular order.
+    fn tree_names(&self) -> Result<Vec<String>, StoreError>;
+    /// Runs body against trees, the only trees it may touch, committing all of its writes at once.
+    /// Concurrent transactions behave as if they ran one after the other.
+    /// Returns false, having written nothing, when body aborted.
+    fn transaction(&self, trees: &[&str], body: &TxBody<'_>) -> Result<bool, StoreError>;
+    /// A number never handed out before by this store, increasing with every call.
+    fn generate_id(&self) -> Result<u64, StoreError>;
+    /// Makes every acknowledged write durable.
+    fn flush(&self) -> Result<(), StoreError>;
+}
+
+/// The backends a server can be configured with.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Backend {
+    /// Everything is lost on exit, meant for tests.
+    Memory,
+    #[default]
+    Sled,
+    Sqlite,
+}
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "memory" => Ok(Self::Memory),
+            "sled" => Ok(Self::Sled),
+            "sqlite" => Ok(Self::Sqlite),
+            other => Err(format!("Unknown storage backend {:?}.", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The behaviour every backend has to agree on.
+    fn conformance(store: &dyn TodoStore) {
+        let t = "things";
+        assert_eq!(store.iter(t).count(), 0);
+        assert_eq!(store.insert(t, b"2", b"two".to_vec()).unwrap(), None);
+        assert_eq!(store.insert(t, b"1", b"one".to_vec()).unwrap(), None);
+        assert_eq!(
+            store.insert(t, b"2", b"TWO".to_vec()).unwrap(),
+            Some(b"two".to_vec())
+        );
+        assert_eq!(store.get(t, b"2").unwrap(), Some(b"TWO".to_vec()));
+        assert_eq!(store.get(t, b"3").unwrap(), None);
+
+        let keys: Vec<Vec<u8>> = store.iter(t).map(|e| e.unwrap().0).collect();
+        assert_eq!(keys, vec![b"1".to_vec(), b"2".to_vec()]);
+
+        assert_eq!(store.insert(t, b"3", b"three".to_vec()).unwrap(), None);
+
+        let after: Vec<Vec<u8>> = store.range_after(t, b"1").map(|e| e.unwrap().0).collect();
+        assert_eq!(after, vec![b"2".to_vec(), b"3".to_vec()]);
+        assert_eq!(store.range_after(t, b"15").count(), 2);
+        assert_eq!(store.range_after(t, b"3").count(), 0);
+        assert_eq!(store.count(t).unwrap(), 3);
+        assert_eq!(store.count("nothing").unwrap(), 0);
+
+        // Trees do not see each other's entries.
+        let other = "others";
+        assert_eq!(store.get(other, b"1").unwrap(), None);
+        assert_eq!(store.insert(other, b"1", b"eins".to_vec()).unwrap(), None);
+        assert_eq!(store.iter(other).count(), 1);
+
+        let first = store.generate_id().unwrap();
+        assert!(store.generate_id().unwrap() > first);
+
+        assert_eq!(store.remove(t, b"1").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(store.remove(t, b"1").unwrap(), None);
+        assert_eq!(store.get(other, b"1").unwrap(), Some(b"eins".to_vec()));
+
+        let ranged: Vec<Vec<u8>> = store.range(t, b"2", b"3").map(|e| e.unwrap().0).collect();
+        assert_eq!(ranged, vec![b"2".to_vec()]);
+        assert_eq!(store.range(t, b"3", b"2").count(), 0);
+
+        // Iterating takes more than one read on backends which read in batches, and the store
+        // may be used in between.
+        let many = "many";
+        for i in 0..150u8 {
+            store.insert(many, &[i], vec![i]).unwrap();
+        }
+        let mut seen = 0;
+        for entry in store.range_after(many, &[9]) {
+            let (key, value) = entry.unwrap();
+            assert_eq!(store.get(many, &key).unwrap(), Some(value));
+            seen += 1;
+        }
+        assert_eq!(seen, 140);
+        assert_eq!(store.range(many, &[0], &[100]).count(), 100);
+        assert_eq!(store.iter(many).take(3).count(), 3);
+        for i in 0..150u8 {
+            store.remove(many, &[i]).unwrap();
+        }
+        let mut names = store.tree_names().unwrap();
+        names.sort();
+        assert_eq!(names, vec![other, t]);
+
+        // A transaction's writes land together.
+        let committed = store
+            .transaction(&[t, other], &|tx| {
+                assert_eq!(tx.insert(t, b"4", b"four".to_vec())?, None);
+                assert_eq!(tx.get(t, b"4")?, Some(b"four".to_vec()));
+                assert_eq!(tx.remove(other, b"1")?, Some(b"eins".to_vec()));
+                assert_eq!(tx.get(other, b"1")?, None);
+                Ok(())
+            })
+            .unwrap();
+        assert!(committed);
+        assert_eq!(store.get(t, b"4").unwrap(), Some(b"four".to_vec()));
+        assert_eq!(store.get(other, b"1").unwrap(), None);
+
+        // An aborted or failed one leaves no trace.
+        let aborted = store.transaction(&[t], &|tx| {
+            tx.insert(t, b"5", b"five".to_vec())?;
+            tx.remove(t, b"4")?;
+            Err(TxError::Abort)
+        });
+        assert_eq!(aborted, Ok(false));
+        let failed = store.transaction(&[t], &|tx| {
+            tx.insert(t, b"5", b"five".to_vec())?;
+            tx.get(other, b"1")?;
+            Ok(())
+        });
+        assert!(failed.is_err());
+        assert_eq!(store.get(t, b"5").unwrap(), None);
+        assert_eq!(store.get(t, b"4").unwrap(), Some(b"four".to_vec()));
+        store.flush().unwrap();
+    }
+
+    #[test]
+    fn memory_store() {
+        conformance(&MemoryStore::new());
+    }
+
+    #[test]
+    fn sled_store() {
+        conformance(&SledStore::temporary().unwrap());
+    }
+
+    #[test]
+    fn sqlite_store() {
+        conformance(&SqliteStore::in_memory().unwrap());
+    }
+
+    #[test]
+    fn legacy_entries_move_to_the_legacy_tree() {
+        let dir = std::env::temp_dir().join(format!("todo-legacy-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let sled_path = dir.join("sled");
+        {
+            let db = sled::open(&sled_path).unwrap();
+            db.insert(b"1", b"one".to_vec()).unwrap();
+            db.flush().unwrap();
+        }
+        let store = SledStore::open(&sled_path).unwrap();
+        assert_eq!(store.get(LEGACY_TREE, b"1").unwrap(), Some(b"one".to_vec()));
+        drop(store);
+
+        let sqlite_path = dir.join("sqlite");
+        {
+            let conn = rusqlite::Connection::open(&sqlite_path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE todos (key BLOB PRIMARY KEY, value BLOB NOT NULL);
+                 INSERT INTO todos VALUES (x'31', x'6f6e65');",
+            )
+            .unwrap();
+        }
+        let store = SqliteStore::open(&sqlite_path).unwrap();
+        assert_eq!(store.get(LEGACY_TREE, b"1").unwrap(), Some(b"one".to_vec()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backend_names() {
+        assert_eq!("SQLite".parse::<Backend>(), Ok(Backend::Sqlite));
+        assert!("postgres".parse::<Backend>().is_err());
+    }
+}
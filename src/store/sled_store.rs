@@ -0,0 +1,260 @@
+use super::{Entries, Entry, StoreError, TodoStore, Transaction, TxBody, TxError, LEGACY_TREE};
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, TransactionalTree, UnabortableTransactionError,
+};
+use sled::{Db, IVec, Transactional, Tree};
+use std::cell::RefCell;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// Embedded sled database on disk, every tree is a sled tree of the same name.
+#[derive(Clone)]
+pub struct SledStore {
+    db: Db,
+}
+impl SledStore {
+    /// Opens the database, moving entries left in the default tree into LEGACY_TREE.
+    /// Waits a moment for the file lock when a closing handle still holds it.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        let config = sled::Config::new().path(path.as_ref());
+        let mut attempts = 0;
+        let opened = loop {
+            match config.open() {
+                // sled only tells lock contention apart by its message.
+                Err(sled::Error::Io(e))
+                    if e.to_string().contains("could not acquire lock")
+                        && attempts < LOCK_ATTEMPTS =>
+                {
+                    attempts += 1;
+                    thread::sleep(Duration::from_millis(50));
+                }
+                opened => break opened,
+            }
+        };
+        let db = opened.map_err(|e| {
+            StoreError(format!(
+                "Could not open database at {:?}: {}",
+                path.as_ref(),
+                e
+            ))
+        })?;
+        let store = Self::new(db);
+        store.adopt_default_tree()?;
+        Ok(store)
+    }
+
+    /// A database which is deleted when dropped.
+    pub fn temporary() -> Result<Self, StoreError> {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .map_err(storage_error)?;
+        Ok(Self::new(db))
+    }
+
+    /// Wraps an already opened database.
+    pub fn new(db: Db) -> Self {
+        SledStore { db }
+    }
+
+    fn tree(&self, name: &str) -> Result<Tree, StoreError> {
+        self.db.open_tree(name).map_err(storage_error)
+    }
+
+    /// Before named trees every todo was written to the default tree.
+    fn adopt_default_tree(&self) -> Result<(), StoreError> {
+        if self.db.is_empty() {
+            return Ok(());
+        }
+        let legacy = self.tree(LEGACY_TREE)?;
+        for item in self.db.iter() {
+            let (key, value) = item.map_err(storage_error)?;
+            legacy.insert(&key, value).map_err(storage_error)?;
+            self.db.remove(&key).map_err(storage_error)?;
+        }
+        self.db.flush().map_err(storage_error)?;
+        Ok(())
+    }
+}
+
+/// How often open tries to get the file lock, 50ms apart.
+const LOCK_ATTEMPTS: usize = 40;
+
+fn storage_error(e: sled::Error) -> StoreError {
+    StoreError(e.to_string())
+}
+
+fn entry((k, v): (IVec, IVec)) -> Entry {
+    (k.to_vec(), v.to_vec())
+}
+
+impl TodoStore for SledStore {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .tree(tree)?
+            .get(key)
+            .map_err(storage_error)?
+            .map(|v| v.to_vec()))
+    }
+
+    fn insert(
+        &self,
+        tree: &str,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .tree(tree)?
+            .insert(key, value)
+            .map_err(storage_error)?
+            .map(|v| v.to_vec()))
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .tree(tree)?
+            .remove(key)
+            .map_err(storage_error)?
+            .map(|v| v.to_vec()))
+    }
+
+    fn iter(&self, tree: &str) -> Entries<'_> {
+        match self.tree(tree) {
+            Ok(tree) => Box::new(
+                tree.iter()
+                    .map(|item| item.map(entry).map_err(storage_error)),
+            ),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn range_after(&self, tree: &str, after: &[u8]) -> Entries<'_> {
+        match self.tree(tree) {
+            Ok(tree) => Box::new(
+                tree.range::<&[u8], _>((Excluded(after), Unbounded))
+                    .map(|item| item.map(entry).map_err(storage_error)),
+            ),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn range(&self, tree: &str, start: &[u8], end: &[u8]) -> Entries<'_> {
+        if start >= end {
+            return Box::new(std::iter::empty());
+        }
+        match self.tree(tree) {
+            Ok(tree) => Box::new(
+                tree.range(start..end)
+                    .map(|item| item.map(entry).map_err(storage_error)),
+            ),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn count(&self, tree: &str) -> Result<u64, StoreError> {
+        Ok(self.tree(tree)?.len() as u64)
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>, StoreError> {
+        let mut names = vec![];
+        for name in self.db.tree_names() {
+            // The default tree is emptied into LEGACY_TREE on open.
+            if name == self.db.name() {
+                continue;
+            }
+            if !self.db.open_tree(&name).map_err(storage_error)?.is_empty() {
+                names.push(String::from_utf8_lossy(&name).into_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    fn transaction(&self, trees: &[&str], body: &TxBody<'_>) -> Result<bool, StoreError> {
+        let opened = trees
+            .iter()
+            .map(|name| self.tree(name))
+            .collect::<Result<Vec<Tree>, _>>()?;
+        let result = opened[..].transaction(|views| {
+            let tx = SledTransaction {
+                names: trees,
+                views,
+                failure: RefCell::new(None),
+            };
+            let outcome = body(&tx);
+            // Conflicts have to reach sled untouched, so it retries the body.
+            if let Some(failure) = tx.failure.into_inner() {
+                return Err(failure.into());
+            }
+            match outcome {
+                Ok(()) => Ok(()),
+                Err(TxError::Abort) => Err(ConflictableTransactionError::Abort(None)),
+                Err(TxError::Store(e)) => Err(ConflictableTransactionError::Abort(Some(e))),
+            }
+        });
+        match result {
+            Ok(()) => Ok(true),
+            Err(TransactionError::Abort(None)) => Ok(false),
+            Err(TransactionError::Abort(Some(e))) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(storage_error(e)),
+        }
+    }
+
+    fn generate_id(&self) -> Result<u64, StoreError> {
+        self.db.generate_id().map_err(storage_error)
+    }
+
+    fn flush(&self) -> Result<(), StoreError> {
+        self.db.flush().map(|_| ()).map_err(storage_error)
+    }
+}
+
+/// A sled transaction over named trees.
+struct SledTransaction<'a> {
+    names: &'a [&'a str],
+    views: &'a [TransactionalTree],
+    /// The first conflict or storage failure sled reported, it decides the transaction's fate.
+    failure: RefCell<Option<UnabortableTransactionError>>,
+}
+impl SledTransaction<'_> {
+    fn view(&self, tree: &str) -> Result<&TransactionalTree, StoreError> {
+        self.names
+            .iter()
+            .position(|name| *name == tree)
+            .map(|i| &self.views[i])
+            .ok_or_else(|| StoreError(format!("Tree {:?} is not part of the transaction.", tree)))
+    }
+
+    fn check(
+        &self,
+        result: Result<Option<IVec>, UnabortableTransactionError>,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        match result {
+            Ok(value) => Ok(value.map(|v| v.to_vec())),
+            Err(e) => {
+                let error = StoreError(e.to_string());
+                self.failure.borrow_mut().get_or_insert(e);
+                Err(error)
+            }
+        }
+    }
+}
+impl Transaction for SledTransaction<'_> {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        self.check(self.view(tree)?.get(key))
+    }
+
+    fn insert(
+        &self,
+        tree: &str,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        self.check(self.view(tree)?.insert(key, value))
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        self.check(self.view(tree)?.remove(key))
+    }
+}
@@ -0,0 +1,330 @@
+use super::{Entries, Entry, StoreError, TodoStore, Transaction, TxBody, TxError, LEGACY_TREE};
+use rusqlite::{params, Connection, OptionalExtension, ToSql};
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+/// Rows iterators read per query.
+const BATCH_LEN: usize = 64;
+
+/// Embedded SQLite database, all trees share one table keyed by tree and key.
+/// Ids come from a one row counter table.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        let conn = Connection::open(path.as_ref()).map_err(|e| {
+            StoreError(format!(
+                "Could not open database at {:?}: {}",
+                path.as_ref(),
+                e
+            ))
+        })?;
+        Self::new(conn)
+    }
+
+    /// A database which only lives as long as the store.
+    pub fn in_memory() -> Result<Self, StoreError> {
+        Self::new(Connection::open_in_memory().map_err(storage_error)?)
+    }
+
+    /// Wraps an already opened connection, creating the tables if needed.
+    /// A `todos` table from before named trees is moved into LEGACY_TREE.
+    pub fn new(conn: Connection) -> Result<Self, StoreError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS entries (
+                tree TEXT NOT NULL,
+                key BLOB NOT NULL,
+                value BLOB NOT NULL,
+                PRIMARY KEY (tree, key)
+            );
+            CREATE TABLE IF NOT EXISTS ids (next INTEGER NOT NULL);",
+        )
+        .map_err(storage_error)?;
+        let legacy: Option<String> = conn
+            .query_row(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'todos'",
+                params![],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(storage_error)?;
+        if legacy.is_some() {
+            conn.execute(
+                "INSERT OR IGNORE INTO entries (tree, key, value) SELECT ?1, key, value FROM todos",
+                params![LEGACY_TREE],
+            )
+            .map_err(storage_error)?;
+            conn.execute("DROP TABLE todos", params![])
+                .map_err(storage_error)?;
+        }
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> Result<MutexGuard<'_, Connection>, StoreError> {
+        self.conn
+            .lock()
+            .map_err(|_| StoreError("SQLite connection is poisoned.".to_owned()))
+    }
+
+    /// Entries of tree from start up to, but excluding, end, if any.
+    fn entries(&self, tree: &str, start: Bound<Vec<u8>>, end: Option<&[u8]>) -> Entries<'_> {
+        Box::new(Batches {
+            store: self,
+            tree: tree.to_owned(),
+            start,
+            end: end.map(<[u8]>::to_vec),
+            batch: Vec::new().into_iter(),
+            done: false,
+        })
+    }
+}
+
+/// Entries read BATCH_LEN rows at a time, each batch starting after the last key of the one
+/// before. The connection is only held while a batch is read, so callers may use the store
+/// between entries, and the rows nobody gets to are never read.
+struct Batches<'a> {
+    store: &'a SqliteStore,
+    tree: String,
+    start: Bound<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    batch: std::vec::IntoIter<Entry>,
+    done: bool,
+}
+impl Batches<'_> {
+    fn read_batch(&self) -> Result<Vec<Entry>, StoreError> {
+        let limit = BATCH_LEN as i64;
+        let mut sql = "SELECT key, value FROM entries WHERE tree = ?".to_owned();
+        let mut params: Vec<&dyn ToSql> = vec![&self.tree];
+        match &self.start {
+            Included(start) => {
+                sql.push_str(" AND key >= ?");
+                params.push(start);
+            }
+            Excluded(after) => {
+                sql.push_str(" AND key > ?");
+                params.push(after);
+            }
+            Unbounded => {}
+        }
+        if let Some(end) = &self.end {
+            sql.push_str(" AND key < ?");
+            params.push(end);
+        }
+        sql.push_str(" ORDER BY key LIMIT ?");
+        params.push(&limit);
+
+        let conn = self.store.conn()?;
+        let mut stmt = conn.prepare(&sql).map_err(storage_error)?;
+        let rows = stmt
+            .query_map(&params, |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(storage_error)?;
+        rows.collect::<Result<Vec<Entry>, _>>()
+            .map_err(storage_error)
+    }
+}
+impl Iterator for Batches<'_> {
+    type Item = Result<Entry, StoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.batch.next() {
+            return Some(Ok(entry));
+        }
+        if self.done {
+            return None;
+        }
+        match self.read_batch() {
+            Ok(batch) => {
+                self.done = batch.len() < BATCH_LEN;
+                if let Some((last, _)) = batch.last() {
+                    self.start = Excluded(last.clone());
+                }
+                self.batch = batch.into_iter();
+                self.batch.next().map(Ok)
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn storage_error(e: rusqlite::Error) -> StoreError {
+    StoreError(e.to_string())
+}
+
+fn get(conn: &Connection, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+    conn.query_row(
+        "SELECT value FROM entries WHERE tree = ?1 AND key = ?2",
+        params![tree, key],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(storage_error)
+}
+
+fn insert(
+    conn: &Connection,
+    tree: &str,
+    key: &[u8],
+    value: Vec<u8>,
+) -> Result<Option<Vec<u8>>, StoreError> {
+    let previous = get(conn, tree, key)?;
+    conn.execute(
+        "INSERT OR REPLACE INTO entries (tree, key, value) VALUES (?1, ?2, ?3)",
+        params![tree, key, value],
+    )
+    .map_err(storage_error)?;
+    Ok(previous)
+}
+
+fn remove(conn: &Connection, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+    let previous = get(conn, tree, key)?;
+    conn.execute(
+        "DELETE FROM entries WHERE tree = ?1 AND key = ?2",
+        params![tree, key],
+    )
+    .map_err(storage_error)?;
+    Ok(previous)
+}
+
+impl TodoStore for SqliteStore {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        get(&*self.conn()?, tree, key)
+    }
+
+    fn insert(
+        &self,
+        tree: &str,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        insert(&*self.conn()?, tree, key, value)
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        remove(&*self.conn()?, tree, key)
+    }
+
+    fn iter(&self, tree: &str) -> Entries<'_> {
+        self.entries(tree, Unbounded, None)
+    }
+
+    fn range_after(&self, tree: &str, after: &[u8]) -> Entries<'_> {
+        self.entries(tree, Excluded(after.to_vec()), None)
+    }
+
+    fn range(&self, tree: &str, start: &[u8], end: &[u8]) -> Entries<'_> {
+        self.entries(tree, Included(start.to_vec()), Some(end))
+    }
+
+    fn count(&self, tree: &str) -> Result<u64, StoreError> {
+        let count: i64 = self
+            .conn()?
+            .query_row(
+                "SELECT COUNT(*) FROM entries WHERE tree = ?1",
+                params![tree],
+                |row| row.get(0),
+            )
+            .map_err(storage_error)?;
+        Ok(count as u64)
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>, StoreError> {
+        let conn = self.conn()?;
+        let mut stmt = conn
+            .prepare("SELECT DISTINCT tree FROM entries")
+            .map_err(storage_error)?;
+        let names = stmt
+            .query_map(params![], |row| row.get(0))
+            .map_err(storage_error)?;
+        names
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(storage_error)
+    }
+
+    fn transaction(&self, trees: &[&str], body: &TxBody<'_>) -> Result<bool, StoreError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction().map_err(storage_error)?;
+        let outcome = body(&SqliteTransaction {
+            tx: &tx,
+            names: trees,
+        });
+        match outcome {
+            Ok(()) => {
+                tx.commit().map_err(storage_error)?;
+                Ok(true)
+            }
+            // Dropping tx rolls it back.
+            Err(TxError::Abort) => Ok(false),
+            Err(TxError::Store(e)) => Err(e),
+        }
+    }
+
+    fn generate_id(&self) -> Result<u64, StoreError> {
+        // The connection lock serializes callers, so read-then-write is safe.
+        let conn = self.conn()?;
+        let next: Option<i64> = conn
+            .query_row("SELECT next FROM ids", params![], |row| row.get(0))
+            .optional()
+            .map_err(storage_error)?;
+        let id = match next {
+            Some(id) => {
+                conn.execute("UPDATE ids SET next = ?1", params![id + 1])
+                    .map_err(storage_error)?;
+                id
+            }
+            None => {
+                conn.execute("INSERT INTO ids (next) VALUES (1)", params![])
+                    .map_err(storage_error)?;
+                0
+            }
+        };
+        Ok(id as u64)
+    }
+
+    fn flush(&self) -> Result<(), StoreError> {
+        // Every statement commits on its own.
+        Ok(())
+    }
+}
+
+/// An SQLite transaction, restricted to the trees it was started for.
+struct SqliteTransaction<'a> {
+    tx: &'a rusqlite::Transaction<'a>,
+    names: &'a [&'a str],
+}
+impl SqliteTransaction<'_> {
+    fn check(&self, tree: &str) -> Result<&Connection, StoreError> {
+        if self.names.contains(&tree) {
+            Ok(self.tx)
+        } else {
+            Err(StoreError(format!(
+                "Tree {:?} is not part of the transaction.",
+                tree
+            )))
+        }
+    }
+}
+impl Transaction for SqliteTransaction<'_> {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        get(self.check(tree)?, tree, key)
+    }
+
+    fn insert(
+        &self,
+        tree: &str,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        insert(self.check(tree)?, tree, key, value)
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        remove(self.check(tree)?, tree, key)
+    }
+}
@@ -0,0 +1,607 @@
+//! Starts every server on an ephemeral port and checks they answer the same requests alike.
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A server binary and the legacy routes it served the todo api on before /v1, where they differ
+/// between servers.
+struct Server {
+    name: &'static str,
+    exe: &'static str,
+    create: (&'static str, &'static str),
+    edit: (&'static str, &'static str),
+    replace: (&'static str, &'static str),
+    delete: (&'static str, &'static str),
+}
+
+const SERVERS: [Server; 3] = [
+    Server {
+        name: "iron",
+        exe: env!("CARGO_BIN_EXE_iron"),
+        create: ("POST", "/todo/add"),
+        edit: ("PATCH", "/todo/edit"),
+        replace: ("PUT", "/todo/replace"),
+        delete: ("DELETE", "/todo/delete"),
+    },
+    Server {
+        name: "warp",
+        exe: env!("CARGO_BIN_EXE_warp"),
+        create: ("POST", "/todo/create"),
+        edit: ("PATCH", "/todo/update"),
+        replace: ("PUT", "/todo/replace"),
+        delete: ("DELETE", "/todo/delete"),
+    },
+    Server {
+        name: "tower_web",
+        exe: env!("CARGO_BIN_EXE_tower_web"),
+        create: ("POST", "/todo/create"),
+        edit: ("PATCH", "/todo/update"),
+        replace: ("PUT", "/todo/replace"),
+        delete: ("DELETE", "/todo/delete"),
+    },
+];
+
+/// A running server, stopped when dropped.
+struct Running {
+    child: Child,
+    addr: SocketAddr,
+}
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_port() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+fn start(server: &Server) -> Running {
+    let addr = free_port();
+    let child = Command::new(server.exe)
+        .args(["--listen", &addr.to_string(), "--db-backend", "memory"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let running = Running { child, addr };
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(addr).is_err() {
+        assert!(Instant::now() < deadline, "{} didn't start", server.name);
+        thread::sleep(Duration::from_millis(50));
+    }
+    running
+}
+
+/// What a server answered, with the headers every server must send alike.
+#[derive(Debug, PartialEq)]
+struct Answer {
+    status: u16,
+    etag: Option<String>,
+    content_type: Option<String>,
+    deprecation: Option<String>,
+    link: Option<String>,
+    body: Value,
+}
+
+fn send(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Answer {
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+    let mut raw = vec![];
+    stream.read_to_end(&mut raw).unwrap();
+    parse(&raw)
+}
+
+/// Posts a body to path, framed by the header, but stops after start and never finishes it.
+/// Servers which don't stop reading at some point never answer.
+fn send_unfinished(addr: SocketAddr, path: &str, header: (&str, &str), start: &[u8]) -> Answer {
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n{}: {}\r\n\r\n",
+        path, addr, header.0, header.1
+    );
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    // Servers may answer and hang up before all of it is sent.
+    let _ = stream.write_all(request.as_bytes());
+    let _ = stream.write_all(start);
+    let mut raw = vec![];
+    let _ = stream.read_to_end(&mut raw);
+    assert!(!raw.is_empty(), "No answer to an unfinished body.");
+    parse(&raw)
+}
+
+/// body as chunks of a chunked transfer, without the last, empty one.
+fn chunked(body: &[u8]) -> Vec<u8> {
+    let mut chunks = vec![];
+    for chunk in body.chunks(64 * 1024) {
+        chunks.extend(format!("{:x}\r\n", chunk.len()).bytes());
+        chunks.extend(chunk);
+        chunks.extend(b"\r\n");
+    }
+    chunks
+}
+
+fn parse(raw: &[u8]) -> Answer {
+    let split = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&raw[..split]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_at(line.find(':')?);
+            Some((name.to_ascii_lowercase(), value[1..].trim().to_owned()))
+        })
+        .collect();
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.clone())
+    };
+    let mut body = raw[split + 4..].to_vec();
+    if header("transfer-encoding").as_deref() == Some("chunked") {
+        body = dechunk(&body);
+    }
+    Answer {
+        status: status.parse().unwrap(),
+        etag: header("etag"),
+        content_type: header("content-type"),
+        deprecation: header("deprecation"),
+        link: header("link"),
+        body: normalize(decode(header("content-type").as_deref(), &body)),
+    }
+}
+
+/// body, in the format content_type names.
+fn decode(content_type: Option<&str>, body: &[u8]) -> Value {
+    let decoded = match content_type {
+        Some("application/cbor") => serde_cbor::from_slice(body).ok(),
+        Some("application/msgpack") => rmp_serde::from_slice(body).ok(),
+        Some("application/yaml") => serde_yaml::from_slice(body).ok(),
+        _ => serde_json::from_slice(body).ok(),
+    };
+    decoded.unwrap_or(Value::Null)
+}
+
+fn dechunk(mut chunked: &[u8]) -> Vec<u8> {
+    let mut body = vec![];
+    loop {
+        let end = chunked.windows(2).position(|w| w == b"\r\n").unwrap();
+        let size = std::str::from_utf8(&chunked[..end]).unwrap();
+        let size = usize::from_str_radix(size.split(';').next().unwrap().trim(), 16).unwrap();
+        if size == 0 {
+            return body;
+        }
+        body.extend_from_slice(&chunked[end + 2..end + 2 + size]);
+        chunked = &chunked[end + 2 + size + 2..];
+    }
+}
+
+/// value, with the times the servers set when they handle a request blanked out.
+fn normalize(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(name, value)| match value {
+                    Value::Number(_) if name.ends_with("_at") => (name, json!("<time>")),
+                    value => (name, normalize(value)),
+                })
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(normalize).collect()),
+        value => value,
+    }
+}
+
+/// The answers of server to the scenario every server is put through, with their statuses.
+fn transcript(server: &Server) -> Vec<(String, Answer)> {
+    let running = start(server);
+    let mut answers = vec![];
+    let mut step = |label: &str, method: &str, path: &str, headers: &[(&str, &str)], body: &str| {
+        let answer = send(running.addr, method, path, headers, body.as_bytes());
+        answers.push((label.to_owned(), answer));
+        answers.last().unwrap().1.body.clone()
+    };
+    let json_type = ("Content-Type", "application/json");
+    let todo = |title: &str| {
+        json!({"_key": "", "title": title, "timestamp": 0, "status": "New"}).to_string()
+    };
+
+    let milk = step("create", "POST", "/v1/todos", &[json_type], &todo("milk"));
+    let key = milk["_key"].as_str().unwrap_or_default().to_owned();
+    let at = |path: &str| path.replace("{}", &key);
+    let todo_path = at("/v1/todos/{}");
+    step("fetch", "GET", &todo_path, &[], "");
+    step("fetch missing", "GET", "/v1/todos/nothing", &[], "");
+    step("create malformed", "POST", "/v1/todos", &[json_type], "{");
+    step(
+        "create invalid",
+        "POST",
+        "/v1/todos",
+        &[json_type],
+        r#"{"title": 5}"#,
+    );
+    let merge = ("Content-Type", "application/merge-patch+json");
+    let stale = [merge, ("If-Match", "\"7\"")];
+    step(
+        "edit stale",
+        "PATCH",
+        &todo_path,
+        &stale,
+        r#"{"title": "oat milk"}"#,
+    );
+    let patch = ("Content-Type", "application/json-patch+json");
+    let failing = r#"[{"op": "test", "path": "/title", "value": "eggs"}]"#;
+    step("patch failing", "PATCH", &todo_path, &[patch], failing);
+    let current = [merge, ("If-Match", "\"1\"")];
+    step(
+        "edit",
+        "PATCH",
+        &todo_path,
+        &current,
+        r#"{"title": "oat milk"}"#,
+    );
+    let replaced = json!({"title": "milk", "timestamp": 0, "status": "New"}).to_string();
+    step("replace", "PUT", &todo_path, &[json_type], &replaced);
+    let renamed = json!({"_key": "other", "title": "milk", "timestamp": 0, "status": "New"});
+    step(
+        "replace renamed",
+        "PUT",
+        &todo_path,
+        &[json_type],
+        &renamed.to_string(),
+    );
+    step("start", "POST", &at("/v1/todos/{}/start"), &[], "");
+    step("fly", "POST", &at("/v1/todos/{}/fly"), &[], "");
+    step("list", "GET", "/v1/todos?status=Started&limit=5", &[], "");
+    step("list bad query", "GET", "/v1/todos?limit=many", &[], "");
+    step("history", "GET", &at("/v1/todos/{}/history"), &[], "");
+    step("revision", "GET", &at("/v1/todos/{}/history/2"), &[], "");
+    let malformed = at("/v1/todos/{}/history/first");
+    step("revision malformed", "GET", &malformed, &[], "");
+    step("diff", "GET", &at("/v1/todos/{}/history/diff/1/3"), &[], "");
+    // The todo was started since revision 1, and can't become new again.
+    let refused = at("/v1/todos/{}/history/1/rollback");
+    step("rollback refused", "POST", &refused, &[], "");
+    let rollback = at("/v1/todos/{}/history/4/rollback");
+    step("rollback", "POST", &rollback, &[], "");
+    let ops = json!([
+        {"op": "create", "todo": {"_key": "", "title": "eggs", "timestamp": 0, "status": "New"}},
+        {"op": "delete", "_key": "nothing"}
+    ]);
+    let bulk = "/v1/todos/bulk?mode=best-effort";
+    step("bulk", "POST", bulk, &[json_type], &ops.to_string());
+    let ops = json!([
+        {"op": "update", "patch": {"_key": key, "title": "whole milk"}},
+        {"op": "delete", "_key": "nothing"}
+    ]);
+    let bulk = "/v1/todos/bulk";
+    step(
+        "bulk rolled back",
+        "POST",
+        bulk,
+        &[json_type],
+        &ops.to_string(),
+    );
+    let outdated = [("If-Match", "\"1\"")];
+    step("delete stale", "DELETE", &todo_path, &outdated, "");
+    step("delete", "DELETE", &todo_path, &[], "");
+    step("trash", "GET", "/v1/trash", &[], "");
+    step("restore", "POST", &at("/v1/trash/{}/restore"), &[], "");
+    step("delete again", "DELETE", &todo_path, &[], "");
+    step("purge", "DELETE", &at("/v1/trash/{}"), &[], "");
+    step("purge missing", "DELETE", &at("/v1/trash/{}"), &[], "");
+    let bad_tenant = [("X-Tenant", "Not A Slug")];
+    step("bad tenant", "GET", "/v1/todos", &bad_tenant, "");
+    step("tenant", "GET", "/v1/todos", &[("X-Tenant", "acme")], "");
+    step("unknown route", "GET", "/v1/nothing/to/see/here", &[], "");
+    let once = [json_type, ("Idempotency-Key", "abc")];
+    step("create once", "POST", "/v1/todos", &once, &todo("bread"));
+    step(
+        "create replayed",
+        "POST",
+        "/v1/todos",
+        &once,
+        &todo("bread"),
+    );
+
+    // The legacy routes, which differ between servers.
+    let (create, edit, replace, delete) =
+        (server.create, server.edit, server.replace, server.delete);
+    let cheese = step(
+        "legacy create",
+        create.0,
+        create.1,
+        &[json_type],
+        &todo("cheese"),
+    );
+    let key = cheese["_key"].as_str().unwrap_or_default().to_owned();
+    step("legacy list", "GET", "/todo/list?limit=1", &[], "");
+    let renamed = json!({"_key": key, "title": "brie"}).to_string();
+    step("legacy edit", edit.0, edit.1, &[merge], &renamed);
+    let replaced = json!({"_key": key, "title": "cheese", "timestamp": 0, "status": "New"});
+    step(
+        "legacy replace",
+        replace.0,
+        replace.1,
+        &[json_type],
+        &replaced.to_string(),
+    );
+    step(
+        "legacy history",
+        "GET",
+        &format!("/todo/history/{}/1", key),
+        &[],
+        "",
+    );
+    step(
+        "legacy delete",
+        delete.0,
+        &format!("{}/{}", delete.1, key),
+        &[],
+        "",
+    );
+
+    // Bodies in the other formats, which may be binary.
+    let mut step =
+        |label: &str, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]| {
+            let answer = send(running.addr, method, path, headers, body);
+            answers.push((label.to_owned(), answer));
+            answers.last().unwrap().1.body.clone()
+        };
+    let cbor = [
+        ("Content-Type", "application/cbor"),
+        ("Accept", "application/cbor"),
+    ];
+    let bagel = json!({"_key": "", "title": "bagel", "timestamp": 0, "status": "New"});
+    let bagel = step(
+        "create cbor",
+        "POST",
+        "/v1/todos",
+        &cbor,
+        &serde_cbor::to_vec(&bagel).unwrap(),
+    );
+    let bagel_path = format!("/v1/todos/{}", bagel["_key"].as_str().unwrap_or_default());
+    for format in ["json", "msgpack", "yaml"] {
+        let media_type = format!("application/{}", format);
+        let label = format!("fetch {}", format);
+        step(&label, "GET", &bagel_path, &[("Accept", &media_type)], b"");
+    }
+    step(
+        "fetch unacceptable",
+        "GET",
+        &bagel_path,
+        &[("Accept", "text/html")],
+        b"",
+    );
+    let yaml = [("Content-Type", "application/yaml")];
+    step("edit yaml", "PATCH", &bagel_path, &yaml, b"title: bagels\n");
+    let text = [("Content-Type", "text/plain")];
+    step(
+        "create unsupported",
+        "POST",
+        "/v1/todos",
+        &text,
+        todo("bread").as_bytes(),
+    );
+    answers
+}
+
+#[test]
+fn servers_answer_alike() {
+    let transcripts: Vec<_> = SERVERS.iter().map(transcript).collect();
+
+    let statuses: Vec<_> = transcripts[0]
+        .iter()
+        .map(|(label, answer)| (label.as_str(), answer.status))
+        .collect();
+    assert_eq!(
+        statuses,
+        vec![
+            ("create", 200),
+            ("fetch", 200),
+            ("fetch missing", 404),
+            ("create malformed", 400),
+            ("create invalid", 422),
+            ("edit stale", 412),
+            ("patch failing", 409),
+            ("edit", 200),
+            ("replace", 200),
+            ("replace renamed", 422),
+            ("start", 200),
+            ("fly", 404),
+            ("list", 200),
+            ("list bad query", 400),
+            ("history", 200),
+            ("revision", 200),
+            ("revision malformed", 400),
+            ("diff", 200),
+            ("rollback refused", 409),
+            ("rollback", 200),
+            ("bulk", 200),
+            ("bulk rolled back", 404),
+            ("delete stale", 412),
+            ("delete", 200),
+            ("trash", 200),
+            ("restore", 200),
+            ("delete again", 200),
+            ("purge", 200),
+            ("purge missing", 404),
+            ("bad tenant", 400),
+            ("tenant", 200),
+            ("unknown route", 404),
+            ("create once", 200),
+            ("create replayed", 200),
+            ("legacy create", 200),
+            ("legacy list", 200),
+            ("legacy edit", 200),
+            ("legacy replace", 200),
+            ("legacy history", 200),
+            ("legacy delete", 200),
+            ("create cbor", 200),
+            ("fetch json", 200),
+            ("fetch msgpack", 200),
+            ("fetch yaml", 200),
+            ("fetch unacceptable", 406),
+            ("edit yaml", 200),
+            ("create unsupported", 415),
+        ]
+    );
+    let answer = |label: &str| &transcripts[0].iter().find(|(l, _)| l == label).unwrap().1;
+    assert_eq!(answer("create").etag.as_deref(), Some("\"1\""));
+    assert_eq!(
+        answer("create").content_type.as_deref(),
+        Some("application/json")
+    );
+    assert_eq!(answer("unknown route").body["detail"], "No such route.");
+    let rolled_back = &answer("bulk rolled back").body;
+    assert_eq!(
+        (&rolled_back["applied"], &rolled_back["failed"]),
+        (&json!(0), &json!(1))
+    );
+    let problem = answer("fetch missing");
+    assert_eq!(
+        problem.content_type.as_deref(),
+        Some("application/problem+json")
+    );
+    assert_eq!(problem.body["instance"], "/v1/todos/nothing");
+    assert_eq!(answer("create invalid").body["errors"][0]["field"], "_key");
+    assert_eq!(answer("create replayed").body, answer("create once").body);
+    assert_eq!(answer("create").deprecation, None);
+    assert_eq!(
+        answer("list").body["links"]["self"],
+        "/v1/todos?limit=5&status=Started"
+    );
+    let legacy = answer("legacy history");
+    assert_eq!(legacy.deprecation.as_deref(), Some("@1792281600"));
+    assert_eq!(
+        legacy.link.as_deref(),
+        Some("</v1/todos/00000000000000000003/history/1>; rel=\"successor-version\"")
+    );
+    assert_eq!(answer("legacy edit").link, None);
+    let cbor = answer("create cbor");
+    assert_eq!(cbor.content_type.as_deref(), Some("application/cbor"));
+    assert_eq!(cbor.body["title"], "bagel");
+    for format in ["json", "msgpack", "yaml"] {
+        let fetched = answer(&format!("fetch {}", format));
+        let media_type = format!("application/{}", format);
+        assert_eq!(fetched.content_type, Some(media_type));
+        assert_eq!(fetched.body, cbor.body);
+    }
+    assert_eq!(answer("edit yaml").body["title"], "bagels");
+    assert_eq!(answer("fetch unacceptable").body["code"], "not_acceptable");
+
+    for (server, other) in SERVERS.iter().zip(&transcripts).skip(1) {
+        for (expected, answer) in transcripts[0].iter().zip(other) {
+            assert_eq!(
+                answer, expected,
+                "{} answers unlike {}",
+                server.name, SERVERS[0].name
+            );
+        }
+    }
+}
+
+/// path with every segment its template captures filled in.
+fn fill(template: &str) -> String {
+    template
+        .split('/')
+        .map(|segment| match segment {
+            "{key}" => "nothing",
+            "{action}" => "start",
+            "{rev}" | "{from}" => "1",
+            "{to}" => "2",
+            segment => segment,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[test]
+fn servers_turn_away_oversized_bodies() {
+    let body = vec![b' '; 1024 * 1024 + 1];
+    for server in SERVERS.iter() {
+        let running = start(server);
+        // Neither a body announcing its size nor one sent in chunks is read to its end.
+        let announced = ("Content-Length", "1073741824");
+        let announced = send_unfinished(running.addr, "/v1/todos", announced, b"");
+        assert_eq!(announced.status, 413, "{}", server.name);
+        let streamed = ("Transfer-Encoding", "chunked");
+        let streamed = send_unfinished(running.addr, "/v1/todos", streamed, &chunked(&body));
+        assert_eq!(streamed.status, 413, "{}", server.name);
+        assert_eq!(
+            streamed.body["detail"], "Request body is larger than 1048576 bytes.",
+            "{}",
+            server.name
+        );
+    }
+}
+
+#[test]
+fn servers_serve_their_openapi_document() {
+    let mut documents = vec![];
+    for server in SERVERS.iter() {
+        let running = start(server);
+        let document = send(running.addr, "GET", "/openapi.json", &[], b"");
+        assert_eq!(document.status, 200, "{}", server.name);
+        assert_eq!(
+            document.content_type.as_deref(),
+            Some("application/json"),
+            "{}",
+            server.name
+        );
+        let paths = document.body["paths"].as_object().unwrap();
+        assert!(!paths.is_empty(), "{}", server.name);
+        for (template, operations) in paths {
+            let path = fill(template);
+            for method in operations.as_object().unwrap().keys() {
+                let method = method.to_ascii_uppercase();
+                let answer = send(running.addr, &method, &path, &[], b"");
+                assert!(
+                    answer.body["detail"] != "No such route.",
+                    "{} doesn't serve {} {}",
+                    server.name,
+                    method,
+                    template
+                );
+            }
+        }
+        // The explorer page isn't JSON.
+        let explorer = send(running.addr, "GET", "/explorer", &[], b"");
+        assert_eq!(explorer.status, 200, "{}", server.name);
+        assert_eq!(
+            explorer.content_type.as_deref(),
+            Some("text/html; charset=utf-8"),
+            "{}",
+            server.name
+        );
+        documents.push(document.body);
+    }
+    assert!(documents.windows(2).all(|pair| pair[0] == pair[1]));
+}